@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::backend::{AddPortError, Backend, Mapping, PortMappingEntry};
+use crate::PortMappingProtocol;
+
+/// A call that was made to the `Memory` backend.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Call {
+    Discover(Option<IpAddr>),
+    Add(Mapping),
+    Remove(Mapping),
+}
+
+#[derive(Debug)]
+pub struct State {
+    /// The address that is returned when discovering without an address.
+    pub local_address: IpAddr,
+    pub external_ip: IpAddr,
+    /// The address of the router. If `None`, every local address counts as a router of its own,
+    /// so that their mappings are processed concurrently.
+    pub gateway: Option<IpAddr>,
+    /// External ports that the router replaces with another one, like NAT-PMP gateways may do.
+    pub reassign: BTreeMap<u16, u16>,
+    /// The router's mapping table.
+    pub mappings: BTreeMap<(PortMappingProtocol, u16), Mapping>,
+    /// Every call, in order.
+    pub calls: Vec<Call>,
+}
+
+/// A router that only exists in memory, for testing.
+///
+/// The backend can be cloned, all clones share the same state. This way, one clone can be handed
+/// over to the daemon, while another one is used to inspect what happened.
+#[derive(Clone, Debug)]
+pub struct Memory {
+    state: Arc<Mutex<State>>,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Memory {
+            state: Arc::new(Mutex::new(State {
+                local_address: Ipv4Addr::new(192, 168, 0, 2).into(),
+                external_ip: Ipv4Addr::new(203, 0, 113, 1).into(),
+                gateway: Some(Ipv4Addr::new(192, 168, 0, 1).into()),
+                reassign: BTreeMap::new(),
+                mappings: BTreeMap::new(),
+                calls: Vec::new(),
+            })),
+        }
+    }
+}
+
+impl Memory {
+    pub fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// The current mapping table, ordered by protocol and external port.
+    pub fn mappings(&self) -> Vec<Mapping> {
+        self.state().mappings.values().cloned().collect()
+    }
+
+    pub fn calls(&self) -> Vec<Call> {
+        self.state().calls.clone()
+    }
+}
+
+impl Backend for Memory {
+    fn discover(
+        &self,
+        address: Option<IpAddr>,
+        _gateway: Option<IpAddr>,
+    ) -> Result<IpAddr, Box<dyn Error>> {
+        let mut state = self.state();
+        state.calls.push(Call::Discover(address));
+        Ok(address.unwrap_or(state.local_address))
+    }
+
+    fn gateway(&self, _internal: IpAddr) -> Option<IpAddr> {
+        self.state().gateway
+    }
+
+    fn add_port(&self, mapping: &Mapping) -> Result<u16, AddPortError> {
+        let mut state = self.state();
+        state.calls.push(Call::Add(mapping.clone()));
+
+        let mut mapping = mapping.clone();
+        if let Some(&port) = state.reassign.get(&mapping.external_port) {
+            mapping.external_port = port;
+        }
+        let key = (mapping.protocol, mapping.external_port);
+        match state.mappings.get(&key) {
+            Some(existing) if existing.internal != mapping.internal => Err(AddPortError::PortInUse),
+            _ => {
+                state.mappings.insert(key, mapping);
+                Ok(key.1)
+            }
+        }
+    }
+
+    fn remove_port(&self, mapping: &Mapping) -> Result<(), Box<dyn Error>> {
+        let mut state = self.state();
+        state.calls.push(Call::Remove(mapping.clone()));
+
+        match state
+            .mappings
+            .remove(&(mapping.protocol, mapping.external_port))
+        {
+            Some(_) => Ok(()),
+            None => Err("The port was not mapped".into()),
+        }
+    }
+
+    fn list(&self, _internal: IpAddr) -> Result<Vec<PortMappingEntry>, Box<dyn Error>> {
+        Ok(self
+            .state()
+            .mappings
+            .values()
+            .map(|mapping| PortMappingEntry {
+                external_port: mapping.external_port,
+                protocol: mapping.protocol,
+                internal_client: mapping.internal.ip().to_string(),
+                internal_port: mapping.internal.port(),
+                enabled: true,
+                description: mapping.comment.clone(),
+                lease_duration: mapping.duration,
+            })
+            .collect())
+    }
+
+    fn external_ip(&self, _internal: IpAddr) -> Result<IpAddr, Box<dyn Error>> {
+        Ok(self.state().external_ip)
+    }
+}
@@ -0,0 +1,457 @@
+//! The protocols that are used to talk to the router, behind a common interface.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::iter;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::pcp::Nonces;
+use crate::{discovery, error, BackendKind, PortMappingProtocol};
+
+pub use self::memory::Memory;
+pub use self::natpmp::NatPmp;
+pub use self::pcp::Pcp;
+pub use self::upnp::Upnp;
+
+pub mod memory;
+mod natpmp;
+mod pcp;
+mod upnp;
+
+/// A single port mapping, as it is sent to the router.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct Mapping {
+    pub internal: SocketAddr,
+    pub external_port: u16,
+    pub protocol: PortMappingProtocol,
+    pub duration: u32,
+    pub comment: String,
+}
+
+/// A port mapping, as it is reported by the router.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PortMappingEntry {
+    pub external_port: u16,
+    pub protocol: PortMappingProtocol,
+    pub internal_client: String,
+    pub internal_port: u16,
+    pub enabled: bool,
+    pub description: String,
+    pub lease_duration: u32,
+}
+
+#[derive(Debug)]
+pub enum AddPortError {
+    /// The external port is already mapped to another client.
+    PortInUse,
+    Other(Box<dyn Error>),
+}
+
+impl fmt::Display for AddPortError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AddPortError::PortInUse => write!(f, "Port is already in use"),
+            AddPortError::Other(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for AddPortError {}
+
+impl From<Box<dyn Error>> for AddPortError {
+    fn from(e: Box<dyn Error>) -> Self {
+        AddPortError::Other(e)
+    }
+}
+
+/// Where and how long to search for UPnP gateways.
+#[derive(Clone, Copy, Debug)]
+pub struct Discovery {
+    pub ssdp_address: SocketAddr,
+    pub timeout: Duration,
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Discovery {
+            ssdp_address: discovery::SSDP_ADDRESS,
+            timeout: discovery::DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+/// A protocol to talk to routers.
+///
+/// Mappings of different gateways are processed concurrently, so backends are shared between
+/// threads. Requests to the same gateway are never sent concurrently, though.
+pub trait Backend: Send + Sync {
+    /// Called before the mappings of a cycle are processed. Backends that remember gateways
+    /// can check here whether they are still valid.
+    fn start_cycle(&self) {}
+
+    /// Find the gateway that is responsible for `address`, or for any local interface if no
+    /// address is given. `gateway` is a hint where to look for it, if the protocol supports it.
+    ///
+    /// Returns the internal address that mappings via this gateway point to.
+    fn discover(
+        &self,
+        address: Option<IpAddr>,
+        gateway: Option<IpAddr>,
+    ) -> Result<IpAddr, Box<dyn Error>>;
+
+    /// The address of the gateway that was discovered for `internal`, if the backend knows it.
+    /// Mappings via the same gateway are processed one after another.
+    fn gateway(&self, _internal: IpAddr) -> Option<IpAddr> {
+        None
+    }
+
+    /// Add a mapping via the gateway that was discovered for its internal address.
+    ///
+    /// Returns the external port that the gateway assigned. Protocols like NAT-PMP treat the
+    /// requested port as a suggestion, so it can differ.
+    fn add_port(&self, mapping: &Mapping) -> Result<u16, AddPortError>;
+
+    fn remove_port(&self, mapping: &Mapping) -> Result<(), Box<dyn Error>>;
+
+    /// Remove mappings that share the internal address and protocol and have consecutive
+    /// external ports, like the ports of a range. Returns one result per mapping.
+    ///
+    /// Backends that can remove a range of ports in one request should override this.
+    fn remove_ports(&self, mappings: &[Mapping]) -> Vec<Result<(), Box<dyn Error>>> {
+        mappings
+            .iter()
+            .map(|mapping| self.remove_port(mapping))
+            .collect()
+    }
+
+    /// List the mappings of the gateway that was discovered for `internal`.
+    fn list(&self, internal: IpAddr) -> Result<Vec<PortMappingEntry>, Box<dyn Error>>;
+
+    /// The external IP address of the gateway that was discovered for `internal`.
+    fn external_ip(&self, internal: IpAddr) -> Result<IpAddr, Box<dyn Error>>;
+}
+
+/// Tries a list of backends in order, using the first one that discovers a gateway.
+pub struct Fallback {
+    backends: Vec<(BackendKind, Box<dyn Backend>)>,
+    chosen: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl Fallback {
+    pub fn new(backends: Vec<(BackendKind, Box<dyn Backend>)>) -> Self {
+        Fallback {
+            backends,
+            chosen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn chosen(&self, internal: IpAddr) -> Result<&dyn Backend, Box<dyn Error>> {
+        let index = *self.chosen.lock().unwrap().get(&internal).ok_or_else(|| {
+            error::Error::Discovery(format!("No gateway discovered for {}", internal))
+        })?;
+        Ok(self.backends[index].1.as_ref())
+    }
+}
+
+impl Backend for Fallback {
+    fn start_cycle(&self) {
+        for (_, backend) in &self.backends {
+            backend.start_cycle();
+        }
+    }
+
+    fn discover(
+        &self,
+        address: Option<IpAddr>,
+        gateway: Option<IpAddr>,
+    ) -> Result<IpAddr, Box<dyn Error>> {
+        let mut last_error = "No backends to try".into();
+
+        for (index, (kind, backend)) in self.backends.iter().enumerate() {
+            match backend.discover(address, gateway) {
+                Ok(internal) => {
+                    debug!("Using {:?} for {}.", kind, internal);
+                    self.chosen.lock().unwrap().insert(internal, index);
+                    return Ok(internal);
+                }
+                Err(e) => {
+                    debug!("No gateway found via {:?}: {}", kind, e);
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+
+    fn gateway(&self, internal: IpAddr) -> Option<IpAddr> {
+        self.chosen(internal).ok()?.gateway(internal)
+    }
+
+    fn add_port(&self, mapping: &Mapping) -> Result<u16, AddPortError> {
+        self.chosen(mapping.internal.ip())?.add_port(mapping)
+    }
+
+    fn remove_port(&self, mapping: &Mapping) -> Result<(), Box<dyn Error>> {
+        self.chosen(mapping.internal.ip())?.remove_port(mapping)
+    }
+
+    fn remove_ports(&self, mappings: &[Mapping]) -> Vec<Result<(), Box<dyn Error>>> {
+        match mappings.first() {
+            Some(first) => match self.chosen(first.internal.ip()) {
+                Ok(backend) => backend.remove_ports(mappings),
+                Err(e) => {
+                    let e = e.to_string();
+                    mappings.iter().map(|_| Err(e.as_str().into())).collect()
+                }
+            },
+            None => Vec::new(),
+        }
+    }
+
+    fn list(&self, internal: IpAddr) -> Result<Vec<PortMappingEntry>, Box<dyn Error>> {
+        self.chosen(internal)?.list(internal)
+    }
+
+    fn external_ip(&self, internal: IpAddr) -> Result<IpAddr, Box<dyn Error>> {
+        self.chosen(internal)?.external_ip(internal)
+    }
+}
+
+/// Identifies a mapping on the router: the gateway is chosen by the backend and the internal
+/// address, the entry on the gateway by protocol and external port.
+type Key = (BackendKind, IpAddr, PortMappingProtocol, u16);
+
+/// The backends to choose from, one per kind.
+///
+/// Mappings that are added via `add_port` are remembered, so they can be removed again when the
+/// daemon shuts down. They are remembered with the external port that the gateway assigned.
+pub struct Backends {
+    default: BackendKind,
+    backends: BTreeMap<BackendKind, Box<dyn Backend>>,
+    owned: Mutex<BTreeMap<Key, Mapping>>,
+    /// The external ports that gateways assigned instead of the requested ones, by the key of
+    /// the request.
+    assigned: Mutex<BTreeMap<Key, u16>>,
+}
+
+impl Backends {
+    /// Create the backends that talk to real routers. Mappings without an explicit backend use
+    /// `default`. PCP mappings get their nonces from `nonces`.
+    pub fn new(default: BackendKind, discovery: Discovery, nonces: Nonces) -> Self {
+        let mut backends = Backends::empty(default);
+        let nonces = Arc::new(Mutex::new(nonces));
+
+        backends.insert(BackendKind::Upnp, Box::new(Upnp::new(discovery)));
+        backends.insert(BackendKind::NatPmp, Box::new(NatPmp::default()));
+        backends.insert(BackendKind::Pcp, Box::new(Pcp::new(nonces.clone())));
+        backends.insert(
+            BackendKind::Auto,
+            Box::new(Fallback::new(vec![
+                (BackendKind::Upnp, Box::new(Upnp::new(discovery))),
+                (BackendKind::Pcp, Box::new(Pcp::new(nonces))),
+                (BackendKind::NatPmp, Box::new(NatPmp::default())),
+            ])),
+        );
+
+        backends
+    }
+
+    /// Create an empty set of backends, to be filled with `insert`.
+    pub fn empty(default: BackendKind) -> Self {
+        Backends {
+            default,
+            backends: BTreeMap::new(),
+            owned: Mutex::new(BTreeMap::new()),
+            assigned: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        kind: BackendKind,
+        backend: Box<dyn Backend>,
+    ) -> Option<Box<dyn Backend>> {
+        self.backends.insert(kind, backend)
+    }
+
+    pub fn get(&self, kind: Option<BackendKind>) -> Result<&dyn Backend, Box<dyn Error>> {
+        let kind = kind.unwrap_or(self.default);
+        match self.backends.get(&kind) {
+            Some(backend) => Ok(backend.as_ref()),
+            None => Err(format!("Backend {:?} is not available", kind).into()),
+        }
+    }
+
+    /// Prepare every backend for a new cycle.
+    pub fn start_cycle(&self) {
+        for backend in self.backends.values() {
+            backend.start_cycle();
+        }
+    }
+
+    /// Add a mapping via the backend of the given kind and remember it. Returns the mapping as
+    /// the gateway made it, with the external port it assigned.
+    ///
+    /// If the gateway assigned another port before, that port is requested again, so that the
+    /// mapping keeps its port when it is refreshed.
+    pub fn add_port(
+        &self,
+        kind: Option<BackendKind>,
+        mapping: &Mapping,
+    ) -> Result<Mapping, AddPortError> {
+        let kind = kind.unwrap_or(self.default);
+        let requested = key(kind, mapping);
+        let previous = self.assigned().get(&requested).copied();
+
+        let mut added = mapping.clone();
+        added.external_port = previous.unwrap_or(mapping.external_port);
+        added.external_port = self.get(Some(kind))?.add_port(&added)?;
+
+        if let Some(previous) = previous.filter(|&port| port != added.external_port) {
+            // The gateway replaced the mapping of the internal port.
+            self.table()
+                .remove(&(kind, requested.1, requested.2, previous));
+        }
+        if added.external_port == mapping.external_port {
+            self.assigned().remove(&requested);
+        } else {
+            self.assigned().insert(requested, added.external_port);
+        }
+        self.table().insert(key(kind, &added), added.clone());
+        Ok(added)
+    }
+
+    /// Remove a mapping via the backend of the given kind and forget it. A mapping whose port
+    /// was assigned by the gateway can be given with the requested port.
+    pub fn remove_port(
+        &self,
+        kind: Option<BackendKind>,
+        mapping: &Mapping,
+    ) -> Result<(), Box<dyn Error>> {
+        let kind = kind.unwrap_or(self.default);
+        let mut mapping = mapping.clone();
+        mapping.external_port = self.assigned_port(key(kind, &mapping));
+        self.get(Some(kind))?.remove_port(&mapping)?;
+        self.forget(key(kind, &mapping));
+        Ok(())
+    }
+
+    fn table(&self) -> MutexGuard<'_, BTreeMap<Key, Mapping>> {
+        self.owned.lock().unwrap()
+    }
+
+    fn assigned(&self) -> MutexGuard<'_, BTreeMap<Key, u16>> {
+        self.assigned.lock().unwrap()
+    }
+
+    /// The external port that the gateway assigned for the request with the given key.
+    fn assigned_port(&self, requested: Key) -> u16 {
+        self.assigned()
+            .get(&requested)
+            .copied()
+            .unwrap_or(requested.3)
+    }
+
+    /// Forget an owned mapping, by the key with its assigned port.
+    fn forget(&self, owned: Key) {
+        self.table().remove(&owned);
+        self.assigned()
+            .retain(|requested, &mut port| (requested.0, requested.1, requested.2, port) != owned);
+    }
+
+    /// Remember a mapping that was added by an earlier process, as the gateway made it, so that
+    /// it is removed like one that was added via `add_port`.
+    pub fn adopt(&self, kind: BackendKind, mapping: Mapping) {
+        self.table().insert(key(kind, &mapping), mapping);
+    }
+
+    /// The mappings that were added via `add_port` and not removed since.
+    pub fn owned(&self) -> impl Iterator<Item = (BackendKind, Mapping)> {
+        self.table()
+            .iter()
+            .map(|(&(kind, ..), mapping)| (kind, mapping.clone()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// The address of the gateway that the backend of the given kind uses for `internal`.
+    pub fn gateway(&self, kind: Option<BackendKind>, internal: IpAddr) -> Option<IpAddr> {
+        self.get(kind).ok()?.gateway(internal)
+    }
+
+    /// Remove the mappings that were added via `add_port`, but are not in `desired` anymore.
+    /// Consecutive ports are removed together, so that ranges are removed as a unit where the
+    /// backend supports it. Failures are logged and the mappings are kept, so that removing them
+    /// is retried later.
+    pub fn reconcile<'a, I>(&self, desired: I)
+    where
+        I: IntoIterator<Item = (Option<BackendKind>, &'a Mapping)>,
+    {
+        let desired: BTreeSet<_> = desired
+            .into_iter()
+            .map(|(kind, mapping)| {
+                let (kind, address, protocol, port) = key(kind.unwrap_or(self.default), mapping);
+                let port = self.assigned_port((kind, address, protocol, port));
+                (kind, address, protocol, port)
+            })
+            .collect();
+
+        // The keys are ordered by backend, address, protocol and port, so ranges are adjacent.
+        let mut groups: Vec<(Key, Vec<Mapping>)> = Vec::new();
+        for (&key, mapping) in self
+            .table()
+            .iter()
+            .filter(|(key, _)| !desired.contains(key))
+        {
+            match groups.last_mut() {
+                Some((last, group))
+                    if (last.0, last.1, last.2) == (key.0, key.1, key.2)
+                        && u32::from(last.3) + 1 == u32::from(key.3) =>
+                {
+                    *last = key;
+                    group.push(mapping.clone());
+                }
+                _ => groups.push((key, vec![mapping.clone()])),
+            }
+        }
+
+        for ((kind, ..), group) in groups {
+            info!("Removing: {:?}", group);
+            let results = match self.get(Some(kind)) {
+                Ok(backend) => backend.remove_ports(&group),
+                Err(e) => {
+                    warn!("Failed to remove {:?}: {}", group, e);
+                    continue;
+                }
+            };
+
+            for (mapping, result) in group.iter().zip(results) {
+                match result {
+                    Ok(()) => self.forget(key(kind, mapping)),
+                    Err(e) => warn!("Failed to remove {:?}: {}", mapping, e),
+                }
+            }
+        }
+    }
+
+    /// Remove all mappings that were added via `add_port`.
+    pub fn remove_owned(&self) {
+        self.reconcile(iter::empty());
+    }
+}
+
+fn key(kind: BackendKind, mapping: &Mapping) -> Key {
+    (
+        kind,
+        mapping.internal.ip(),
+        mapping.protocol,
+        mapping.external_port,
+    )
+}
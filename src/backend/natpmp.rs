@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Mutex;
+
+use log::info;
+
+use crate::backend::{AddPortError, Backend, Mapping, PortMappingEntry};
+use crate::error;
+use crate::natpmp::{self, Client};
+
+/// NAT-PMP gateways, for the machine the daemon is running on.
+#[derive(Default)]
+pub struct NatPmp {
+    gateways: Mutex<HashMap<Ipv4Addr, SocketAddr>>,
+}
+
+impl NatPmp {
+    fn client(&self, internal: IpAddr) -> Result<Client, Box<dyn Error>> {
+        let internal = match internal {
+            IpAddr::V4(internal) => internal,
+            IpAddr::V6(_) => return Err(error::Error::Ipv6Unsupported.into()),
+        };
+
+        let gateway = self
+            .gateways
+            .lock()
+            .unwrap()
+            .get(&internal)
+            .copied()
+            .ok_or_else(|| {
+                error::Error::Discovery(format!("No gateway discovered for {}", internal))
+            })?;
+
+        Ok(Client::new(internal, gateway)?)
+    }
+}
+
+impl Backend for NatPmp {
+    fn discover(
+        &self,
+        address: Option<IpAddr>,
+        gateway: Option<IpAddr>,
+    ) -> Result<IpAddr, Box<dyn Error>> {
+        let gateway = match gateway {
+            Some(IpAddr::V4(gateway)) => gateway,
+            Some(IpAddr::V6(_)) => return Err(error::Error::Ipv6Unsupported.into()),
+            None => {
+                natpmp::default_gateway().map_err(|e| error::Error::Discovery(e.to_string()))?
+            }
+        };
+        let gateway = SocketAddr::new(gateway.into(), natpmp::PORT);
+
+        let bind_addr = match address {
+            None => Ipv4Addr::UNSPECIFIED,
+            Some(IpAddr::V4(addr)) => addr,
+            Some(IpAddr::V6(_)) => return Err(error::Error::Ipv6Unsupported.into()),
+        };
+
+        // Asking for the external address tells whether the gateway speaks NAT-PMP at all.
+        let client = Client::new(bind_addr, gateway)?;
+        client
+            .external_address()
+            .map_err(|e| error::Error::Discovery(e.to_string()))?;
+
+        self.gateways
+            .lock()
+            .unwrap()
+            .insert(client.client_address(), gateway);
+        Ok(client.client_address().into())
+    }
+
+    fn add_port(&self, mapping: &Mapping) -> Result<u16, AddPortError> {
+        let client = self.client(mapping.internal.ip())?;
+
+        let lifetime = match mapping.duration {
+            0 => natpmp::DEFAULT_LIFETIME,
+            duration => duration,
+        };
+
+        let port = mapping.internal.port();
+        let assigned = client
+            .add_port(mapping.protocol, port, mapping.external_port, lifetime)
+            .map_err(|e| AddPortError::Other(e.into()))?;
+
+        if assigned.external_port != mapping.external_port {
+            info!(
+                "NAT-PMP gateway mapped port {} to external port {} instead of {}",
+                port, assigned.external_port, mapping.external_port
+            );
+        }
+
+        Ok(assigned.external_port)
+    }
+
+    fn remove_port(&self, mapping: &Mapping) -> Result<(), Box<dyn Error>> {
+        let client = self.client(mapping.internal.ip())?;
+        Ok(client.remove_port(mapping.protocol, mapping.internal.port())?)
+    }
+
+    fn list(&self, _internal: IpAddr) -> Result<Vec<PortMappingEntry>, Box<dyn Error>> {
+        Err("NAT-PMP does not support listing mappings".into())
+    }
+
+    fn external_ip(&self, internal: IpAddr) -> Result<IpAddr, Box<dyn Error>> {
+        Ok(self.client(internal)?.external_address()?.into())
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use log::info;
+
+use crate::backend::{AddPortError, Backend, Mapping, PortMappingEntry};
+use crate::error;
+use crate::pcp::{self, Client, Nonces};
+
+/// PCP servers, for the machine the daemon is running on.
+#[derive(Default)]
+pub struct Pcp {
+    servers: Mutex<HashMap<IpAddr, SocketAddr>>,
+    nonces: Arc<Mutex<Nonces>>,
+    /// The external address of the last mapping, by internal address.
+    external_ips: Mutex<HashMap<IpAddr, IpAddr>>,
+}
+
+impl Pcp {
+    /// Use `nonces` for the mappings, which may be shared with other instances.
+    pub fn new(nonces: Arc<Mutex<Nonces>>) -> Self {
+        Pcp {
+            nonces,
+            ..Default::default()
+        }
+    }
+
+    fn client(&self, internal: IpAddr) -> Result<Client, Box<dyn Error>> {
+        let server = self
+            .servers
+            .lock()
+            .unwrap()
+            .get(&internal)
+            .copied()
+            .ok_or_else(|| {
+                error::Error::Discovery(format!("No gateway discovered for {}", internal))
+            })?;
+
+        Ok(Client::new(internal, server)?)
+    }
+}
+
+impl Backend for Pcp {
+    fn discover(
+        &self,
+        address: Option<IpAddr>,
+        gateway: Option<IpAddr>,
+    ) -> Result<IpAddr, Box<dyn Error>> {
+        let server = match gateway {
+            Some(gateway) => SocketAddr::new(gateway, pcp::PORT),
+            None => pcp::default_gateway(address.map_or(false, |a| a.is_ipv6()))
+                .map_err(|e| error::Error::Discovery(e.to_string()))?,
+        };
+
+        let bind_addr = address.unwrap_or(match server {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        });
+
+        let client = Client::new(bind_addr, server)?;
+        client
+            .announce()
+            .map_err(|e| error::Error::Discovery(e.to_string()))?;
+
+        self.servers
+            .lock()
+            .unwrap()
+            .insert(client.client_address(), server);
+        Ok(client.client_address())
+    }
+
+    fn add_port(&self, mapping: &Mapping) -> Result<u16, AddPortError> {
+        let client = self.client(mapping.internal.ip())?;
+        let nonce = self
+            .nonces
+            .lock()
+            .unwrap()
+            .get(mapping.protocol, mapping.internal);
+
+        let lifetime = match mapping.duration {
+            0 => pcp::DEFAULT_LIFETIME,
+            duration => duration,
+        };
+
+        let assigned = client
+            .add_port(
+                &nonce,
+                mapping.protocol,
+                mapping.internal.port(),
+                mapping.external_port,
+                lifetime,
+            )
+            .map_err(|e| match e {
+                pcp::Error::NotAuthorized => AddPortError::Other(
+                    format!(
+                        "{}, the mapping may belong to a nonce that was lost and expires later",
+                        e
+                    )
+                    .into(),
+                ),
+                e => AddPortError::Other(e.into()),
+            })?;
+        self.external_ips
+            .lock()
+            .unwrap()
+            .insert(mapping.internal.ip(), assigned.external_address);
+
+        info!(
+            "PCP server mapped {} to {} for {} seconds",
+            mapping.internal,
+            SocketAddr::new(assigned.external_address, assigned.external_port),
+            assigned.lifetime
+        );
+
+        Ok(assigned.external_port)
+    }
+
+    fn remove_port(&self, mapping: &Mapping) -> Result<(), Box<dyn Error>> {
+        let client = self.client(mapping.internal.ip())?;
+        let nonce = self
+            .nonces
+            .lock()
+            .unwrap()
+            .get(mapping.protocol, mapping.internal);
+        Ok(client.remove_port(&nonce, mapping.protocol, mapping.internal.port())?)
+    }
+
+    fn list(&self, _internal: IpAddr) -> Result<Vec<PortMappingEntry>, Box<dyn Error>> {
+        Err("PCP does not support listing mappings".into())
+    }
+
+    /// PCP has no dedicated request for the external address, so it is taken from the response
+    /// to the last mapping. Without a mapping, it is unknown.
+    fn external_ip(&self, internal: IpAddr) -> Result<IpAddr, Box<dyn Error>> {
+        self.external_ips
+            .lock()
+            .unwrap()
+            .get(&internal)
+            .copied()
+            .ok_or_else(|| {
+                format!(
+                    "The PCP server of {} did not report an external address yet",
+                    internal
+                )
+                .into()
+            })
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Instant;
+
+use igd::GetGenericPortMappingEntryError;
+use log::{debug, info, warn};
+
+use crate::backend::{AddPortError, Backend, Discovery, Mapping, PortMappingEntry};
+use crate::connection::Connection;
+use crate::discovery;
+use crate::error;
+use crate::metrics;
+use crate::pinhole::{self, Firewall, Pinholes};
+
+/// UPnP Internet Gateway Devices, with IPv6 firewall pinholes for IPv6 addresses.
+///
+/// Gateways are searched once per local address and kept until the address disappears or the
+/// gateway stops answering. A failed search is not repeated in the same cycle.
+#[derive(Default)]
+pub struct Upnp {
+    discovery: Discovery,
+    state: Mutex<State>,
+    pinholes: Mutex<Pinholes>,
+}
+
+#[derive(Default)]
+struct State {
+    gateways: HashMap<IpAddr, Connection>,
+    /// The local address that was chosen for mappings without an address.
+    default: Option<IpAddr>,
+    /// Searches that failed in this cycle, by local address, or `None` for any address.
+    failed: HashMap<Option<IpAddr>, String>,
+    firewalls: HashMap<Ipv6Addr, Firewall>,
+}
+
+impl Upnp {
+    pub fn new(discovery: Discovery) -> Self {
+        Upnp {
+            discovery,
+            ..Default::default()
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn find_gateway_with_bind_addr(
+        &self,
+        bind_addr: SocketAddr,
+    ) -> Result<Connection, Box<dyn Error>> {
+        let start = Instant::now();
+        let result = discovery::search_gateway(
+            bind_addr,
+            self.discovery.ssdp_address,
+            self.discovery.timeout,
+        );
+        metrics::discovery(start.elapsed());
+        Ok(result?)
+    }
+
+    fn find_gateway_and_addr(&self) -> Result<(Connection, IpAddr), Box<dyn Error>> {
+        let ifaces = get_if_addrs::get_if_addrs()?;
+        let mut ifaces = ifaces
+            .iter()
+            .filter(|iface| !iface.is_loopback() && iface.ip().is_ipv4())
+            .peekable();
+        if ifaces.peek().is_none() {
+            return Err(error::Error::NoInterface.into());
+        }
+
+        ifaces
+            .find_map(|iface| {
+                self.find_gateway_with_bind_addr(SocketAddr::new(iface.ip(), 0))
+                    .ok()
+                    .map(|gateway| (gateway, iface.ip()))
+            })
+            .ok_or_else(|| error::Error::Discovery("No UPnP gateway found".into()).into())
+    }
+
+    /// Search the gateway for `local`, unless it is known already, or the search failed in this
+    /// cycle. `None` searches on every interface until a gateway answers.
+    ///
+    /// Returns the local address and its gateway.
+    fn search(&self, local: Option<IpAddr>) -> Result<(IpAddr, Connection), Box<dyn Error>> {
+        {
+            let state = self.state();
+            if let Some(addr) = local.or(state.default) {
+                if let Some(gateway) = state.gateways.get(&addr) {
+                    return Ok((addr, gateway.clone()));
+                }
+            }
+            if let Some(e) = state.failed.get(&local) {
+                return Err(error::Error::Discovery(e.clone()).into());
+            }
+        }
+
+        // Searching takes a while, other gateways can be used in the meantime.
+        let result = match local {
+            Some(addr) => self
+                .find_gateway_with_bind_addr(SocketAddr::new(addr, 0))
+                .map(|gateway| (gateway, addr)),
+            None => self.find_gateway_and_addr(),
+        };
+
+        let mut state = self.state();
+        match result {
+            Ok((gateway, addr)) => {
+                debug!("Found gateway {} for {}", gateway, addr);
+                state.gateways.insert(addr, gateway.clone());
+                if local.is_none() {
+                    state.default = Some(addr);
+                }
+                Ok((addr, gateway))
+            }
+            Err(e) => {
+                state.failed.insert(local, e.to_string());
+                Err(e)
+            }
+        }
+    }
+
+    /// The gateway for `internal`, which is searched again if it was forgotten.
+    fn gateway_for(&self, internal: IpAddr) -> Result<Connection, Box<dyn Error>> {
+        Ok(self.search(Some(internal))?.1)
+    }
+
+    /// The firewall for `internal`, which is searched again if it was forgotten.
+    fn firewall(&self, internal: Ipv6Addr) -> Result<Firewall, Box<dyn Error>> {
+        if let Some(firewall) = self.state().firewalls.get(&internal) {
+            return Ok(firewall.clone());
+        }
+
+        // IGDv2 routers announce themselves via IPv4, the firewall service is part of the
+        // same device description.
+        let (_, connection) = self.search(None)?;
+        let firewall = Firewall::from_gateway(&connection.gateway)?;
+        self.state().firewalls.insert(internal, firewall.clone());
+        Ok(firewall)
+    }
+
+    /// Forget the firewall for `internal` if it did not answer, so it is searched again.
+    fn check_pinhole<T>(
+        &self,
+        internal: &Ipv6Addr,
+        result: Result<T, pinhole::Error>,
+    ) -> Result<T, pinhole::Error> {
+        if let Err(pinhole::Error::Http(_)) | Err(pinhole::Error::InvalidResponse) = &result {
+            info!(
+                "Firewall for {} did not answer, searching it again.",
+                internal
+            );
+            self.state().firewalls.remove(internal);
+        }
+        result
+    }
+
+    /// Forget the gateway for `internal` if it did not answer, so it is searched again.
+    fn check(&self, internal: IpAddr, e: &igd::RequestError) {
+        if let igd::RequestError::ErrorCode(..) | igd::RequestError::UnsupportedAction(_) = e {
+            return;
+        }
+        info!(
+            "Gateway for {} did not answer, searching it again.",
+            internal
+        );
+        let mut state = self.state();
+        state.gateways.remove(&internal);
+        if state.default == Some(internal) {
+            state.default = None;
+        }
+    }
+}
+
+fn lease_time(duration: u32) -> u32 {
+    match duration {
+        0 => pinhole::MAX_LEASE_TIME,
+        duration => duration.min(pinhole::MAX_LEASE_TIME),
+    }
+}
+
+impl Backend for Upnp {
+    fn start_cycle(&self) {
+        let mut state = self.state();
+        state.failed.clear();
+
+        let local: HashSet<IpAddr> = match get_if_addrs::get_if_addrs() {
+            Ok(ifaces) => ifaces.iter().map(|iface| iface.ip()).collect(),
+            Err(e) => {
+                warn!("Cannot list local addresses: {}", e);
+                return;
+            }
+        };
+        state.gateways.retain(|addr, _| {
+            let keep = local.contains(addr);
+            if !keep {
+                info!("Local address {} is gone, forgetting its gateway.", addr);
+            }
+            keep
+        });
+        if let Some(addr) = state.default {
+            if !state.gateways.contains_key(&addr) {
+                state.default = None;
+            }
+        }
+    }
+
+    fn discover(
+        &self,
+        address: Option<IpAddr>,
+        _gateway: Option<IpAddr>,
+    ) -> Result<IpAddr, Box<dyn Error>> {
+        match address {
+            None | Some(IpAddr::V4(_)) => Ok(self.search(address)?.0),
+            Some(IpAddr::V6(addr)) => {
+                self.firewall(addr)?;
+                Ok(addr.into())
+            }
+        }
+    }
+
+    fn gateway(&self, internal: IpAddr) -> Option<IpAddr> {
+        let state = self.state();
+        match internal {
+            IpAddr::V4(_) => state.gateways.get(&internal),
+            IpAddr::V6(_) => state.default.and_then(|addr| state.gateways.get(&addr)),
+        }
+        .map(|connection| IpAddr::V4(*connection.gateway.addr.ip()))
+    }
+
+    fn add_port(&self, mapping: &Mapping) -> Result<u16, AddPortError> {
+        match mapping.internal {
+            SocketAddr::V4(internal) => {
+                let gateway = self.gateway_for((*internal.ip()).into())?;
+                let result = gateway.add_port(
+                    mapping.protocol,
+                    mapping.external_port,
+                    internal,
+                    mapping.duration,
+                    &mapping.comment,
+                );
+                if let Err(igd::AddPortError::RequestError(e)) = &result {
+                    self.check((*internal.ip()).into(), e);
+                }
+                result.map(|()| mapping.external_port).map_err(|e| match e {
+                    igd::AddPortError::PortInUse => AddPortError::PortInUse,
+                    e => AddPortError::Other(e.into()),
+                })
+            }
+
+            SocketAddr::V6(internal) => {
+                let internal = SocketAddrV6::new(*internal.ip(), internal.port(), 0, 0);
+                let firewall = self.firewall(*internal.ip())?;
+
+                let result = self.pinholes.lock().unwrap().open(
+                    &firewall,
+                    mapping.protocol,
+                    internal,
+                    lease_time(mapping.duration),
+                );
+                let id = self
+                    .check_pinhole(internal.ip(), result)
+                    .map_err(|e| AddPortError::Other(e.into()))?;
+                info!("Pinhole {} is open for {}", id, internal);
+
+                // A pinhole lets the internal port itself through, there is no translation.
+                if mapping.external_port != internal.port() {
+                    warn!(
+                        "Pinhole {} ignores external port {}, {} is reachable on port {}",
+                        id,
+                        mapping.external_port,
+                        internal.ip(),
+                        internal.port()
+                    );
+                }
+                Ok(internal.port())
+            }
+        }
+    }
+
+    fn remove_port(&self, mapping: &Mapping) -> Result<(), Box<dyn Error>> {
+        match mapping.internal {
+            SocketAddr::V4(internal) => {
+                let gateway = self.gateway_for((*internal.ip()).into())?;
+                let result = gateway.remove_port(mapping.protocol, mapping.external_port);
+                if let Err(igd::RemovePortError::RequestError(e)) = &result {
+                    self.check((*internal.ip()).into(), e);
+                }
+                Ok(result?)
+            }
+
+            SocketAddr::V6(internal) => {
+                let internal = SocketAddrV6::new(*internal.ip(), internal.port(), 0, 0);
+                let firewall = self.firewall(*internal.ip())?;
+                let result =
+                    self.pinholes
+                        .lock()
+                        .unwrap()
+                        .close(&firewall, mapping.protocol, internal);
+                Ok(self.check_pinhole(internal.ip(), result)?)
+            }
+        }
+    }
+
+    fn remove_ports(&self, mappings: &[Mapping]) -> Vec<Result<(), Box<dyn Error>>> {
+        if let (Some(first), Some(last)) = (mappings.first(), mappings.last()) {
+            if mappings.len() > 1 {
+                if let Ok(gateway) = self.gateway_for(first.internal.ip()) {
+                    // Only consecutive ports that were mapped by this daemon are passed here.
+                    if gateway.supports("DeletePortMappingRange") {
+                        match gateway.remove_port_range(
+                            first.protocol,
+                            first.external_port,
+                            last.external_port,
+                        ) {
+                            Ok(()) => return mappings.iter().map(|_| Ok(())).collect(),
+                            Err(e) => debug!("Removing the range failed, remove each port: {}", e),
+                        }
+                    }
+                }
+            }
+        }
+
+        mappings
+            .iter()
+            .map(|mapping| self.remove_port(mapping))
+            .collect()
+    }
+
+    fn list(&self, internal: IpAddr) -> Result<Vec<PortMappingEntry>, Box<dyn Error>> {
+        let gateway = self.gateway_for(internal)?;
+        let mut entries = Vec::new();
+
+        for index in 0.. {
+            let entry = match gateway.get_generic_port_mapping_entry(index) {
+                Ok(entry) => entry,
+                Err(GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid) => break,
+                Err(GetGenericPortMappingEntryError::RequestError(e)) => {
+                    self.check(internal, &e);
+                    return Err(e.into());
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            entries.push(entry);
+        }
+
+        Ok(entries)
+    }
+
+    fn external_ip(&self, internal: IpAddr) -> Result<IpAddr, Box<dyn Error>> {
+        let gateway = self.gateway_for(internal)?;
+        match gateway.get_external_ip() {
+            Ok(ip) => Ok(ip.into()),
+            Err(igd::GetExternalIpError::RequestError(e)) => {
+                self.check(internal, &e);
+                Err(e.into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
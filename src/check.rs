@@ -0,0 +1,320 @@
+//! Validation of a mapping file, with diagnostics that point to the offending line and column.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+
+use crate::config::{Config, Format};
+use crate::{BackendKind, ConflictPolicy, Options, PortRange, Protocols};
+
+/// A problem in the mapping file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Diagnostic {
+    /// Line and column, both starting at 1. Structured formats only have positions for syntax
+    /// errors, other problems are located by the number of the mapping instead.
+    pub position: Option<(usize, usize)>,
+    /// The number of the row or mapping, starting at 1.
+    pub mapping: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.position, self.mapping) {
+            (Some((line, column)), _) => write!(f, "{}:{}: {}", line, column, self.message),
+            (None, Some(mapping)) => write!(f, "mapping {}: {}", mapping, self.message),
+            (None, None) => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Where the fields of a mapping are located in the file.
+trait Locate {
+    fn locate(&self, field: &str) -> Option<(usize, usize)>;
+}
+
+/// The positions of the fields of a CSV row.
+struct CsvRow {
+    line: usize,
+    columns: BTreeMap<String, usize>,
+}
+
+impl Locate for CsvRow {
+    fn locate(&self, field: &str) -> Option<(usize, usize)> {
+        let column = self
+            .columns
+            .get(field)
+            .or_else(|| self.columns.values().next())?;
+        Some((self.line, *column))
+    }
+}
+
+/// Mappings of structured formats have no positions after parsing.
+struct Unknown;
+
+impl Locate for Unknown {
+    fn locate(&self, _field: &str) -> Option<(usize, usize)> {
+        None
+    }
+}
+
+/// Parse the file and validate every mapping. Returns all problems that were found.
+pub fn check(path: &Path, format: Format) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    let mappings: Vec<(Options, Box<dyn Locate>)> = match format {
+        Format::Csv => match read_csv(path, &mut diagnostics) {
+            Some(mappings) => mappings,
+            None => return diagnostics,
+        },
+        format => match read_structured(path, format) {
+            Ok(config) => config
+                .mappings
+                .into_iter()
+                .map(|options| (options, Box::new(Unknown) as Box<dyn Locate>))
+                .collect(),
+            Err(diagnostic) => return vec![diagnostic],
+        },
+    };
+
+    let mut external_ports = BTreeMap::new();
+    // Without the interfaces, only ULAs and link-local addresses count as local IPv6 addresses.
+    let interfaces: Vec<IpAddr> = get_if_addrs::get_if_addrs()
+        .map(|interfaces| interfaces.iter().map(|interface| interface.ip()).collect())
+        .unwrap_or_default();
+
+    for (index, (options, location)) in mappings.iter().enumerate() {
+        let mut report = |field: &str, message: String| {
+            diagnostics.push(Diagnostic {
+                position: location.locate(field),
+                mapping: Some(index + 1),
+                message,
+            })
+        };
+
+        if let Some(address) = &options.address {
+            match address.parse::<IpAddr>() {
+                Ok(address) if !is_local(address, &interfaces) => report(
+                    "address",
+                    format!("{} is not an address of a local network", address),
+                ),
+                Ok(_) => {}
+                Err(_) => report("address", format!("{} is not a valid IP address", address)),
+            }
+        }
+
+        let ports = [
+            ("port", options.port),
+            ("external_port", options.external_port),
+            ("internal_port", options.internal_port),
+        ];
+        for (field, range) in ports.iter() {
+            if let Some(range) = range {
+                if range.start == 0 {
+                    report(field, "Port 0 cannot be mapped".to_string());
+                }
+            }
+        }
+
+        let external = options.external_port.or(options.port);
+        let internal = options.internal_port.or(options.port);
+        let (external, internal) = match (external, internal) {
+            (Some(external), Some(internal)) => (external, internal),
+            _ => {
+                report(
+                    "port",
+                    "Either port or both external_port and internal_port are needed".to_string(),
+                );
+                continue;
+            }
+        };
+        if external.size() != internal.size() {
+            report(
+                "internal_port",
+                format!(
+                    "External ports {} and internal ports {} differ in size",
+                    external, internal
+                ),
+            );
+        }
+
+        for &protocol in options.protocol.protocols() {
+            let key = (options.gateway, protocol);
+            let taken: &mut Vec<(PortRange, usize)> = external_ports.entry(key).or_default();
+            for (other, row) in taken.iter() {
+                if overlap(external, *other) {
+                    report(
+                        if options.external_port.is_some() {
+                            "external_port"
+                        } else {
+                            "port"
+                        },
+                        format!(
+                            "External port {} ({:?}) is already used by mapping {}",
+                            external, protocol, row
+                        ),
+                    );
+                }
+            }
+            taken.push((external, index + 1));
+        }
+    }
+
+    // Problems found while parsing come first, but should be sorted in with the others.
+    diagnostics.sort_by_key(|diagnostic| diagnostic.mapping);
+    diagnostics
+}
+
+fn read_csv(
+    path: &Path,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> Option<Vec<(Options, Box<dyn Locate>)>> {
+    let mut rdr = match csv::ReaderBuilder::new().delimiter(b';').from_path(path) {
+        Ok(rdr) => rdr,
+        Err(e) => {
+            diagnostics.push(error(e.to_string()));
+            return None;
+        }
+    };
+    let headers = match rdr.headers() {
+        Ok(headers) => headers.clone(),
+        Err(e) => {
+            diagnostics.push(error(e.to_string()));
+            return None;
+        }
+    };
+
+    let mut mappings = Vec::new();
+    for (index, record) in rdr.records().enumerate() {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                diagnostics.push(Diagnostic {
+                    position: e.position().map(|p| (p.line() as usize, 1)),
+                    mapping: Some(index + 1),
+                    message: e.to_string(),
+                });
+                continue;
+            }
+        };
+
+        // Fields are separated by a single delimiter, quotes are not accounted for.
+        let mut columns = BTreeMap::new();
+        let mut column = 1;
+        for (name, field) in headers.iter().zip(record.iter()) {
+            columns.insert(name.to_string(), column);
+            column += field.len() + 1;
+        }
+        let row = CsvRow {
+            line: record.position().map_or(0, |p| p.line() as usize),
+            columns,
+        };
+
+        match record.deserialize::<Options>(Some(&headers)) {
+            Ok(options) => mappings.push((options, Box::new(row) as Box<dyn Locate>)),
+            Err(e) => {
+                let (field, message) = match e.kind() {
+                    csv::ErrorKind::Deserialize { err, .. } => (
+                        err.field()
+                            .map(|field| field as usize)
+                            .or_else(|| invalid_field(&headers, &record))
+                            .and_then(|field| headers.get(field))
+                            .unwrap_or(""),
+                        err.kind().to_string(),
+                    ),
+                    _ => ("", e.to_string()),
+                };
+                diagnostics.push(Diagnostic {
+                    position: row.locate(field),
+                    mapping: Some(index + 1),
+                    message: if field.is_empty() {
+                        message
+                    } else {
+                        format!("{}: {}", field, message)
+                    },
+                });
+            }
+        }
+    }
+
+    Some(mappings)
+}
+
+/// Find the field of a CSV row that cannot be deserialized. The CSV reader only knows the field
+/// of an error if it could not parse a primitive value, not if the value itself refused it.
+fn invalid_field(headers: &csv::StringRecord, record: &csv::StringRecord) -> Option<usize> {
+    fn parses<T: DeserializeOwned>(value: &str) -> bool {
+        csv::StringRecord::from(vec![value])
+            .deserialize::<(T,)>(None)
+            .is_ok()
+    }
+
+    headers
+        .iter()
+        .zip(record.iter())
+        .position(|(name, value)| !match name {
+            "port" | "external_port" | "internal_port" => parses::<Option<PortRange>>(value),
+            "protocol" => parses::<Protocols>(value),
+            "duration" => parses::<u32>(value),
+            "backend" => parses::<Option<BackendKind>>(value),
+            "gateway" => parses::<Option<IpAddr>>(value),
+            "conflict" => parses::<Option<ConflictPolicy>>(value),
+            _ => true,
+        })
+}
+
+fn read_structured(path: &Path, format: Format) -> Result<Config, Diagnostic> {
+    let content = fs::read_to_string(path).map_err(|e| error(e.to_string()))?;
+
+    match format {
+        Format::Toml => toml::from_str(&content).map_err(|e| Diagnostic {
+            position: e.line_col().map(|(line, column)| (line + 1, column + 1)),
+            mapping: None,
+            message: e.to_string(),
+        }),
+        Format::Yaml => serde_yaml::from_str(&content).map_err(|e| Diagnostic {
+            position: e.location().map(|l| (l.line(), l.column())),
+            mapping: None,
+            message: e.to_string(),
+        }),
+        Format::Json => serde_json::from_str(&content).map_err(|e| Diagnostic {
+            position: Some((e.line(), e.column())),
+            mapping: None,
+            message: e.to_string(),
+        }),
+        Format::Csv => unreachable!("CSV is read row by row"),
+    }
+}
+
+fn error(message: String) -> Diagnostic {
+    Diagnostic {
+        position: None,
+        mapping: None,
+        message,
+    }
+}
+
+/// Whether a mapping to `address` makes sense: private IPv4 addresses behind a NAT, or IPv6
+/// addresses of the local network. Global IPv6 addresses, for firewall pinholes, only count if
+/// they belong to one of the `interfaces` of this machine.
+fn is_local(address: IpAddr, interfaces: &[IpAddr]) -> bool {
+    match address {
+        IpAddr::V4(address) => address.is_private(),
+        IpAddr::V6(address) => {
+            let segment = address.segments()[0];
+            let unique_local = segment & 0xfe00 == 0xfc00;
+            let link_local = segment & 0xffc0 == 0xfe80;
+            unique_local
+                || link_local
+                || (!address.is_loopback() && interfaces.contains(&IpAddr::V6(address)))
+        }
+    }
+}
+
+fn overlap(a: PortRange, b: PortRange) -> bool {
+    a.start <= b.end && b.start <= a.end
+}
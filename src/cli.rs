@@ -1,36 +1,125 @@
+use std::env;
 use std::error::Error;
-use std::time::Duration;
-use std::{fs, thread};
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use clap::{crate_authors, crate_description, crate_name, crate_version, value_t, App, Arg};
+use clap::{
+    crate_authors, crate_description, crate_name, crate_version, value_t, values_t, App,
+    AppSettings, Arg, ArgMatches, SubCommand,
+};
 use daemonize::Daemonize;
-use log::info;
+use log::{error, info, warn};
+use serde_json::json;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1, SIGUSR2};
+use signal_hook::iterator::Signals;
 
-use crate::{run, Options};
+use crate::backend::{Backends, Discovery, Mapping};
+use crate::check::check;
+use crate::config::{Config, Daemon, Format};
+use crate::control::{self, MappingStatus, Request, Response};
+use crate::ddns;
+use crate::external_ip::Tracker;
+use crate::list::{self, list, Output};
+use crate::logger;
+use crate::metrics;
+use crate::pcp::Nonces;
+use crate::state;
+use crate::{
+    external_ips, remove, run, run_all, BackendKind, ConflictPolicy, Options, PortRange, Protocols,
+    Removal, Report, Settings,
+};
 
 const ARG_FILE: &str = "file";
 const ARG_FOREGROUND: &str = "foreground";
 const ARG_ONESHOT: &str = "oneshot";
 const ARG_INTERVAL: &str = "interval";
+const ARG_BACKEND: &str = "backend";
+const ARG_SSDP_ADDRESS: &str = "ssdp-address";
+const ARG_DISCOVERY_TIMEOUT: &str = "discovery-timeout";
+const ARG_KEEP_MAPPINGS: &str = "keep-mappings";
+const ARG_CONFLICT: &str = "conflict";
+const ARG_FORMAT: &str = "format";
+const ARG_PARALLELISM: &str = "parallelism";
+const ARG_FAIL_FAST: &str = "fail-fast";
+const ARG_ADDRESS: &str = "address";
+const ARG_OUTPUT: &str = "output";
+const ARG_PORT: &str = "port";
+const ARG_EXTERNAL_PORT: &str = "external-port";
+const ARG_INTERNAL_PORT: &str = "internal-port";
+const ARG_PROTOCOL: &str = "protocol";
+const ARG_DURATION: &str = "duration";
+const ARG_COMMENT: &str = "comment";
+const ARG_CONTROL_SOCKET: &str = "control-socket";
+const ARG_CONTROL_SOCKET_MODE: &str = "control-socket-mode";
+const ARG_SOCKET: &str = "socket";
+const ARG_METRICS_PORT: &str = "metrics-port";
+const ARG_EXTERNAL_IP_FILE: &str = "external-ip-file";
+const ARG_ON_IP_CHANGE: &str = "on-ip-change";
+
+const CMD_CHECK: &str = "check";
+const CMD_LIST: &str = "list";
+const CMD_ADD: &str = "add";
+const CMD_REMOVE: &str = "remove";
+const CMD_CONTROL: &str = "control";
+const CMD_STATUS: &str = "status";
+const CMD_REFRESH: &str = "refresh";
+const CMD_RELOAD: &str = "reload";
+const CMD_OPEN: &str = "open";
+const CMD_CLOSE: &str = "close";
+const CMD_SHUTDOWN: &str = "shutdown";
+
+/// What wakes the daemon up between two regular refreshes.
+enum Event {
+    Signal(i32),
+    /// The mapping file was written.
+    Changed,
+    /// A request on the control socket, to be answered on the sender.
+    Control(Request, mpsc::Sender<Response>),
+}
+
+/// A mapping that was opened via the control socket.
+struct Temporary {
+    options: Options,
+    /// When it is removed, none to keep it until the daemon stops.
+    expires: Option<SystemTime>,
+    status: MappingStatus,
+}
 
 pub struct Cli;
 
 impl Cli {
     pub fn run() -> Result<(), Box<dyn Error>> {
+        Cli::run_from(env::args_os())
+    }
+
+    /// Like `run`, but with the given command line instead of the one of the process.
+    pub fn run_from<I, T>(args: I) -> Result<(), Box<dyn Error>>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
         let arguments = App::new(crate_name!())
             .version(crate_version!())
             .author(crate_authors!())
             .about(crate_description!())
+            .setting(AppSettings::SubcommandsNegateReqs)
             .args(&[
                 Arg::with_name(ARG_FILE)
                     .short(&ARG_FILE[0..1])
                     .long(ARG_FILE)
-                    .help("The file with the port descriptions, in CSV format")
+                    .help("The file with the port descriptions, in CSV, TOML, YAML or JSON format")
                     .required(true)
                     .takes_value(true)
                     .number_of_values(1),
                 Arg::with_name(ARG_FOREGROUND)
-                    .short(&ARG_FOREGROUND[0..1].to_uppercase())
+                    .short(ARG_FOREGROUND[0..1].to_uppercase())
                     .long(ARG_FOREGROUND)
                     .help("Run in foreground instead of forking to background"),
                 Arg::with_name(ARG_ONESHOT)
@@ -43,42 +132,1172 @@ impl Cli {
                     .help("Specify update interval in seconds")
                     .takes_value(true)
                     .number_of_values(1),
+                backend_arg().help("Default protocol for mappings that do not specify a backend"),
+                ssdp_address_arg(),
+                discovery_timeout_arg(),
+                Arg::with_name(ARG_KEEP_MAPPINGS)
+                    .long(ARG_KEEP_MAPPINGS)
+                    .help("Do not remove the created mappings when shutting down"),
+                conflict_arg(),
+                Arg::with_name(ARG_FORMAT)
+                    .long(ARG_FORMAT)
+                    .help("The format of the file, instead of guessing it from the extension")
+                    .takes_value(true)
+                    .number_of_values(1)
+                    .possible_values(&["csv", "toml", "yaml", "json"]),
+                Arg::with_name(ARG_PARALLELISM)
+                    .short("j")
+                    .long(ARG_PARALLELISM)
+                    .help("How many gateways to talk to at the same time")
+                    .takes_value(true)
+                    .number_of_values(1)
+                    .default_value("4"),
+                Arg::with_name(ARG_FAIL_FAST)
+                    .long(ARG_FAIL_FAST)
+                    .help("Stop at the first row that fails, instead of logging it and going on"),
+                Arg::with_name(ARG_CONTROL_SOCKET)
+                    .long(ARG_CONTROL_SOCKET)
+                    .help("Listen for control commands on a Unix socket at this path")
+                    .takes_value(true)
+                    .number_of_values(1),
+                Arg::with_name(ARG_CONTROL_SOCKET_MODE)
+                    .long(ARG_CONTROL_SOCKET_MODE)
+                    .help("The permissions of the control socket in octal, 600 by default")
+                    .takes_value(true)
+                    .number_of_values(1),
+                Arg::with_name(ARG_METRICS_PORT)
+                    .long(ARG_METRICS_PORT)
+                    .help("Serve metrics for Prometheus on this port of localhost")
+                    .takes_value(true)
+                    .number_of_values(1),
+                Arg::with_name(ARG_EXTERNAL_IP_FILE)
+                    .long(ARG_EXTERNAL_IP_FILE)
+                    .help("Keep the last known external addresses in this file")
+                    .takes_value(true)
+                    .number_of_values(1),
+                Arg::with_name(ARG_ON_IP_CHANGE)
+                    .long(ARG_ON_IP_CHANGE)
+                    .help("Run this shell command when the external address changes")
+                    .takes_value(true)
+                    .number_of_values(1),
             ])
-            .get_matches_safe()
+            .subcommand(
+                SubCommand::with_name(CMD_CHECK)
+                    .about("Validate the file without mapping any ports")
+                    .args(&[
+                        Arg::with_name(ARG_FILE)
+                            .short(&ARG_FILE[0..1])
+                            .long(ARG_FILE)
+                            .help("The file to validate")
+                            .required(true)
+                            .takes_value(true)
+                            .number_of_values(1),
+                        Arg::with_name(ARG_FORMAT)
+                            .long(ARG_FORMAT)
+                            .help(
+                                "The format of the file, instead of guessing it from the extension",
+                            )
+                            .takes_value(true)
+                            .number_of_values(1)
+                            .possible_values(&["csv", "toml", "yaml", "json"]),
+                    ]),
+            )
+            .subcommand(
+                SubCommand::with_name(CMD_LIST)
+                    .about("Print the port mappings of the gateways")
+                    .args(&[
+                        Arg::with_name(ARG_ADDRESS)
+                            .short("a")
+                            .long(ARG_ADDRESS)
+                            .help("List the gateway of this local address, instead of the default")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1),
+                        backend_arg().help("The protocol to talk to the gateways"),
+                        ssdp_address_arg(),
+                        discovery_timeout_arg(),
+                        Arg::with_name(ARG_OUTPUT)
+                            .short("o")
+                            .long(ARG_OUTPUT)
+                            .help("Print a table, JSON, or CSV rows for the mapping file")
+                            .takes_value(true)
+                            .number_of_values(1)
+                            .possible_values(&["table", "json", "csv"])
+                            .default_value("table"),
+                    ]),
+            )
+            .subcommand(
+                SubCommand::with_name(CMD_ADD)
+                    .about("Map a port without adding it to the file")
+                    .args(&mapping_args())
+                    .args(&[
+                        backend_arg().help("The protocol to talk to the gateway"),
+                        ssdp_address_arg(),
+                        discovery_timeout_arg(),
+                        conflict_arg(),
+                    ]),
+            )
+            .subcommand(
+                SubCommand::with_name(CMD_REMOVE)
+                    .about("Remove mappings by external port or by description")
+                    .args(&[
+                        Arg::with_name(ARG_ADDRESS)
+                            .short("a")
+                            .long(ARG_ADDRESS)
+                            .help("Use the gateway of this local address, instead of the default")
+                            .takes_value(true)
+                            .number_of_values(1),
+                        Arg::with_name(ARG_PORT)
+                            .short("p")
+                            .long(ARG_PORT)
+                            .help("The external port or range of ports to remove")
+                            .takes_value(true)
+                            .number_of_values(1)
+                            .required_unless(ARG_COMMENT)
+                            .conflicts_with(ARG_COMMENT)
+                            .requires(ARG_PROTOCOL),
+                        protocol_arg(),
+                        Arg::with_name(ARG_COMMENT)
+                            .short("c")
+                            .long(ARG_COMMENT)
+                            .help("Remove every mapping with exactly this description")
+                            .takes_value(true)
+                            .number_of_values(1),
+                        backend_arg().help("The protocol to talk to the gateway"),
+                        ssdp_address_arg(),
+                        discovery_timeout_arg(),
+                    ]),
+            )
+            .subcommand(
+                SubCommand::with_name(CMD_CONTROL)
+                    .about("Send a command to a running daemon")
+                    .setting(AppSettings::SubcommandRequiredElseHelp)
+                    .arg(
+                        Arg::with_name(ARG_SOCKET)
+                            .short("s")
+                            .long(ARG_SOCKET)
+                            .help("The control socket of the daemon")
+                            .required(true)
+                            .takes_value(true)
+                            .number_of_values(1),
+                    )
+                    .subcommand(
+                        SubCommand::with_name(CMD_STATUS)
+                            .about("Print the result of the last attempt of each mapping"),
+                    )
+                    .subcommand(
+                        SubCommand::with_name(CMD_REFRESH).about("Update the mappings right away"),
+                    )
+                    .subcommand(
+                        SubCommand::with_name(CMD_RELOAD)
+                            .about("Read the daemon section of the file again"),
+                    )
+                    .subcommand(
+                        SubCommand::with_name(CMD_OPEN)
+                            .about("Map a port until its duration is over")
+                            .args(&mapping_args()),
+                    )
+                    .subcommand(
+                        SubCommand::with_name(CMD_CLOSE)
+                            .about("Remove a mapping by external port")
+                            .args(&[
+                                Arg::with_name(ARG_ADDRESS)
+                                    .short("a")
+                                    .long(ARG_ADDRESS)
+                                    .help("Use the gateway of this local address")
+                                    .takes_value(true)
+                                    .number_of_values(1),
+                                Arg::with_name(ARG_PORT)
+                                    .short("p")
+                                    .long(ARG_PORT)
+                                    .help("The external port or range of ports to remove")
+                                    .required(true)
+                                    .takes_value(true)
+                                    .number_of_values(1),
+                                protocol_arg().required(true),
+                            ]),
+                    )
+                    .subcommand(
+                        SubCommand::with_name(CMD_SHUTDOWN)
+                            .about("Remove the mappings and stop the daemon"),
+                    ),
+            )
+            .get_matches_from_safe(args)
             .unwrap_or_else(|e| e.exit());
 
+        // The subcommands log with `RUST_LOG`, the daemon applies its own settings below.
+        logger::set_filter(&log_filter(&Daemon::default()));
+
+        if let Some(arguments) = arguments.subcommand_matches(CMD_CHECK) {
+            return Cli::check(arguments);
+        }
+        if let Some(arguments) = arguments.subcommand_matches(CMD_LIST) {
+            return Cli::list(arguments);
+        }
+        if let Some(arguments) = arguments.subcommand_matches(CMD_ADD) {
+            return Cli::add(arguments);
+        }
+        if let Some(arguments) = arguments.subcommand_matches(CMD_REMOVE) {
+            return Cli::remove(arguments);
+        }
+        if let Some(arguments) = arguments.subcommand_matches(CMD_CONTROL) {
+            return Cli::control(arguments);
+        }
+
         let file = fs::canonicalize(arguments.value_of_os(ARG_FILE).unwrap())?;
         let foreground = arguments.is_present(ARG_FOREGROUND);
         let oneshot = arguments.is_present(ARG_ONESHOT);
-        let interval = if arguments.is_present(ARG_INTERVAL) {
-            value_t!(arguments.value_of(ARG_INTERVAL), u64).unwrap_or_else(|e| e.exit())
+        let keep_mappings = arguments.is_present(ARG_KEEP_MAPPINGS);
+        let format = format(&arguments, &file);
+        let daemon = Config::read(&file, format)?.daemon;
+        let mut interval = interval(&arguments, &daemon);
+        let settings = Settings {
+            conflict: value_t!(arguments.value_of(ARG_CONFLICT), ConflictPolicy)
+                .unwrap_or_else(|e| e.exit()),
+            parallelism: value_t!(arguments.value_of(ARG_PARALLELISM), usize)
+                .unwrap_or_else(|e| e.exit()),
+            fail_fast: arguments.is_present(ARG_FAIL_FAST),
+        };
+
+        let control_socket = control_socket(&arguments, &daemon)?;
+        let metrics_port = if arguments.is_present(ARG_METRICS_PORT) {
+            Some(value_t!(arguments.value_of(ARG_METRICS_PORT), u16).unwrap_or_else(|e| e.exit()))
         } else {
-            60
+            daemon.metrics_port
         };
+        // Daemonizing changes the working directory.
+        let external_ip_file = match arguments.value_of_os(ARG_EXTERNAL_IP_FILE) {
+            Some(file) => Some(PathBuf::from(file)),
+            None => daemon.external_ip_file.clone(),
+        }
+        .map(|file| env::current_dir().map(|dir| dir.join(file)))
+        .transpose()?;
+        let on_ip_change = arguments
+            .value_of(ARG_ON_IP_CHANGE)
+            .map(String::from)
+            .or_else(|| daemon.on_ip_change.clone());
+        let ddns = daemon.ddns.clone();
+        // Without the last known addresses, a change while the daemon was not running would
+        // neither run the hook nor update the DNS records.
+        let external_ip_file = match external_ip_file {
+            None if on_ip_change.is_some() || ddns.is_some() => state::file("external-ips.json"),
+            file => file,
+        };
+        let nonces = match pcp_nonce_file(&daemon) {
+            Some(file) => Nonces::load(env::current_dir()?.join(file)),
+            None => Nonces::default(),
+        };
+
+        logger::set_filter(&log_filter(&daemon));
 
-        if !foreground {
-            Daemonize::new()
-                .pid_file(format!("/tmp/{}.pid", crate_name!()))
-                .start()
-                .expect("Failed to daemonize.");
+        let pid_file = if foreground {
+            None
+        } else {
+            Some(pid_file(&daemon))
+        };
+        if let Some(pid_file) = &pid_file {
+            let mut daemonize = Daemonize::new().pid_file(pid_file);
+            if let Some(log_file) = daemon.log_file {
+                let log_file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(log_file)?;
+                daemonize = daemonize.stdout(log_file.try_clone()?).stderr(log_file);
+            }
+            daemonize.start()?;
         }
 
-        loop {
-            let mut rdr = csv::ReaderBuilder::new().delimiter(b';').from_path(&file)?;
+        let mut backends = backends(&arguments, nonces);
+
+        if oneshot {
+            let report = run_all(
+                Config::read(&file, format)?.mappings,
+                &mut backends,
+                &settings,
+            )?;
+            return match report.failed.len() {
+                0 => Ok(()),
+                _ => Err(report.to_string().into()),
+            };
+        }
 
-            for result in rdr.deserialize() {
-                let options: Options = result?;
-                info!("Processing: {:?}", options);
-                run(options)?;
+        // Handle signals only between iterations, so that no mapping is left half done.
+        // Registering them also disables the default handlers.
+        let (sender, events) = mpsc::channel();
+        let mut handler = Signals::new([SIGINT, SIGTERM, SIGHUP, SIGUSR1, SIGUSR2])?;
+        let signals = sender.clone();
+        thread::spawn(move || {
+            for signal in handler.forever() {
+                if signals.send(Event::Signal(signal)).is_err() {
+                    break;
+                }
             }
+        });
 
-            if oneshot {
-                break;
-            } else {
-                thread::sleep(Duration::from_secs(interval));
+        // Requests are answered by the loop below, between iterations like the signals.
+        let mut listener = None;
+        if let Some((path, mode)) = &control_socket {
+            let requests = sender.clone();
+            listener = Some(
+                control::listen(path, *mode, move |request| {
+                    let (reply, response) = mpsc::channel();
+                    requests
+                        .send(Event::Control(request, reply))
+                        .ok()
+                        .and_then(|_| response.recv().ok())
+                        .unwrap_or_else(|| {
+                            Response::error("The daemon is shutting down".to_string())
+                        })
+                })
+                .map_err(|e| format!("Cannot listen on {}: {}", path.display(), e))?,
+            );
+        }
+
+        if let Some(port) = metrics_port {
+            let address = SocketAddr::from(([127, 0, 0, 1], port));
+            metrics::serve(address)
+                .map_err(|e| format!("Cannot serve metrics on {}: {}", address, e))?;
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Err(e) = crate::watch::spawn(&file, move || sender.send(Event::Changed).is_ok()) {
+            warn!("Cannot watch {} for changes: {}", file.display(), e);
+        }
+
+        let mut tracker = Tracker::new(external_ip_file, on_ip_change);
+        // The address the DNS records still have to point to, until an update succeeds.
+        let mut dns_address = None;
+        let mut temporary: Vec<Temporary> = Vec::new();
+        let mut status = Vec::new();
+        // Control requests that are answered once the next iteration is done.
+        let mut waiting: Vec<mpsc::Sender<Response>> = Vec::new();
+        let mut next_refresh = Instant::now();
+        // Why the daemon stops, if it is not asked to.
+        let mut failure: Option<Box<dyn Error>> = None;
+        'refresh: loop {
+            let regular = Instant::now() >= next_refresh;
+            let now = SystemTime::now();
+            temporary.retain(|t| t.expires.map_or(true, |expires| expires > now));
+
+            // Without fail-fast, failures are logged and retried in the next iteration.
+            let outcome = match Config::read(&file, format) {
+                Ok(config) => {
+                    let rows: Vec<_> = config
+                        .mappings
+                        .into_iter()
+                        .chain(temporary.iter().map(|t| t.options.clone()))
+                        .collect();
+                    let result = run_all(rows.clone(), &mut backends, &settings);
+                    status = mapping_status(&rows, &result);
+                    let opened = status.split_off(status.len() - temporary.len());
+                    for (temporary, status) in temporary.iter_mut().zip(opened) {
+                        temporary.status = MappingStatus {
+                            row: None,
+                            expires: temporary.expires.map(unix_time),
+                            ..status
+                        };
+                    }
+                    match result {
+                        Ok(report) => Ok(report.to_string()),
+                        Err(e) => {
+                            failure = Some(e.into());
+                            break 'refresh;
+                        }
+                    }
+                }
+                Err(e) if settings.fail_fast => {
+                    failure = Some(e.into());
+                    break 'refresh;
+                }
+                Err(e) => {
+                    error!("Cannot read {}: {}", file.display(), e);
+                    Err(format!("Cannot read {}: {}", file.display(), e))
+                }
+            };
+            let mut addresses = Vec::new();
+            for (gateway, result) in external_ips(&backends) {
+                match result {
+                    Ok(external_ip) => {
+                        metrics::set_external_ip(gateway, external_ip);
+                        addresses.push((gateway, external_ip));
+                    }
+                    Err(e) => warn!("Cannot get the external address of {}: {}", gateway, e),
+                }
+            }
+            if let Some(change) = tracker.update(addresses).last() {
+                dns_address = Some(change.new);
+            }
+            if let (Some(ddns), Some(address)) = (&ddns, dns_address) {
+                match ddns::update(ddns, address) {
+                    Ok(()) => {
+                        info!("Pointed the DNS records of {} to {}", ddns.zone, address);
+                        dns_address = None;
+                    }
+                    Err(e) => error!("Cannot update the DNS records of {}: {}", ddns.zone, e),
+                }
+            }
+            for reply in waiting.drain(..) {
+                let _ = reply.send(match &outcome {
+                    Ok(summary) => Response::message(summary.clone()),
+                    Err(e) => Response::error(e.clone()),
+                });
+            }
+
+            // A change of the file or a forced refresh does not postpone the next regular one.
+            if regular {
+                next_refresh = Instant::now() + interval;
+            }
+
+            loop {
+                // Wake up early to remove temporary mappings when they expire.
+                let wake_up = temporary.iter().filter_map(|t| t.expires).min().map_or(
+                    next_refresh,
+                    |expires| {
+                        let remaining = expires.duration_since(SystemTime::now());
+                        next_refresh.min(Instant::now() + remaining.unwrap_or_default())
+                    },
+                );
+                match events.recv_timeout(wake_up.saturating_duration_since(Instant::now())) {
+                    Ok(Event::Signal(SIGHUP)) => {
+                        info!("Received SIGHUP, reloading.");
+                        match reload(&file, format, &arguments, foreground) {
+                            Ok(new_interval) => {
+                                interval = new_interval;
+                                // Start the new interval right away.
+                                next_refresh = Instant::now();
+                            }
+                            Err(e) => warn!("Cannot reload {}: {}", file.display(), e),
+                        }
+                        break;
+                    }
+                    Ok(Event::Signal(SIGUSR1)) => {
+                        info!("Received SIGUSR1, refreshing now.");
+                        break;
+                    }
+                    Ok(Event::Signal(SIGUSR2)) => {
+                        dump_state(&file, interval, next_refresh, &backends);
+                    }
+                    Ok(Event::Signal(signal)) => {
+                        info!("Received signal {}, shutting down.", signal);
+                        break 'refresh;
+                    }
+                    Ok(Event::Changed) => {
+                        info!("Mapping file changed, reloading.");
+                        break;
+                    }
+                    Ok(Event::Control(Request::Status, reply)) => {
+                        let _ = reply.send(Response {
+                            messages: vec![format!(
+                                "Next refresh in {}s",
+                                next_refresh
+                                    .saturating_duration_since(Instant::now())
+                                    .as_secs()
+                            )],
+                            mappings: status
+                                .iter()
+                                .chain(temporary.iter().map(|t| &t.status))
+                                .cloned()
+                                .collect(),
+                            ..Default::default()
+                        });
+                    }
+                    Ok(Event::Control(Request::Refresh, reply)) => {
+                        info!("Refresh requested, refreshing now.");
+                        waiting.push(reply);
+                        break;
+                    }
+                    Ok(Event::Control(Request::Reload, reply)) => {
+                        info!("Reload requested, reloading.");
+                        match reload(&file, format, &arguments, foreground) {
+                            Ok(new_interval) => {
+                                interval = new_interval;
+                                next_refresh = Instant::now();
+                                waiting.push(reply);
+                            }
+                            Err(e) => {
+                                warn!("Cannot reload {}: {}", file.display(), e);
+                                let _ = reply.send(Response::error(format!(
+                                    "Cannot reload {}: {}",
+                                    file.display(),
+                                    e
+                                )));
+                            }
+                        }
+                        break;
+                    }
+                    Ok(Event::Control(Request::Open(options), reply)) => {
+                        let _ = reply.send(open(options, &mut backends, &settings, &mut temporary));
+                    }
+                    Ok(Event::Control(
+                        Request::Close {
+                            address,
+                            port,
+                            protocol,
+                        },
+                        reply,
+                    )) => {
+                        close(&mut temporary, port, protocol);
+                        let removal = Removal::Ports(port, protocol);
+                        let _ = reply.send(match remove(address, None, &removal, &mut backends) {
+                            Ok(removed) => Response {
+                                messages: removed
+                                    .iter()
+                                    .map(|m| {
+                                        format!("Removed {} ({:?})", m.external_port, m.protocol)
+                                    })
+                                    .collect(),
+                                ..Default::default()
+                            },
+                            Err(e) => Response::error(e.to_string()),
+                        });
+                    }
+                    Ok(Event::Control(Request::Shutdown, reply)) => {
+                        info!("Shutdown requested, shutting down.");
+                        waiting.push(reply);
+                        break 'refresh;
+                    }
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        failure = Some("Signal handling stopped unexpectedly".into());
+                        break 'refresh;
+                    }
+                }
+            }
+        }
+
+        // Every way out of the loop ends here, so that nothing is left behind.
+        if !keep_mappings {
+            backends.remove_owned();
+        }
+        if let Some((path, _)) = &control_socket {
+            let _ = fs::remove_file(path);
+        }
+        if let Some(pid_file) = &pid_file {
+            let _ = fs::remove_file(pid_file);
+        }
+        for reply in waiting {
+            let _ = reply.send(match &failure {
+                Some(e) => Response::error(e.to_string()),
+                None => Response::message("Shut down".to_string()),
+            });
+        }
+        // Requests that were not answered yet fail, and every response is sent before exiting.
+        drop(events);
+        if let Some(listener) = listener {
+            listener.wait(Duration::from_secs(1));
+        }
+
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Print every problem of the file, and fail if there is any.
+    fn check(arguments: &ArgMatches) -> Result<(), Box<dyn Error>> {
+        let file = PathBuf::from(arguments.value_of_os(ARG_FILE).unwrap());
+        let diagnostics = check(&file, format(arguments, &file));
+
+        for diagnostic in &diagnostics {
+            match diagnostic.position {
+                Some(_) => println!("{}:{}", file.display(), diagnostic),
+                None => println!("{}: {}", file.display(), diagnostic),
+            }
+        }
+
+        match diagnostics.len() {
+            0 => Ok(()),
+            1 => Err("Found 1 problem".into()),
+            n => Err(format!("Found {} problems", n).into()),
+        }
+    }
+
+    /// Print the mappings of the gateways.
+    fn list(arguments: &ArgMatches) -> Result<(), Box<dyn Error>> {
+        let addresses = if arguments.is_present(ARG_ADDRESS) {
+            values_t!(arguments.values_of(ARG_ADDRESS), IpAddr).unwrap_or_else(|e| e.exit())
+        } else {
+            Vec::new()
+        };
+        let output = value_t!(arguments.value_of(ARG_OUTPUT), Output).unwrap_or_else(|e| e.exit());
+
+        let listings = list(&backends(arguments, default_nonces()), None, &addresses)?;
+        list::write(&listings, output, io::stdout().lock())?;
+        Ok(())
+    }
+
+    /// Map the port given on the command line, and print where it can be reached from outside.
+    fn add(arguments: &ArgMatches) -> Result<(), Box<dyn Error>> {
+        let options = Options {
+            address: arguments.value_of(ARG_ADDRESS).map(String::from),
+            port: port_range(arguments, ARG_PORT),
+            external_port: port_range(arguments, ARG_EXTERNAL_PORT),
+            internal_port: port_range(arguments, ARG_INTERNAL_PORT),
+            protocol: value_t!(arguments.value_of(ARG_PROTOCOL), Protocols)
+                .unwrap_or_else(|e| e.exit()),
+            duration: value_t!(arguments.value_of(ARG_DURATION), u32).unwrap_or_else(|e| e.exit()),
+            comment: arguments
+                .value_of(ARG_COMMENT)
+                .unwrap_or_default()
+                .to_string(),
+            backend: None,
+            gateway: None,
+            conflict: None,
+        };
+        let settings = Settings {
+            conflict: value_t!(arguments.value_of(ARG_CONFLICT), ConflictPolicy)
+                .unwrap_or_else(|e| e.exit()),
+            ..Default::default()
+        };
+        let mut backends = backends(arguments, default_nonces());
+        adopt_added(&backends);
+        let added = run(options, &mut backends, &settings);
+        save_added(&backends);
+
+        for mapping in added? {
+            // Pinholes let the IPv6 address itself be reached from outside.
+            let external = match mapping.internal {
+                SocketAddr::V4(_) => backends.get(None)?.external_ip(mapping.internal.ip()),
+                SocketAddr::V6(internal) => Ok(IpAddr::V6(*internal.ip())),
+            };
+            match external {
+                Ok(ip) => println!(
+                    "{} ({:?}) -> {}",
+                    SocketAddr::new(ip, mapping.external_port),
+                    mapping.protocol,
+                    mapping.internal
+                ),
+                Err(e) => {
+                    warn!("Cannot get the external address: {}", e);
+                    println!(
+                        "Port {} ({:?}) -> {}",
+                        mapping.external_port, mapping.protocol, mapping.internal
+                    );
+                }
             }
         }
 
         Ok(())
     }
+
+    /// Remove the mappings given on the command line, and print them.
+    fn remove(arguments: &ArgMatches) -> Result<(), Box<dyn Error>> {
+        let address = if arguments.is_present(ARG_ADDRESS) {
+            Some(value_t!(arguments.value_of(ARG_ADDRESS), IpAddr).unwrap_or_else(|e| e.exit()))
+        } else {
+            None
+        };
+        let removal = match (
+            arguments.value_of(ARG_COMMENT),
+            port_range(arguments, ARG_PORT),
+        ) {
+            (Some(comment), _) => Removal::Description(comment.to_string()),
+            (None, Some(ports)) => Removal::Ports(
+                ports,
+                value_t!(arguments.value_of(ARG_PROTOCOL), Protocols).unwrap_or_else(|e| e.exit()),
+            ),
+            (None, None) => return Err("Neither port nor comment is given".into()),
+        };
+
+        let mut backends = backends(arguments, default_nonces());
+        adopt_added(&backends);
+        let removed = remove(address, None, &removal, &mut backends);
+        save_added(&backends);
+
+        let removed = removed?;
+        for mapping in &removed {
+            println!("Removed {} ({:?})", mapping.external_port, mapping.protocol);
+        }
+
+        match removed.len() {
+            0 => Err("No mapping matched".into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Send the command given on the command line to the control socket, and print the answer.
+    fn control(arguments: &ArgMatches) -> Result<(), Box<dyn Error>> {
+        let socket = PathBuf::from(arguments.value_of_os(ARG_SOCKET).unwrap());
+        let request = match arguments.subcommand() {
+            (CMD_OPEN, Some(arguments)) => json!({
+                "command": CMD_OPEN,
+                "address": arguments.value_of(ARG_ADDRESS),
+                "port": port_range(arguments, ARG_PORT).map(|ports| ports.to_string()),
+                "external_port": port_range(arguments, ARG_EXTERNAL_PORT).map(|ports| ports.to_string()),
+                "internal_port": port_range(arguments, ARG_INTERNAL_PORT).map(|ports| ports.to_string()),
+                "protocol": value_t!(arguments.value_of(ARG_PROTOCOL), Protocols)
+                    .unwrap_or_else(|e| e.exit()),
+                "duration": value_t!(arguments.value_of(ARG_DURATION), u32)
+                    .unwrap_or_else(|e| e.exit()),
+                "comment": arguments.value_of(ARG_COMMENT),
+            }),
+            (CMD_CLOSE, Some(arguments)) => json!({
+                "command": CMD_CLOSE,
+                "address": if arguments.is_present(ARG_ADDRESS) {
+                    Some(value_t!(arguments.value_of(ARG_ADDRESS), IpAddr)
+                        .unwrap_or_else(|e| e.exit()))
+                } else {
+                    None
+                },
+                "port": port_range(arguments, ARG_PORT).map(|ports| ports.to_string()),
+                "protocol": value_t!(arguments.value_of(ARG_PROTOCOL), Protocols)
+                    .unwrap_or_else(|e| e.exit()),
+            }),
+            (command, _) => json!({ "command": command }),
+        };
+
+        let response = control::send(&socket, &request)
+            .map_err(|e| format!("Cannot talk to {}: {}", socket.display(), e))?;
+        for message in &response.messages {
+            println!("{}", message);
+        }
+        if !response.mappings.is_empty() {
+            print_status(&response.mappings);
+        }
+
+        match response.error {
+            Some(e) => Err(e.into()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Print the status of the mappings as a table, with the reason below each failed row.
+fn print_status(mappings: &[MappingStatus]) {
+    let now = unix_time(SystemTime::now());
+    println!(
+        "{:>4}  {:<15}  {:<11}  {:<8}  {:<6}  {:>12}  Comment",
+        "Row", "Address", "Ports", "Protocol", "Result", "Last attempt"
+    );
+    for mapping in mappings {
+        let mut comment = mapping.comment.clone();
+        if let Some(expires) = mapping.expires {
+            comment.push_str(&format!(" (expires in {}s)", expires.saturating_sub(now)));
+        }
+        println!(
+            "{:>4}  {:<15}  {:<11}  {:<8}  {:<6}  {:>12}  {}",
+            mapping
+                .row
+                .map_or_else(|| "-".to_string(), |row| row.to_string()),
+            mapping
+                .address
+                .as_deref()
+                .filter(|address| !address.is_empty())
+                .unwrap_or("any"),
+            mapping.ports,
+            format!("{:?}", mapping.protocol),
+            if mapping.error.is_some() {
+                "failed"
+            } else {
+                "ok"
+            },
+            format!("{}s ago", now.saturating_sub(mapping.time)),
+            comment
+        );
+        if let Some(e) = &mapping.error {
+            println!("      {}", e);
+        }
+    }
+}
+
+/// The port range of the given argument, if it is present.
+fn port_range(arguments: &ArgMatches, name: &str) -> Option<PortRange> {
+    if arguments.is_present(name) {
+        Some(value_t!(arguments.value_of(name), PortRange).unwrap_or_else(|e| e.exit()))
+    } else {
+        None
+    }
+}
+
+/// The arguments of a single mapping, for `add` and `control open`.
+fn mapping_args<'a, 'b>() -> [Arg<'a, 'b>; 7] {
+    [
+        Arg::with_name(ARG_ADDRESS)
+            .short("a")
+            .long(ARG_ADDRESS)
+            .help("The address to map the port to, instead of the local one")
+            .takes_value(true)
+            .number_of_values(1),
+        Arg::with_name(ARG_PORT)
+            .short("p")
+            .long(ARG_PORT)
+            .help("The port or range of ports, both external and internal")
+            .takes_value(true)
+            .number_of_values(1)
+            .required_unless_all(&[ARG_EXTERNAL_PORT, ARG_INTERNAL_PORT])
+            .conflicts_with_all(&[ARG_EXTERNAL_PORT, ARG_INTERNAL_PORT]),
+        Arg::with_name(ARG_EXTERNAL_PORT)
+            .long(ARG_EXTERNAL_PORT)
+            .help("The external port or range of ports")
+            .takes_value(true)
+            .number_of_values(1)
+            .requires(ARG_INTERNAL_PORT),
+        Arg::with_name(ARG_INTERNAL_PORT)
+            .long(ARG_INTERNAL_PORT)
+            .help("The internal port or range of ports")
+            .takes_value(true)
+            .number_of_values(1)
+            .requires(ARG_EXTERNAL_PORT),
+        protocol_arg().required(true),
+        Arg::with_name(ARG_DURATION)
+            .short("d")
+            .long(ARG_DURATION)
+            .help("The lease time in seconds, 0 for a permanent mapping")
+            .takes_value(true)
+            .number_of_values(1)
+            .default_value("3600"),
+        Arg::with_name(ARG_COMMENT)
+            .short("c")
+            .long(ARG_COMMENT)
+            .help("The description of the mapping")
+            .takes_value(true)
+            .number_of_values(1)
+            .default_value(crate_name!()),
+    ]
+}
+
+fn protocol_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(ARG_PROTOCOL)
+        .short("P")
+        .long(ARG_PROTOCOL)
+        .help("TCP, UDP or BOTH")
+        .takes_value(true)
+        .number_of_values(1)
+}
+
+fn conflict_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(ARG_CONFLICT)
+        .long(ARG_CONFLICT)
+        .help("What to do if a port is already mapped to another client")
+        .takes_value(true)
+        .number_of_values(1)
+        .possible_values(&["steal", "skip-and-warn", "fail", "steal-only-if-ours"])
+        .default_value("steal")
+}
+
+fn backend_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(ARG_BACKEND)
+        .short("b")
+        .long(ARG_BACKEND)
+        .takes_value(true)
+        .number_of_values(1)
+        .possible_values(&["upnp", "natpmp", "pcp", "auto"])
+        .default_value("upnp")
+}
+
+fn ssdp_address_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(ARG_SSDP_ADDRESS)
+        .long(ARG_SSDP_ADDRESS)
+        .help("Send UPnP searches to this address instead of the SSDP multicast group")
+        .takes_value(true)
+        .number_of_values(1)
+        .default_value("239.255.255.250:1900")
+}
+
+fn discovery_timeout_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name(ARG_DISCOVERY_TIMEOUT)
+        .long(ARG_DISCOVERY_TIMEOUT)
+        .help("Specify how long to wait for UPnP gateways in seconds")
+        .takes_value(true)
+        .number_of_values(1)
+        .default_value("10")
+}
+
+/// The backends with the default backend and discovery settings of the command line.
+fn backends(arguments: &ArgMatches, nonces: Nonces) -> Backends {
+    let backend =
+        value_t!(arguments.value_of(ARG_BACKEND), BackendKind).unwrap_or_else(|e| e.exit());
+    let discovery = Discovery {
+        ssdp_address: value_t!(arguments.value_of(ARG_SSDP_ADDRESS), SocketAddr)
+            .unwrap_or_else(|e| e.exit()),
+        timeout: Duration::from_secs(
+            value_t!(arguments.value_of(ARG_DISCOVERY_TIMEOUT), u64).unwrap_or_else(|e| e.exit()),
+        ),
+    };
+    Backends::new(backend, discovery, nonces)
+}
+
+fn pid_file(daemon: &Daemon) -> PathBuf {
+    daemon
+        .pid_file
+        .clone()
+        .unwrap_or_else(|| format!("/tmp/{}.pid", crate_name!()).into())
+}
+
+/// The PCP nonces are kept in the state directory, so that the mappings of an earlier run can
+/// still be renewed and deleted.
+fn pcp_nonce_file(daemon: &Daemon) -> Option<PathBuf> {
+    daemon
+        .pcp_nonce_file
+        .clone()
+        .or_else(|| state::file("pcp-nonces.json"))
+}
+
+/// Where the `add` subcommand keeps the mappings as the gateways made them, so that `remove`
+/// can find them by the external port that was printed.
+fn added_file() -> Option<PathBuf> {
+    state::file("added.json")
+}
+
+/// Take over the mappings of earlier `add` subcommands.
+fn adopt_added(backends: &Backends) {
+    let file = match added_file() {
+        Some(file) => file,
+        None => return,
+    };
+    let added: Vec<(BackendKind, Mapping)> = match state::read(&file) {
+        Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+            warn!("Ignoring {}: {}", file.display(), e);
+            Vec::new()
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => {
+            warn!("Ignoring {}: {}", file.display(), e);
+            Vec::new()
+        }
+    };
+    for (kind, mapping) in added {
+        backends.adopt(kind, mapping);
+    }
+}
+
+fn save_added(backends: &Backends) {
+    let file = match added_file() {
+        Some(file) => file,
+        None => return,
+    };
+    let added: Vec<_> = backends.owned().collect();
+    if added.is_empty() {
+        let _ = fs::remove_file(&file);
+        return;
+    }
+    let result = serde_json::to_string_pretty(&added)
+        .map_err(io::Error::from)
+        .and_then(|content| state::write(&file, &(content + "\n")));
+    if let Err(e) = result {
+        warn!("Cannot save {}: {}", file.display(), e);
+    }
+}
+
+/// The PCP nonces of a daemon with the default settings, which the subcommands share.
+fn default_nonces() -> Nonces {
+    pcp_nonce_file(&Daemon::default()).map_or_else(Nonces::default, Nonces::load)
+}
+
+/// The interval given on the command line, or the one of the daemon section.
+fn interval(arguments: &ArgMatches, daemon: &Daemon) -> Duration {
+    Duration::from_secs(if arguments.is_present(ARG_INTERVAL) {
+        value_t!(arguments.value_of(ARG_INTERVAL), u64).unwrap_or_else(|e| e.exit())
+    } else {
+        daemon.interval.unwrap_or(60)
+    })
+}
+
+/// Read the daemon section of `file` again, apply its logging settings and return the interval.
+fn reload(
+    file: &Path,
+    format: Format,
+    arguments: &ArgMatches,
+    foreground: bool,
+) -> Result<Duration, crate::error::Error> {
+    let config = Config::read(file, format)?;
+    apply_logging(&config.daemon, foreground);
+    Ok(interval(arguments, &config.daemon))
+}
+
+/// The control socket of the command line or the daemon section, with its permissions.
+fn control_socket(
+    arguments: &ArgMatches,
+    daemon: &Daemon,
+) -> Result<Option<(PathBuf, u32)>, Box<dyn Error>> {
+    let path = match arguments
+        .value_of_os(ARG_CONTROL_SOCKET)
+        .map(PathBuf::from)
+        .or_else(|| daemon.control_socket.clone())
+    {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let mode = arguments
+        .value_of(ARG_CONTROL_SOCKET_MODE)
+        .or(daemon.control_socket_mode.as_deref())
+        .unwrap_or("600");
+    let mode = u32::from_str_radix(mode, 8)
+        .map_err(|_| format!("Invalid mode of the control socket: {}", mode))?;
+
+    // Daemonizing changes the working directory.
+    Ok(Some((env::current_dir()?.join(path), mode)))
+}
+
+/// Map the ports of an `open` request, and keep them in the following iterations until they
+/// expire.
+fn open(
+    options: Options,
+    backends: &mut Backends,
+    settings: &Settings,
+    temporary: &mut Vec<Temporary>,
+) -> Response {
+    let expires = match options.duration {
+        0 => None,
+        duration => Some(SystemTime::now() + Duration::from_secs(duration.into())),
+    };
+    match run(options.clone(), backends, settings) {
+        Ok(mappings) => {
+            temporary.push(Temporary {
+                status: MappingStatus {
+                    expires: expires.map(unix_time),
+                    ..row_status(&options, None)
+                },
+                options,
+                expires,
+            });
+            Response {
+                messages: mappings
+                    .iter()
+                    .map(|m| {
+                        format!(
+                            "Mapped {} ({:?}) to {}",
+                            m.external_port, m.protocol, m.internal
+                        )
+                    })
+                    .collect(),
+                ..Default::default()
+            }
+        }
+        Err(e) => Response::error(e.to_string()),
+    }
+}
+
+/// Forget the protocols that a `close` request removed from the temporary mappings of `port`.
+/// A mapping of both protocols keeps the other one.
+fn close(temporary: &mut Vec<Temporary>, port: PortRange, protocol: Protocols) {
+    *temporary = temporary
+        .drain(..)
+        .filter_map(|mut t| {
+            if t.options.external_port.or(t.options.port) != Some(port) {
+                return Some(t);
+            }
+            let left = t.options.protocol.without(protocol)?;
+            t.options.protocol = left;
+            t.status.protocol = left;
+            Some(t)
+        })
+        .collect();
+}
+
+/// The status of each row after an iteration.
+fn mapping_status(
+    rows: &[Options],
+    result: &Result<Report, crate::error::Error>,
+) -> Vec<MappingStatus> {
+    rows.iter()
+        .enumerate()
+        .map(|(index, options)| {
+            let error = match result {
+                Ok(report) => report
+                    .failed
+                    .iter()
+                    .find(|(row, _)| *row == index + 1)
+                    .map(|(_, e)| e.to_string()),
+                Err(e) => Some(e.to_string()),
+            };
+            MappingStatus {
+                row: Some(index + 1),
+                ..row_status(options, error)
+            }
+        })
+        .collect()
+}
+
+/// The status of a row that was attempted just now.
+fn row_status(options: &Options, error: Option<String>) -> MappingStatus {
+    MappingStatus {
+        row: None,
+        address: options.address.clone(),
+        ports: match (options.port, options.external_port, options.internal_port) {
+            (Some(ports), _, _) => ports.to_string(),
+            (None, Some(external), Some(internal)) if external != internal => {
+                format!("{}->{}", external, internal)
+            }
+            (None, Some(external), _) => external.to_string(),
+            _ => String::new(),
+        },
+        protocol: options.protocol,
+        comment: options.comment.clone(),
+        error,
+        time: unix_time(SystemTime::now()),
+        expires: None,
+    }
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+/// The log filter of `RUST_LOG`, or the log level of the daemon section.
+fn log_filter(daemon: &Daemon) -> String {
+    env::var("RUST_LOG")
+        .ok()
+        .or_else(|| daemon.log_level.clone())
+        .unwrap_or_default()
+}
+
+/// Use the log level and, in daemon mode, the log file of a reloaded daemon section.
+fn apply_logging(daemon: &Daemon, foreground: bool) {
+    logger::set_filter(&log_filter(daemon));
+
+    if let (false, Some(log_file)) = (foreground, &daemon.log_file) {
+        if let Err(e) = redirect_output(log_file) {
+            warn!("Cannot open {}: {}", log_file.display(), e);
+        }
+    }
+}
+
+/// Point stdout and stderr to `log_file`, like daemonizing does. Reopening the file after it was
+/// rotated lets the daemon write to the new one.
+fn redirect_output(log_file: &Path) -> io::Result<()> {
+    let log_file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file)?;
+    for fd in &[libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(log_file.as_raw_fd(), *fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Log the current settings and every mapping that the daemon added.
+fn dump_state(file: &Path, interval: Duration, next_refresh: Instant, backends: &Backends) {
+    info!("Mapping file: {}", file.display());
+    info!(
+        "Interval: {}s, next refresh in {}s",
+        interval.as_secs(),
+        next_refresh
+            .saturating_duration_since(Instant::now())
+            .as_secs()
+    );
+    info!("Owned mappings: {}", backends.owned().count());
+    for (kind, mapping) in backends.owned() {
+        info!(
+            "  {} ({:?}) to {} via {:?}, duration {}, comment {:?}",
+            mapping.external_port,
+            mapping.protocol,
+            mapping.internal,
+            kind,
+            mapping.duration,
+            mapping.comment
+        );
+    }
+}
+
+/// The format given on the command line, or the one guessed from the extension of `file`.
+fn format(arguments: &ArgMatches, file: &Path) -> Format {
+    if arguments.is_present(ARG_FORMAT) {
+        value_t!(arguments.value_of(ARG_FORMAT), Format).unwrap_or_else(|e| e.exit())
+    } else {
+        Format::from_path(file)
+    }
 }
@@ -0,0 +1,118 @@
+//! The mapping file, either in the original CSV format or as a structured TOML, YAML or JSON
+//! document with an additional daemon section.
+
+use std::error::Error;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::Options;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    Csv,
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl Format {
+    /// Guess the format from the file extension, falling back to CSV.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("yaml") | Some("yml") => Format::Yaml,
+            Some("json") => Format::Json,
+            _ => Format::Csv,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "toml" => Ok(Format::Toml),
+            "yaml" => Ok(Format::Yaml),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("Unknown format: {}", s)),
+        }
+    }
+}
+
+/// Settings of the daemon itself. Command line arguments take precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Daemon {
+    /// Update interval in seconds.
+    pub interval: Option<u64>,
+    pub pid_file: Option<PathBuf>,
+    /// Where the nonces of PCP mappings are kept, in the state directory by default.
+    pub pcp_nonce_file: Option<PathBuf>,
+    /// Log filter in the syntax of `RUST_LOG`, which takes precedence.
+    pub log_level: Option<String>,
+    /// Where the output goes in daemon mode.
+    pub log_file: Option<PathBuf>,
+    /// Where to listen for control commands, none to not listen at all.
+    pub control_socket: Option<PathBuf>,
+    /// The permissions of the control socket, in octal like `660`.
+    pub control_socket_mode: Option<String>,
+    /// Serve metrics for Prometheus on this port of localhost, none to not serve them.
+    pub metrics_port: Option<u16>,
+    /// Where the last known external addresses are kept, by default in the state directory if
+    /// there is a hook or dynamic DNS.
+    pub external_ip_file: Option<PathBuf>,
+    /// A shell command to run when an external address changes.
+    pub on_ip_change: Option<String>,
+    /// Records to update when an external address changes.
+    pub ddns: Option<crate::ddns::Config>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub daemon: Daemon,
+    #[serde(default)]
+    pub mappings: Vec<Options>,
+}
+
+impl Config {
+    pub fn read(path: &Path, format: Format) -> Result<Self, crate::error::Error> {
+        Self::parse(path, format).map_err(|e| match e.downcast::<crate::error::Error>() {
+            Ok(e) => *e,
+            Err(e) => crate::error::Error::Config(e.to_string()),
+        })
+    }
+
+    fn parse(path: &Path, format: Format) -> Result<Self, Box<dyn Error>> {
+        match format {
+            Format::Csv => Ok(Config {
+                daemon: Daemon::default(),
+                mappings: read_csv(File::open(path)?)?,
+            }),
+            Format::Toml => Ok(toml::from_str(&fs::read_to_string(path)?)?),
+            Format::Yaml => Ok(serde_yaml::from_str(&fs::read_to_string(path)?)?),
+            Format::Json => Ok(serde_json::from_str(&fs::read_to_string(path)?)?),
+        }
+    }
+}
+
+/// Read the rows of a mapping file in CSV format.
+pub fn read_csv<R: io::Read>(reader: R) -> Result<Vec<Options>, crate::error::Error> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(b';')
+        .from_reader(reader);
+
+    let mut rows = Vec::new();
+    for result in rdr.deserialize() {
+        rows.push(result?);
+    }
+
+    Ok(rows)
+}
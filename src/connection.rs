@@ -0,0 +1,218 @@
+//! The port mapping actions of the connection service of a UPnP gateway.
+//!
+//! `igd` sends every action to version 1 of `WANIPConnection`, which gateways that only offer
+//! version 2 or `WANPPPConnection` refuse. The actions are sent here with the service type that
+//! was found on the gateway instead, while the errors keep the types of `igd`.
+
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::str::FromStr;
+
+use igd::{
+    AddPortError, Gateway, GetExternalIpError, GetGenericPortMappingEntryError, RemovePortError,
+    RequestError,
+};
+use xmltree::Element;
+
+use crate::backend::PortMappingEntry;
+use crate::soap;
+use crate::PortMappingProtocol;
+
+/// A gateway together with the connection service to use on it.
+#[derive(Clone, Debug)]
+pub struct Connection {
+    pub gateway: Gateway,
+    /// The type of the connection service, one of `discovery::SERVICE_TYPES`.
+    pub service_type: String,
+}
+
+impl Connection {
+    /// Whether the service offers `action`, according to its control schema.
+    pub fn supports(&self, action: &str) -> bool {
+        self.gateway.control_schema.contains_key(action)
+    }
+
+    fn call(&self, action: &str, arguments: &[(&str, String)]) -> Result<Element, RequestError> {
+        soap::call(
+            &format!("http://{}{}", self.gateway.addr, self.gateway.control_url),
+            &self.service_type,
+            action,
+            arguments,
+        )
+        .map_err(|e| match e {
+            soap::Error::Http(e) => RequestError::AttoHttpError(e),
+            soap::Error::Xml(e) => RequestError::InvalidResponse(e.to_string()),
+            soap::Error::InvalidResponse => RequestError::InvalidResponse(String::new()),
+            soap::Error::Upnp(code, description) => RequestError::ErrorCode(code, description),
+        })
+    }
+
+    pub fn add_port(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+        internal: SocketAddrV4,
+        lease_duration: u32,
+        description: &str,
+    ) -> Result<(), AddPortError> {
+        if external_port == 0 {
+            return Err(AddPortError::ExternalPortZeroInvalid);
+        }
+        if internal.port() == 0 {
+            return Err(AddPortError::InternalPortZeroInvalid);
+        }
+
+        self.call(
+            "AddPortMapping",
+            &[
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", external_port.to_string()),
+                ("NewProtocol", protocol_name(protocol)),
+                ("NewInternalPort", internal.port().to_string()),
+                ("NewInternalClient", internal.ip().to_string()),
+                ("NewEnabled", "1".to_string()),
+                ("NewPortMappingDescription", description.to_string()),
+                ("NewLeaseDuration", lease_duration.to_string()),
+            ],
+        )
+        .map(|_| ())
+        .map_err(|e| match e {
+            RequestError::ErrorCode(605, _) => AddPortError::DescriptionTooLong,
+            RequestError::ErrorCode(606, _) => AddPortError::ActionNotAuthorized,
+            RequestError::ErrorCode(718, _) => AddPortError::PortInUse,
+            RequestError::ErrorCode(724, _) => AddPortError::SamePortValuesRequired,
+            RequestError::ErrorCode(725, _) => AddPortError::OnlyPermanentLeasesSupported,
+            e => AddPortError::RequestError(e),
+        })
+    }
+
+    pub fn remove_port(
+        &self,
+        protocol: PortMappingProtocol,
+        external_port: u16,
+    ) -> Result<(), RemovePortError> {
+        self.call(
+            "DeletePortMapping",
+            &[
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", external_port.to_string()),
+                ("NewProtocol", protocol_name(protocol)),
+            ],
+        )
+        .map(|_| ())
+        .map_err(|e| match e {
+            RequestError::ErrorCode(606, _) => RemovePortError::ActionNotAuthorized,
+            RequestError::ErrorCode(714, _) => RemovePortError::NoSuchPortMapping,
+            e => RemovePortError::RequestError(e),
+        })
+    }
+
+    /// Remove the mappings of the ports from `start` to `end` in one request, which version 2 of
+    /// `WANIPConnection` supports. The gateway is asked to leave mappings of other clients alone.
+    pub fn remove_port_range(
+        &self,
+        protocol: PortMappingProtocol,
+        start: u16,
+        end: u16,
+    ) -> Result<(), RequestError> {
+        self.call(
+            "DeletePortMappingRange",
+            &[
+                ("NewStartPort", start.to_string()),
+                ("NewEndPort", end.to_string()),
+                ("NewProtocol", protocol_name(protocol)),
+                ("NewManage", "0".to_string()),
+            ],
+        )
+        .map(|_| ())
+    }
+
+    pub fn get_external_ip(&self) -> Result<Ipv4Addr, GetExternalIpError> {
+        let response = self
+            .call("GetExternalIPAddress", &[])
+            .map_err(|e| match e {
+                RequestError::ErrorCode(606, _) => GetExternalIpError::ActionNotAuthorized,
+                e => GetExternalIpError::RequestError(e),
+            })?;
+
+        text(&response, "NewExternalIPAddress")
+            .and_then(|ip| ip.parse().ok())
+            .ok_or_else(|| {
+                GetExternalIpError::RequestError(invalid("NewExternalIPAddress is invalid"))
+            })
+    }
+
+    /// The entry at `index` of the port mapping table.
+    pub fn get_generic_port_mapping_entry(
+        &self,
+        index: u32,
+    ) -> Result<PortMappingEntry, GetGenericPortMappingEntryError> {
+        let response = self
+            .call(
+                "GetGenericPortMappingEntry",
+                &[("NewPortMappingIndex", index.to_string())],
+            )
+            .map_err(|e| match e {
+                RequestError::ErrorCode(606, _) => {
+                    GetGenericPortMappingEntryError::ActionNotAuthorized
+                }
+                RequestError::ErrorCode(713, _) => {
+                    GetGenericPortMappingEntryError::SpecifiedArrayIndexInvalid
+                }
+                e => GetGenericPortMappingEntryError::RequestError(e),
+            })?;
+
+        Ok(PortMappingEntry {
+            external_port: number(&response, "NewExternalPort")?,
+            protocol: match field(&response, "NewProtocol")?.as_str() {
+                "TCP" => PortMappingProtocol::TCP,
+                "UDP" => PortMappingProtocol::UDP,
+                _ => {
+                    return Err(GetGenericPortMappingEntryError::RequestError(invalid(
+                        "NewProtocol is invalid",
+                    )))
+                }
+            },
+            internal_port: number(&response, "NewInternalPort")?,
+            internal_client: field(&response, "NewInternalClient")?,
+            enabled: field(&response, "NewEnabled")? == "1",
+            description: text(&response, "NewPortMappingDescription").unwrap_or_default(),
+            lease_duration: number(&response, "NewLeaseDuration")?,
+        })
+    }
+}
+
+impl fmt::Display for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.gateway.fmt(f)
+    }
+}
+
+fn protocol_name(protocol: PortMappingProtocol) -> String {
+    igd::PortMappingProtocol::from(protocol).to_string()
+}
+
+/// The trimmed text of the child `name` of a response, if it has any.
+fn text(response: &Element, name: &str) -> Option<String> {
+    Some(response.get_child(name)?.get_text()?.trim().to_string())
+}
+
+/// A required field of a port mapping entry.
+fn field(response: &Element, name: &str) -> Result<String, GetGenericPortMappingEntryError> {
+    text(response, name).ok_or_else(|| {
+        GetGenericPortMappingEntryError::RequestError(invalid(&format!("{} is missing", name)))
+    })
+}
+
+fn number<T: FromStr>(
+    response: &Element,
+    name: &str,
+) -> Result<T, GetGenericPortMappingEntryError> {
+    field(response, name)?.parse().map_err(|_| {
+        GetGenericPortMappingEntryError::RequestError(invalid(&format!("{} is invalid", name)))
+    })
+}
+
+fn invalid(message: &str) -> RequestError {
+    RequestError::InvalidResponse(message.to_string())
+}
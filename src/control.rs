@@ -0,0 +1,224 @@
+//! The control socket of a running daemon.
+//!
+//! Every request is a JSON object on a line of its own, with the name of the command in the
+//! `command` field, and is answered with a JSON object on a line of its own. A connection can
+//! send several requests, one after another.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::process;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{Options, PortRange, Protocols};
+
+/// How long a connection may stay idle before it is closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    /// The result of the last attempt of each mapping.
+    Status,
+    /// Refresh the mappings right away, without waiting for the interval.
+    Refresh,
+    /// Reread the daemon section of the mapping file, like `SIGHUP`.
+    Reload,
+    /// Map a port that is not in the mapping file. It is refreshed like the rows of the file
+    /// until its duration is over, or until the daemon stops if the duration is 0.
+    Open(Options),
+    /// Remove a mapping by external port, and forget it if it was opened temporarily.
+    Close {
+        #[serde(default)]
+        address: Option<IpAddr>,
+        port: PortRange,
+        protocol: Protocols,
+    },
+    /// Remove the mappings and stop, like `SIGTERM`.
+    Shutdown,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Response {
+    /// Why the command failed, if it did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// What the command did, one line each.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub messages: Vec<String>,
+    /// The mappings of the last refresh, for `status`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mappings: Vec<MappingStatus>,
+}
+
+impl Response {
+    pub fn message(message: String) -> Self {
+        Response {
+            messages: vec![message],
+            ..Default::default()
+        }
+    }
+
+    pub fn error(error: String) -> Self {
+        Response {
+            error: Some(error),
+            ..Default::default()
+        }
+    }
+}
+
+/// How the last attempt of a row went.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MappingStatus {
+    /// The number of the row in the mapping file, none for a temporary mapping.
+    pub row: Option<usize>,
+    pub address: Option<String>,
+    /// The external ports, and the internal ones if they differ.
+    pub ports: String,
+    pub protocol: Protocols,
+    pub comment: String,
+    /// The reason the last attempt failed, none if it succeeded.
+    pub error: Option<String>,
+    /// When the row was last attempted, in seconds since the Unix epoch.
+    pub time: u64,
+    /// When a temporary mapping is removed, in seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+}
+
+/// Keeps track of the requests that are being answered.
+#[derive(Clone, Default)]
+pub struct Listener {
+    busy: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl Listener {
+    /// Wait until the responses to all requests have been sent, but at most for `timeout`.
+    pub fn wait(&self, timeout: Duration) {
+        let (busy, idle) = &*self.busy;
+        let _ = idle.wait_timeout_while(busy.lock().unwrap(), timeout, |busy| *busy > 0);
+    }
+
+    fn start(&self) {
+        *self.busy.0.lock().unwrap() += 1;
+    }
+
+    fn finish(&self) {
+        let (busy, idle) = &*self.busy;
+        *busy.lock().unwrap() -= 1;
+        idle.notify_all();
+    }
+}
+
+/// Listen on a socket at `path` with the given permissions, and answer every request with
+/// `handle`. A socket file that no daemon listens on anymore is replaced.
+///
+/// Every connection is served by its own thread, and closed if the client does not send a request
+/// for a while.
+pub fn listen<F>(path: &Path, mode: u32, handle: F) -> io::Result<Listener>
+where
+    F: Fn(Request) -> Response + Send + Sync + 'static,
+{
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("Another daemon listens on {}", path.display()),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+
+    let socket = bind(path, mode)?;
+    let handle = Arc::new(handle);
+    let listener = Listener::default();
+
+    let busy = listener.clone();
+    thread::spawn(move || {
+        for stream in socket.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let (handle, busy) = (handle.clone(), busy.clone());
+                    thread::spawn(move || {
+                        if let Err(e) = serve(stream, &*handle, &busy) {
+                            debug!("Control connection failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("Cannot accept a control connection: {}", e),
+            }
+        }
+    });
+
+    Ok(listener)
+}
+
+/// Bind a socket at `path` with the given permissions. It is created in a private directory and
+/// only moved to `path` once it has them, so that nobody can connect in between.
+fn bind(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let dir = path.with_file_name(format!(".{}.{}", name, process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let private = dir.join("socket");
+    let result = UnixListener::bind(&private).and_then(|listener| {
+        fs::set_permissions(&private, fs::Permissions::from_mode(mode))?;
+        fs::rename(&private, path)?;
+        Ok(listener)
+    });
+
+    let _ = fs::remove_file(&private);
+    fs::remove_dir(&dir)?;
+    result
+}
+
+fn serve<F: Fn(Request) -> Response>(
+    stream: UnixStream,
+    handle: &F,
+    busy: &Listener,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        busy.start();
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => handle(request),
+            Err(e) => Response::error(format!("Invalid request: {}", e)),
+        };
+        let written = serde_json::to_writer(&mut writer, &response)
+            .map_err(io::Error::from)
+            .and_then(|()| writeln!(writer));
+        busy.finish();
+        written?;
+    }
+    Ok(())
+}
+
+/// Send a request to the daemon listening at `path`, and wait for its response.
+pub fn send(path: &Path, request: &serde_json::Value) -> io::Result<Response> {
+    let mut stream = UnixStream::connect(path)?;
+    serde_json::to_writer(&mut stream, request)?;
+    writeln!(stream)?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
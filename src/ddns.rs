@@ -0,0 +1,539 @@
+//! A minimal dynamic DNS client, which sends UPDATE messages (RFC 2136) signed with TSIG
+//! (RFC 8945) to the primary name server of a zone.
+//!
+//! Every update replaces the addresses of the configured records with a single new one: an `A`
+//! record for an IPv4 address, an `AAAA` record for an IPv6 address. The update only counts as
+//! done if the server signed its response with the same key, so that a forged response cannot
+//! hide a failed update.
+
+use std::convert::TryFrom;
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::debug;
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+
+use crate::hmac::hmac_sha256;
+
+/// The only TSIG algorithm that is supported.
+pub const ALGORITHM: &str = "hmac-sha256";
+
+/// How far the clock of the server may be off, in seconds, as recommended by RFC 8945.
+pub const FUDGE: u16 = 300;
+
+const OPCODE_UPDATE: u16 = 5;
+const FLAG_RESPONSE: u16 = 0x8000;
+
+const TYPE_A: u16 = 1;
+const TYPE_SOA: u16 = 6;
+const TYPE_AAAA: u16 = 28;
+const TYPE_TSIG: u16 = 250;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+const ATTEMPTS: u32 = 3;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Timeout,
+    InvalidName(String),
+    /// More records than fit into one message.
+    TooManyRecords(usize),
+    InvalidResponse,
+    /// The response has no TSIG record.
+    Unsigned,
+    /// The TSIG record of the response does not match the key, or is too old.
+    BadSignature,
+    /// The server answered with this result code, or this TSIG error.
+    Rejected(u16),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "DNS update I/O error: {}", e),
+            Error::Timeout => write!(f, "DNS server did not answer"),
+            Error::InvalidName(name) => write!(f, "Invalid domain name: {}", name),
+            Error::TooManyRecords(count) => {
+                write!(f, "Too many records to update at once: {}", count)
+            }
+            Error::InvalidResponse => write!(f, "DNS server sent an invalid response"),
+            Error::Unsigned => write!(f, "DNS server did not sign its response"),
+            Error::BadSignature => write!(f, "DNS server response has an invalid signature"),
+            Error::Rejected(rcode) => match rcode_name(*rcode) {
+                Some(name) => write!(f, "DNS server rejected the update: {}", name),
+                None => write!(f, "DNS server returned result code {}", rcode),
+            },
+        }
+    }
+}
+
+impl StdError for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Error::Timeout,
+            _ => Error::Io(e),
+        }
+    }
+}
+
+fn rcode_name(rcode: u16) -> Option<&'static str> {
+    match rcode {
+        1 => Some("FORMERR"),
+        2 => Some("SERVFAIL"),
+        3 => Some("NXDOMAIN"),
+        4 => Some("NOTIMP"),
+        5 => Some("REFUSED"),
+        6 => Some("YXDOMAIN"),
+        7 => Some("YXRRSET"),
+        8 => Some("NXRRSET"),
+        9 => Some("NOTAUTH"),
+        10 => Some("NOTZONE"),
+        16 => Some("BADSIG"),
+        17 => Some("BADKEY"),
+        18 => Some("BADTIME"),
+        _ => None,
+    }
+}
+
+/// The records to update, and how.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The primary name server of the zone, like `192.0.2.53:53`.
+    pub server: SocketAddr,
+    pub zone: String,
+    /// The names of the records, relative to the zone unless they end with a dot. `@` is the
+    /// zone itself.
+    pub records: Vec<String>,
+    /// The time to live of the records in seconds.
+    #[serde(default = "default_ttl")]
+    pub ttl: u32,
+    /// The name of the TSIG key, as the server knows it.
+    pub key_name: String,
+    /// The secret of the TSIG key, in base64 like `tsig-keygen` prints it.
+    pub key_secret: Secret,
+}
+
+fn default_ttl() -> u32 {
+    60
+}
+
+/// A TSIG secret, which is never printed.
+#[derive(Clone)]
+pub struct Secret(Vec<u8>);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret(..)")
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        match decode_base64(&text) {
+            Some(secret) if !secret.is_empty() => Ok(Secret(secret)),
+            _ => Err(de::Error::custom("the key secret is not valid base64")),
+        }
+    }
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut bits = 0u32;
+    let mut count = 0;
+    for c in text.trim().trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | u32::from(value);
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+/// Point the records of `config` to `address`.
+pub fn update(config: &Config, address: IpAddr) -> Result<(), Error> {
+    let id = rand::random();
+    let (request, request_mac) = signed_message(config, address, id, now())?;
+
+    let local: SocketAddr = match config.server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(config.server)?;
+    socket.set_read_timeout(Some(TIMEOUT))?;
+
+    for attempt in 1..=ATTEMPTS {
+        debug!(
+            "Sending a DNS update to {}, attempt {}",
+            config.server, attempt
+        );
+        socket.send(&request)?;
+
+        let mut buf = [0u8; 512];
+        loop {
+            let read = match socket.recv(&mut buf) {
+                Ok(read) => read,
+                Err(e) => match Error::from(e) {
+                    Error::Timeout => break,
+                    e => return Err(e),
+                },
+            };
+            if read >= 2 && buf[..2] == id.to_be_bytes() {
+                return check_response(&buf[..read], config, &request_mac);
+            }
+            debug!("Ignoring a DNS response to another request");
+        }
+    }
+
+    Err(Error::Timeout)
+}
+
+/// Check the response to a request that was signed with `request_mac`. Errors are taken as they
+/// are, but success only with a valid signature.
+fn check_response(response: &[u8], config: &Config, request_mac: &[u8]) -> Result<(), Error> {
+    if response.len() < 12 {
+        return Err(Error::InvalidResponse);
+    }
+    let flags = u16::from_be_bytes([response[2], response[3]]);
+    if flags & FLAG_RESPONSE == 0 || (flags >> 11) & 0xf != OPCODE_UPDATE {
+        return Err(Error::InvalidResponse);
+    }
+
+    let tsig = find_tsig(response)?;
+    match (flags & 0xf, tsig) {
+        (_, Some(tsig)) if tsig.error != 0 => Err(Error::Rejected(tsig.error)),
+        (0, Some(tsig)) => verify(response, &tsig, config, request_mac),
+        (0, None) => Err(Error::Unsigned),
+        (rcode, _) => Err(Error::Rejected(rcode)),
+    }
+}
+
+/// The TSIG record at the end of a message.
+struct Tsig<'a> {
+    /// Where the record starts.
+    start: usize,
+    key: &'a [u8],
+    algorithm: &'a [u8],
+    time: &'a [u8],
+    fudge: u16,
+    mac: &'a [u8],
+    original_id: &'a [u8],
+    error: u16,
+    other: &'a [u8],
+}
+
+/// Find the TSIG record, which is the last additional record if there is one.
+fn find_tsig(message: &[u8]) -> Result<Option<Tsig<'_>>, Error> {
+    let count =
+        |index: usize| usize::from(u16::from_be_bytes([message[index], message[index + 1]]));
+    let (questions, records, additional) = (count(4), count(6) + count(8), count(10));
+    if additional == 0 {
+        return Ok(None);
+    }
+
+    let mut reader = Reader {
+        message,
+        position: 12,
+    };
+    for _ in 0..questions {
+        reader.name()?;
+        reader.take(4)?;
+    }
+    for _ in 0..records + additional - 1 {
+        reader.name()?;
+        reader.take(8)?;
+        let length = reader.u16()?;
+        reader.take(usize::from(length))?;
+    }
+
+    let start = reader.position;
+    let key = reader.name()?;
+    if reader.u16()? != TYPE_TSIG {
+        return Ok(None);
+    }
+    reader.take(6)?;
+    let length = usize::from(reader.u16()?);
+    if reader.position + length != message.len() {
+        return Err(Error::InvalidResponse);
+    }
+
+    let algorithm = reader.name()?;
+    let time = reader.take(6)?;
+    let fudge = reader.u16()?;
+    let mac_size = usize::from(reader.u16()?);
+    let mac = reader.take(mac_size)?;
+    let original_id = reader.take(2)?;
+    let error = reader.u16()?;
+    let other_size = usize::from(reader.u16()?);
+    let other = reader.take(other_size)?;
+
+    Ok(Some(Tsig {
+        start,
+        key,
+        algorithm,
+        time,
+        fudge,
+        mac,
+        original_id,
+        error,
+        other,
+    }))
+}
+
+/// Reads the parts of a message one after another.
+struct Reader<'a> {
+    message: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], Error> {
+        let part = self
+            .message
+            .get(self.position..self.position + length)
+            .ok_or(Error::InvalidResponse)?;
+        self.position += length;
+        Ok(part)
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// A name in wire format, which ends with a pointer if it is compressed.
+    fn name(&mut self) -> Result<&'a [u8], Error> {
+        let start = self.position;
+        loop {
+            let length = self.take(1)?[0];
+            match length {
+                0 => break,
+                length if length & 0xc0 == 0xc0 => {
+                    self.take(1)?;
+                    break;
+                }
+                length => {
+                    self.take(usize::from(length))?;
+                }
+            }
+        }
+        Ok(&self.message[start..self.position])
+    }
+}
+
+/// Verify the signature of a response, as described in section 5.3 of RFC 8945.
+fn verify(response: &[u8], tsig: &Tsig, config: &Config, request_mac: &[u8]) -> Result<(), Error> {
+    let mut key = Vec::new();
+    encode_name(&mut key, &config.key_name.to_ascii_lowercase())?;
+    let mut algorithm = Vec::new();
+    encode_name(&mut algorithm, ALGORITHM)?;
+    if tsig.key.to_ascii_lowercase() != key || tsig.algorithm.to_ascii_lowercase() != algorithm {
+        return Err(Error::BadSignature);
+    }
+
+    // The response is signed after the MAC of the request, with its original ID and without the
+    // TSIG record.
+    let mut signed = Vec::new();
+    signed.extend_from_slice(&(request_mac.len() as u16).to_be_bytes());
+    signed.extend_from_slice(request_mac);
+    signed.extend_from_slice(tsig.original_id);
+    signed.extend_from_slice(&response[2..tsig.start]);
+    let additional = u16::from_be_bytes([response[10], response[11]]) - 1;
+    signed[request_mac.len() + 12..request_mac.len() + 14]
+        .copy_from_slice(&additional.to_be_bytes());
+    signed.extend_from_slice(&variables(
+        &key, &algorithm, tsig.time, tsig.fudge, tsig.error, tsig.other,
+    ));
+    let mac = hmac_sha256(&config.key_secret.0, &signed);
+
+    // Compare in constant time, so that the MAC cannot be guessed byte by byte.
+    let difference = mac
+        .iter()
+        .zip(tsig.mac)
+        .fold(tsig.mac.len() ^ mac.len(), |difference, (a, b)| {
+            difference | usize::from(a ^ b)
+        });
+    if difference != 0 {
+        return Err(Error::BadSignature);
+    }
+
+    // A replayed response is as bad as a forged one.
+    let mut time = [0; 8];
+    time[2..].copy_from_slice(tsig.time);
+    let time = u64::from_be_bytes(time);
+    if now().abs_diff(time) > u64::from(tsig.fudge) {
+        return Err(Error::BadSignature);
+    }
+    Ok(())
+}
+
+/// Seconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The signed UPDATE message that points the records of `config` to `address`, with the given
+/// message ID and signing time in seconds since the Unix epoch.
+pub fn message(config: &Config, address: IpAddr, id: u16, time: u64) -> Result<Vec<u8>, Error> {
+    Ok(signed_message(config, address, id, time)?.0)
+}
+
+/// The signed UPDATE message, and its MAC.
+fn signed_message(
+    config: &Config,
+    address: IpAddr,
+    id: u16,
+    time: u64,
+) -> Result<(Vec<u8>, [u8; 32]), Error> {
+    let (kind, rdata) = match address {
+        IpAddr::V4(address) => (TYPE_A, address.octets().to_vec()),
+        IpAddr::V6(address) => (TYPE_AAAA, address.octets().to_vec()),
+    };
+    let updates = u16::try_from(config.records.len() * 2)
+        .map_err(|_| Error::TooManyRecords(config.records.len()))?;
+
+    let mut message = Vec::new();
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&(OPCODE_UPDATE << 11).to_be_bytes());
+    // One zone, no prerequisites, the updates, and no additional records yet.
+    for count in &[1, 0, updates, 0u16] {
+        message.extend_from_slice(&count.to_be_bytes());
+    }
+
+    encode_name(&mut message, &config.zone)?;
+    message.extend_from_slice(&TYPE_SOA.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    for record in &config.records {
+        let name = match record.as_str() {
+            "@" => config.zone.clone(),
+            name if name.ends_with('.') => name.to_string(),
+            name => format!("{}.{}", name, config.zone),
+        };
+
+        // Delete all addresses of the record, then add the new one.
+        encode_name(&mut message, &name)?;
+        resource(&mut message, kind, CLASS_ANY, 0, &[]);
+        encode_name(&mut message, &name)?;
+        resource(&mut message, kind, CLASS_IN, config.ttl, &rdata);
+    }
+
+    let mac = sign(&mut message, &config.key_name, &config.key_secret.0, time)?;
+    Ok((message, mac))
+}
+
+/// Append the fields of a resource record that follow its name.
+fn resource(message: &mut Vec<u8>, kind: u16, class: u16, ttl: u32, rdata: &[u8]) {
+    message.extend_from_slice(&kind.to_be_bytes());
+    message.extend_from_slice(&class.to_be_bytes());
+    message.extend_from_slice(&ttl.to_be_bytes());
+    message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    message.extend_from_slice(rdata);
+}
+
+/// Append `name` in wire format, without compression.
+fn encode_name(message: &mut Vec<u8>, name: &str) -> Result<(), Error> {
+    let invalid = || Error::InvalidName(name.to_string());
+
+    let start = message.len();
+    let labels = name.strip_suffix('.').unwrap_or(name);
+    if !labels.is_empty() {
+        for label in labels.split('.') {
+            if label.is_empty() || label.len() > 63 {
+                return Err(invalid());
+            }
+            message.push(label.len() as u8);
+            message.extend_from_slice(label.as_bytes());
+        }
+    }
+    message.push(0);
+
+    if message.len() - start > 255 {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+/// Append a TSIG record to `message`, as described in section 4.3 of RFC 8945. Returns the MAC,
+/// which the response is signed with as well.
+fn sign(
+    message: &mut Vec<u8>,
+    key_name: &str,
+    secret: &[u8],
+    time: u64,
+) -> Result<[u8; 32], Error> {
+    // The names are signed in canonical form, which is lowercase.
+    let mut key = Vec::new();
+    encode_name(&mut key, &key_name.to_ascii_lowercase())?;
+    let mut algorithm = Vec::new();
+    encode_name(&mut algorithm, ALGORITHM)?;
+    let time = &time.to_be_bytes()[2..];
+
+    // No error and no other data.
+    let mut signed = message.clone();
+    signed.extend_from_slice(&variables(&key, &algorithm, time, FUDGE, 0, &[]));
+    let mac = hmac_sha256(secret, &signed);
+
+    let mut rdata = algorithm;
+    rdata.extend_from_slice(time);
+    rdata.extend_from_slice(&FUDGE.to_be_bytes());
+    rdata.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+    rdata.extend_from_slice(&mac);
+    // The original ID, no error and no other data.
+    rdata.extend_from_slice(&message[..2]);
+    rdata.extend_from_slice(&[0; 4]);
+
+    message.extend_from_slice(&key);
+    resource(message, TYPE_TSIG, CLASS_ANY, 0, &rdata);
+
+    let additional = u16::from_be_bytes([message[10], message[11]]) + 1;
+    message[10..12].copy_from_slice(&additional.to_be_bytes());
+    Ok(mac)
+}
+
+/// The TSIG variables, which are signed after the message: the owner, class and TTL of the
+/// record, then its data without the MAC and the original ID.
+fn variables(
+    key: &[u8],
+    algorithm: &[u8],
+    time: &[u8],
+    fudge: u16,
+    error: u16,
+    other: &[u8],
+) -> Vec<u8> {
+    let mut variables = key.to_vec();
+    variables.extend_from_slice(&CLASS_ANY.to_be_bytes());
+    variables.extend_from_slice(&0u32.to_be_bytes());
+    variables.extend_from_slice(algorithm);
+    variables.extend_from_slice(time);
+    variables.extend_from_slice(&fudge.to_be_bytes());
+    variables.extend_from_slice(&error.to_be_bytes());
+    variables.extend_from_slice(&(other.len() as u16).to_be_bytes());
+    variables.extend_from_slice(other);
+    variables
+}
@@ -0,0 +1,260 @@
+//! Discovery of UPnP Internet Gateway Devices via SSDP.
+//!
+//! `igd` only accepts gateways that offer version 1 of the `WANIPConnection` service, while many
+//! IGDv2 routers only announce version 2. The gateway is searched here instead, and the actions
+//! are sent to the service that was found, see `connection`.
+
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+use igd::Gateway;
+use log::debug;
+use xmltree::Element;
+
+use crate::connection::Connection;
+
+/// The multicast address that gateways listen on for searches.
+pub const SSDP_ADDRESS: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900));
+
+/// How long to wait for answers to a search.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The connection services that can be used for port mappings, in order of preference.
+pub const SERVICE_TYPES: &[&str] = &[
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+const SEARCH_REQUEST: &str = "M-SEARCH * HTTP/1.1\r
+Host: 239.255.255.250:1900\r
+ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r
+Man: \"ssdp:discover\"\r
+MX: 3\r
+\r
+";
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Http(attohttpc::Error),
+    Xml(xmltree::ParseError),
+    /// No gateway answered within the timeout.
+    Timeout,
+    InvalidResponse,
+    /// The gateway does not offer a connection service that can be used for port mappings.
+    ServiceNotFound,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "IO error: {}", e),
+            Error::Http(e) => write!(f, "HTTP error: {}", e),
+            Error::Xml(e) => write!(f, "XML error: {}", e),
+            Error::Timeout => write!(f, "No UPnP gateway answered"),
+            Error::InvalidResponse => write!(f, "Gateway sent an invalid response"),
+            Error::ServiceNotFound => write!(f, "Gateway does not support port mappings"),
+        }
+    }
+}
+
+impl StdError for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Error::Timeout,
+            _ => Error::Io(e),
+        }
+    }
+}
+
+impl From<attohttpc::Error> for Error {
+    fn from(e: attohttpc::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+impl From<xmltree::ParseError> for Error {
+    fn from(e: xmltree::ParseError) -> Self {
+        Error::Xml(e)
+    }
+}
+
+/// Send a search from `bind_addr` to `ssdp_address` and return the first usable gateway that
+/// answers within `timeout`.
+pub fn search_gateway(
+    bind_addr: SocketAddr,
+    ssdp_address: SocketAddr,
+    timeout: Duration,
+) -> Result<Connection, Error> {
+    let socket = UdpSocket::bind(bind_addr)?;
+    socket.send_to(SEARCH_REQUEST.as_bytes(), ssdp_address)?;
+
+    let deadline = Instant::now() + timeout;
+    let mut last_error = Error::Timeout;
+    let mut buffer = [0; 1500];
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return Err(last_error);
+        }
+        socket.set_read_timeout(Some(remaining))?;
+
+        let (len, from) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e) => match Error::from(e) {
+                Error::Timeout => return Err(last_error),
+                e => return Err(e),
+            },
+        };
+
+        let (addr, root_url) = match parse_location(&String::from_utf8_lossy(&buffer[..len])) {
+            Some(location) => location,
+            None => {
+                debug!("Ignore invalid search response from {}.", from);
+                continue;
+            }
+        };
+
+        match gateway(addr, root_url) {
+            Ok(gateway) => return Ok(gateway),
+            Err(e) => {
+                debug!("Ignore gateway at {}: {}", addr, e);
+                last_error = e;
+            }
+        }
+    }
+}
+
+/// Fetch the device description and the control schema of the gateway at `addr`.
+fn gateway(addr: SocketAddrV4, root_url: String) -> Result<Connection, Error> {
+    let description = fetch(addr, &root_url)?;
+
+    let (service_type, service) = SERVICE_TYPES
+        .iter()
+        .find_map(|service_type| Some((service_type, find_service(&description, service_type)?)))
+        .ok_or(Error::ServiceNotFound)?;
+    let control_url = child_text(service, "controlURL").ok_or(Error::InvalidResponse)?;
+    let control_schema_url = child_text(service, "SCPDURL").ok_or(Error::InvalidResponse)?;
+
+    let schema = fetch(addr, &url_path(&control_schema_url))?;
+    let control_schema = find(&schema, "actionList")
+        .ok_or(Error::InvalidResponse)?
+        .children
+        .iter()
+        .filter_map(|action| action.as_element())
+        .filter_map(|action| Some((child_text(action, "name")?, in_arguments(action))))
+        .collect::<HashMap<_, _>>();
+
+    Ok(Connection {
+        gateway: Gateway {
+            addr,
+            root_url,
+            control_url: url_path(&control_url),
+            control_schema_url: url_path(&control_schema_url),
+            control_schema,
+        },
+        service_type: service_type.to_string(),
+    })
+}
+
+fn fetch(addr: SocketAddrV4, path: &str) -> Result<Element, Error> {
+    let response = attohttpc::get(format!("http://{}{}", addr, path)).send()?;
+    Ok(Element::parse(&response.bytes()?[..])?)
+}
+
+/// Extract the address and path of the device description from a search response.
+fn parse_location(response: &str) -> Option<(SocketAddrV4, String)> {
+    let location = response.lines().find_map(|line| {
+        let (name, value) = line.split_at(line.find(':')?);
+        if name.trim().eq_ignore_ascii_case("location") {
+            Some(value[1..].trim())
+        } else {
+            None
+        }
+    })?;
+
+    let location = location.strip_prefix("http://")?;
+    let (host, path) = location.split_at(location.find('/').unwrap_or(location.len()));
+    let addr = match host.parse() {
+        Ok(addr) => addr,
+        Err(_) => SocketAddrV4::new(host.parse().ok()?, 80),
+    };
+
+    Some((addr, url_path(path)))
+}
+
+/// The path of a URL as `igd` expects it, that is relative to the gateway's address.
+fn url_path(url: &str) -> String {
+    let path = match url.strip_prefix("http://") {
+        Some(url) => &url[url.find('/').unwrap_or(url.len())..],
+        None => url,
+    };
+
+    if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("/{}", path)
+    }
+}
+
+/// The names of the input arguments of an action in a control schema.
+fn in_arguments(action: &Element) -> Vec<String> {
+    action
+        .get_child("argumentList")
+        .map(|arguments| {
+            arguments
+                .children
+                .iter()
+                .filter_map(|argument| argument.as_element())
+                .filter(|argument| child_text(argument, "direction").as_deref() == Some("in"))
+                .filter_map(|argument| child_text(argument, "name"))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn child_text(element: &Element, name: &str) -> Option<String> {
+    Some(element.get_child(name)?.get_text()?.trim().to_string())
+}
+
+/// Depth-first search for the first element with the given local name.
+pub(crate) fn find<'a>(element: &'a Element, name: &str) -> Option<&'a Element> {
+    if element.name == name {
+        return Some(element);
+    }
+
+    element
+        .children
+        .iter()
+        .filter_map(|child| child.as_element())
+        .find_map(|child| find(child, name))
+}
+
+/// Depth-first search for the service with the given type in a device description.
+pub(crate) fn find_service<'a>(element: &'a Element, service_type: &str) -> Option<&'a Element> {
+    if element.name == "service"
+        && child_text(element, "serviceType").as_deref() == Some(service_type)
+    {
+        return Some(element);
+    }
+
+    element
+        .children
+        .iter()
+        .filter_map(|child| child.as_element())
+        .find_map(|child| find_service(child, service_type))
+}
+
+/// The control URL of the service with the given type in a device description.
+pub(crate) fn find_control_url(element: &Element, service_type: &str) -> Option<String> {
+    child_text(find_service(element, service_type)?, "controlURL")
+}
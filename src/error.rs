@@ -0,0 +1,153 @@
+//! The error type of the library, so that embedding programs can tell what went wrong.
+//!
+//! The backends still report their errors as `Box<dyn Error>`, but box the variants of this type
+//! where they know the cause. Converting such a box back recovers the variant, everything else
+//! ends up as `Error::Other`.
+
+use std::error::Error as StdError;
+use std::fmt;
+
+use crate::{backend, discovery, pinhole, PortMappingProtocol};
+
+#[derive(Debug)]
+pub enum Error {
+    /// No gateway was found, or it stopped answering.
+    Discovery(String),
+    /// There is no network interface to search for a gateway on.
+    NoInterface,
+    /// The address of a row is not an IP address.
+    InvalidAddress(String),
+    /// The backend cannot map ports for IPv6 addresses.
+    Ipv6Unsupported,
+    /// The router refused to add a port mapping.
+    AddPort(igd::AddPortError),
+    /// The router refused to remove a port mapping.
+    RemovePort(igd::RemovePortError),
+    /// The router refused to open or close an IPv6 pinhole.
+    Pinhole(pinhole::Error),
+    /// Some ports of a row failed, the others were mapped.
+    Ports {
+        total: usize,
+        failed: Vec<(u16, PortMappingProtocol, Error)>,
+    },
+    /// The mapping file, or a row of it, is invalid.
+    Config(String),
+    Other(String),
+}
+
+impl Error {
+    /// The UPnP error code the router answered with, if any.
+    pub fn code(&self) -> Option<u16> {
+        match self {
+            Error::AddPort(e) => match e {
+                igd::AddPortError::DescriptionTooLong => Some(605),
+                igd::AddPortError::ActionNotAuthorized => Some(606),
+                igd::AddPortError::PortInUse => Some(718),
+                igd::AddPortError::SamePortValuesRequired => Some(724),
+                igd::AddPortError::OnlyPermanentLeasesSupported => Some(725),
+                igd::AddPortError::RequestError(e) => request_code(e),
+                _ => None,
+            },
+            Error::RemovePort(e) => match e {
+                igd::RemovePortError::ActionNotAuthorized => Some(606),
+                igd::RemovePortError::NoSuchPortMapping => Some(714),
+                igd::RemovePortError::RequestError(e) => request_code(e),
+            },
+            Error::Pinhole(pinhole::Error::Upnp(code, _)) => Some(*code),
+            _ => None,
+        }
+    }
+}
+
+fn request_code(e: &igd::RequestError) -> Option<u16> {
+    match e {
+        igd::RequestError::ErrorCode(code, _) => Some(*code),
+        _ => None,
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Discovery(message) | Error::Config(message) | Error::Other(message) => {
+                write!(f, "{}", message)
+            }
+            Error::NoInterface => write!(f, "No network interface to search for a gateway on"),
+            Error::InvalidAddress(address) => write!(f, "Invalid address: {}", address),
+            Error::Ipv6Unsupported => write!(f, "The backend does not support IPv6 addresses"),
+            Error::AddPort(e) => e.fmt(f),
+            Error::RemovePort(e) => e.fmt(f),
+            Error::Pinhole(e) => e.fmt(f),
+            Error::Ports { total, failed } => write!(
+                f,
+                "Failed to map {} of {} ports: {}",
+                failed.len(),
+                total,
+                failed
+                    .iter()
+                    .map(|(port, protocol, e)| format!("{}/{:?} ({})", port, protocol, e))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+impl StdError for Error {}
+
+impl From<igd::AddPortError> for Error {
+    fn from(e: igd::AddPortError) -> Self {
+        Error::AddPort(e)
+    }
+}
+
+impl From<igd::RemovePortError> for Error {
+    fn from(e: igd::RemovePortError) -> Self {
+        Error::RemovePort(e)
+    }
+}
+
+impl From<backend::AddPortError> for Error {
+    fn from(e: backend::AddPortError) -> Self {
+        match e {
+            backend::AddPortError::PortInUse => Error::AddPort(igd::AddPortError::PortInUse),
+            backend::AddPortError::Other(e) => e.into(),
+        }
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(e: csv::Error) -> Self {
+        Error::Config(e.to_string())
+    }
+}
+
+/// Recover the variant a backend boxed, or classify the errors of the modules it uses.
+impl From<Box<dyn StdError>> for Error {
+    fn from(e: Box<dyn StdError>) -> Self {
+        let e = match e.downcast::<Error>() {
+            Ok(e) => return *e,
+            Err(e) => e,
+        };
+        let e = match e.downcast::<backend::AddPortError>() {
+            Ok(e) => return (*e).into(),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<igd::AddPortError>() {
+            Ok(e) => return Error::AddPort(*e),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<igd::RemovePortError>() {
+            Ok(e) => return Error::RemovePort(*e),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<pinhole::Error>() {
+            Ok(e) => return Error::Pinhole(*e),
+            Err(e) => e,
+        };
+        if let Some(e) = e.downcast_ref::<discovery::Error>() {
+            return Error::Discovery(e.to_string());
+        }
+        Error::Other(e.to_string())
+    }
+}
@@ -0,0 +1,123 @@
+//! Tracking the external addresses of the gateways, to react when the provider assigns a new one.
+//!
+//! The last known address of each gateway is kept in a file, so that a change while the daemon
+//! was not running is noticed as well.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread;
+
+use log::{debug, error, info, warn};
+
+use crate::state;
+
+/// The external address of a gateway changed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Change {
+    pub gateway: IpAddr,
+    pub old: IpAddr,
+    pub new: IpAddr,
+}
+
+pub struct Tracker {
+    /// Where the known addresses are kept between runs.
+    file: Option<PathBuf>,
+    /// A shell command to run for every change.
+    hook: Option<String>,
+    /// The last known external address, by the address of the gateway.
+    known: BTreeMap<IpAddr, IpAddr>,
+}
+
+impl Tracker {
+    /// Start with the addresses of `file`, if it exists.
+    pub fn new(file: Option<PathBuf>, hook: Option<String>) -> Self {
+        let known = match &file {
+            Some(file) => match state::read(file) {
+                Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                    warn!("Ignoring {}: {}", file.display(), e);
+                    BTreeMap::new()
+                }),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+                Err(e) => {
+                    warn!("Cannot read {}: {}", file.display(), e);
+                    BTreeMap::new()
+                }
+            },
+            None => BTreeMap::new(),
+        };
+
+        Tracker { file, hook, known }
+    }
+
+    /// Remember the external addresses the gateways reported, and return those that changed.
+    /// The hook is run for every change.
+    pub fn update<I>(&mut self, addresses: I) -> Vec<Change>
+    where
+        I: IntoIterator<Item = (IpAddr, IpAddr)>,
+    {
+        let mut changes = Vec::new();
+        let mut learned = false;
+        for (gateway, new) in addresses {
+            match self.known.insert(gateway, new) {
+                Some(old) if old != new => {
+                    info!(
+                        "External address of gateway {} changed from {} to {}",
+                        gateway, old, new
+                    );
+                    changes.push(Change { gateway, old, new });
+                }
+                Some(_) => {}
+                None => {
+                    info!("External address of gateway {} is {}", gateway, new);
+                    learned = true;
+                }
+            }
+        }
+
+        if learned || !changes.is_empty() {
+            self.save();
+        }
+        if let Some(hook) = &self.hook {
+            for change in &changes {
+                run_hook(hook, change);
+            }
+        }
+
+        changes
+    }
+
+    fn save(&self) {
+        if let Some(file) = &self.file {
+            // A map of addresses always serializes.
+            let content = serde_json::to_string_pretty(&self.known).unwrap();
+            if let Err(e) = state::write(file, &(content + "\n")) {
+                warn!("Cannot write {}: {}", file.display(), e);
+            }
+        }
+    }
+}
+
+/// Run `hook` with the shell, without waiting for it to finish.
+fn run_hook(hook: &str, change: &Change) {
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(hook)
+        .env("UPNP_DAEMON_GATEWAY", change.gateway.to_string())
+        .env("UPNP_DAEMON_OLD_IP", change.old.to_string())
+        .env("UPNP_DAEMON_NEW_IP", change.new.to_string())
+        .spawn();
+
+    match child {
+        Ok(mut child) => {
+            thread::spawn(move || match child.wait() {
+                Ok(status) if status.success() => debug!("The address change hook finished"),
+                Ok(status) => error!("The address change hook failed: {}", status),
+                Err(e) => error!("Cannot wait for the address change hook: {}", e),
+            });
+        }
+        Err(e) => error!("Cannot run the address change hook: {}", e),
+    }
+}
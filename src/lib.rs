@@ -76,6 +76,244 @@
 //! know when the process has finished, which could take some time, depending on
 //! the size of the mapping file.
 //!
+//! ### Failures
+//!
+//! A mapping that fails, for example because its gateway does not answer, is
+//! logged and retried in the next iteration. The other mappings are added anyway,
+//! and the daemon keeps running. If the mapping file cannot be read, the daemon
+//! keeps the current mappings until it can be read again. In oneshot mode, the
+//! program ends with an error if any mapping failed.
+//!
+//! To stop at the first failure instead, which is mostly useful together with
+//! `oneshot` in scripts, use the `fail-fast` flag:
+//!
+//! ```shell script
+//! upnp-daemon --foreground --oneshot --fail-fast --file ports.csv
+//! ```
+//!
+//! ### Checking the File
+//!
+//! To validate the mapping file without mapping any ports, use the `check`
+//! subcommand:
+//!
+//! ```shell script
+//! upnp-daemon check --file ports.csv
+//! ```
+//!
+//! Every problem is printed with the line and column where it was found, for
+//! example invalid or public addresses, port 0, ranges of different sizes and
+//! external ports that are used by more than one mapping. If there is any
+//! problem, the exit code is non-zero, so the check can be used in CI. In
+//! structured formats, only syntax errors have a position, other problems name
+//! the number of the mapping instead.
+//!
+//! Global IPv6 addresses are only accepted if they belong to this machine, unique
+//! local and link-local ones always are.
+//!
+//! ### Listing Mappings
+//!
+//! To see what is currently mapped on the router, use the `list` subcommand:
+//!
+//! ```shell script
+//! upnp-daemon list --address 192.168.0.10
+//! ```
+//!
+//! It prints the external port, protocol, internal client and port, remaining
+//! lease time, enabled flag and description of every mapping of the gateway that
+//! is found for the given local address. The `address` option can be repeated to
+//! list several gateways, without it the default gateway is listed. The `backend`,
+//! `ssdp-address` and `discovery-timeout` options work like for the daemon, but
+//! only UPnP gateways can list their mappings.
+//!
+//! With `--output json`, the mappings are printed as JSON. With `--output csv`,
+//! they are printed in the CSV format of the mapping file. Gateways only report
+//! the remaining lease time, not the one that was requested, so the duration is
+//! always 0, which means permanent. Set the duration before pasting such rows into
+//! the mapping file.
+//!
+//! ### Adding and Removing Single Mappings
+//!
+//! To open a port for a while without touching the mapping file, use the `add`
+//! subcommand. It takes the fields of a row as options, maps the port with the
+//! same discovery and conflict handling as the daemon, and prints the external
+//! address and port:
+//!
+//! ```shell script
+//! upnp-daemon add --address 192.168.0.10 --port 8080 --protocol TCP --duration 3600 --comment "Test server"
+//! ```
+//!
+//! The duration defaults to one hour, and the mapping is not removed when the
+//! command exits. `external-port` and `internal-port` can be used instead of
+//! `port`, and ranges like `50000-50010` work as well.
+//!
+//! NAT-PMP and PCP gateways may assign another external port than the requested
+//! one, so the port that is printed is the one to use from outside. The `add`
+//! subcommand keeps the mappings it made in `added.json` in the state directory,
+//! `$XDG_STATE_HOME/upnp-daemon` or `~/.local/state/upnp-daemon`, so that they can
+//! be removed by that port as well. Only the current user may access that
+//! directory, and files of other users in it are ignored.
+//!
+//! The `remove` subcommand removes mappings again, either by external port and
+//! protocol, or every mapping whose description equals the given comment:
+//!
+//! ```shell script
+//! upnp-daemon remove --port 8080 --protocol TCP
+//! upnp-daemon remove --comment "Test server"
+//! ```
+//!
+//! ### Shutdown
+//!
+//! When the daemon receives `SIGTERM` or `SIGINT`, it finishes the current
+//! iteration and removes every mapping it created before it exits, so no port
+//! stays open after the service is stopped. The same happens when the daemon
+//! stops because of the `fail-fast` flag, and in both cases the control socket and
+//! the PID file are removed as well. To leave the mappings on the router
+//! until their lease expires, use the `keep-mappings` flag:
+//!
+//! ```shell script
+//! upnp-daemon --keep-mappings --file ports.csv
+//! ```
+//!
+//! Mappings are not removed in oneshot mode.
+//!
+//! ### Signals
+//!
+//! Besides the termination signals, a running daemon reacts to the following
+//! signals:
+//!
+//! -   `SIGHUP` reads the daemon section of the file again and applies the new
+//!     interval, log level and log file, then updates the mappings. The log file
+//!     is reopened as well, which is useful after it was rotated. The PID file
+//!     can only be set at startup.
+//! -   `SIGUSR1` updates the mappings right away.
+//! -   `SIGUSR2` logs the current settings and every mapping the daemon added,
+//!     with log level `info`.
+//!
+//! The daemon does not wait for the end of the interval before it reacts. Neither
+//! `SIGUSR1` nor a change of the file moves the next regular update.
+//!
+//! ### Control Socket
+//!
+//! Instead of sending signals, a running daemon can be controlled through a Unix
+//! socket. It only listens if a path is given with the `control-socket` option or
+//! in the daemon section of a [structured config file](#structured-formats):
+//!
+//! ```shell script
+//! upnp-daemon --control-socket /tmp/upnp-daemon.sock --file ports.csv
+//! ```
+//!
+//! By default, only the user running the daemon can connect. Since everyone who
+//! can connect is able to open ports, widen the permissions with care, for
+//! example to a group with `--control-socket-mode 660`.
+//!
+//! The `control` subcommand sends a command to the daemon and prints the answer:
+//!
+//! ```shell script
+//! upnp-daemon control --socket /tmp/upnp-daemon.sock status
+//! upnp-daemon control --socket /tmp/upnp-daemon.sock open --port 8080 --protocol TCP --duration 600
+//! upnp-daemon control --socket /tmp/upnp-daemon.sock close --port 8080 --protocol TCP
+//! ```
+//!
+//! -   `status` prints the result of the last attempt of each mapping and when it
+//!     happened.
+//! -   `refresh` updates the mappings right away, like `SIGUSR1`, and `reload`
+//!     reads the daemon section of the file again, like `SIGHUP`. Both answer
+//!     once the update is done.
+//! -   `open` maps a port with the same options as the `add` subcommand. The
+//!     daemon keeps it like a row of the file until its duration is over, or
+//!     until the daemon stops if the duration is 0.
+//! -   `close` removes a mapping by external port and protocol.
+//! -   `shutdown` removes the mappings and stops the daemon, like `SIGTERM`.
+//!
+//! The protocol is simple enough to be used without the subcommand: every request
+//! is a JSON object on a line of its own, with the command in the `command` field
+//! and the options of `open` and `close` as further fields, and is answered with
+//! a JSON object on a line of its own.
+//!
+//! ```shell script
+//! echo '{"command": "status"}' | socat - UNIX-CONNECT:/tmp/upnp-daemon.sock
+//! ```
+//!
+//! ### Metrics
+//!
+//! For monitoring with Prometheus, the daemon can serve metrics in the
+//! OpenMetrics format. This is off by default, the `metrics-port` option or the
+//! `metrics_port` field of the daemon section turns it on. The port is only
+//! opened on localhost:
+//!
+//! ```shell script
+//! upnp-daemon --metrics-port 9184 --file ports.csv
+//! curl http://localhost:9184/metrics
+//! ```
+//!
+//! The following metrics are served:
+//!
+//! -   `upnp_daemon_cycles_total`: iterations over the mapping file.
+//! -   `upnp_daemon_mapping_successes_total` and
+//!     `upnp_daemon_mapping_failures_total`: ports that were mapped or failed,
+//!     by protocol, external port and comment.
+//! -   `upnp_daemon_mapping_last_success_timestamp_seconds`: when a port was last
+//!     mapped.
+//! -   `upnp_daemon_conflicts_stolen_total`: ports of other clients that were
+//!     taken over because of the [conflict policy](#conflicts).
+//! -   `upnp_daemon_ssdp_discovery_seconds`: duration of UPnP gateway searches.
+//! -   `upnp_daemon_soap_call_seconds`: duration of UPnP requests, by action.
+//! -   `upnp_daemon_external_ip`: the external address each gateway reports,
+//!     in the `address` label, see [external address](#external-address).
+//!
+//! ### External Address
+//!
+//! After every iteration, the daemon asks each gateway it added mappings to for
+//! its external address, and logs when it changes. To notice a change that
+//! happened while the daemon was not running, the last known addresses are kept
+//! in `external-ips.json` in the state directory whenever `on-ip-change` or
+//! dynamic DNS is configured. The `external-ip-file` option chooses another file,
+//! which also keeps them otherwise.
+//!
+//! When an address changes, the shell command given with the `on-ip-change`
+//! option is run, for example to update a firewall allowlist or to notify peers.
+//! The gateway and the old and new address are passed in the environment
+//! variables `UPNP_DAEMON_GATEWAY`, `UPNP_DAEMON_OLD_IP` and
+//! `UPNP_DAEMON_NEW_IP`:
+//!
+//! ```shell script
+//! upnp-daemon --on-ip-change 'logger "New address: $UPNP_DAEMON_NEW_IP"' --file ports.csv
+//! ```
+//!
+//! The daemon does not wait for the command to finish. Both options can also be
+//! set in the daemon section, see [structured formats](#structured-formats).
+//!
+//! ### Dynamic DNS
+//!
+//! When an external address changes, the daemon can point DNS records to the new
+//! address itself, with dynamic updates as specified in RFC 2136. The updates are
+//! signed with a TSIG key, using HMAC-SHA256, and sent to the primary name server
+//! of the zone. An IPv4 address replaces the `A` records of the names, an IPv6
+//! address their `AAAA` records. An update only counts as done if the server
+//! signed its response with the same key. A failed update is retried after the
+//! next iteration.
+//!
+//! The updates are configured in the `ddns` table of the daemon section of a
+//! [structured config file](#structured-formats):
+//!
+//! ```toml
+//! [daemon.ddns]
+//! # The primary name server of the zone.
+//! server = "192.0.2.53:53"
+//! zone = "example.com"
+//! # Relative to the zone unless they end with a dot, `@` is the zone itself.
+//! records = ["home", "@"]
+//! # The time to live of the records in seconds, 60 by default.
+//! ttl = 300
+//! # The TSIG key, as printed by `tsig-keygen -a hmac-sha256 upnp-daemon`.
+//! key_name = "upnp-daemon"
+//! key_secret = "c2VjcmV0LWtleS1mb3ItdGhlLXRlc3Rz"
+//! ```
+//!
+//! The records are only updated when an address changes, including a change that
+//! happened while the daemon was not running, see
+//! [external address](#external-address). The settings are read at startup.
+//!
 //! ### Logging
 //!
 //! If you want to activate logging to have a better understanding what the
@@ -92,9 +330,93 @@
 //! RUST_LOG=debug upnp-daemon --foreground --file ports.csv
 //! ```
 //!
-//! Please note that it does not make sense to activate logging without using
-//! `foreground`, since the output (stdout as well as stderr) will not be saved in
-//! daemon mode. This might change in a future release.
+//! Please note that the output (stdout as well as stderr) will not be saved in
+//! daemon mode, unless a `log_file` is given in the daemon section of a
+//! [structured config file](#structured-formats). The log level can be set there
+//! as well, `RUST_LOG` takes precedence.
+//!
+//! ### Backends
+//!
+//! Not every router speaks UPnP. Apple and OpenWrt routers, for example, often
+//! use NAT-PMP (RFC 6886) instead, and carrier-grade NATs of some ISPs only speak
+//! PCP (RFC 6887). The protocol that is used to talk to the router can be chosen
+//! with the `backend` option:
+//!
+//! ```shell script
+//! upnp-daemon --backend natpmp --file ports.csv
+//! ```
+//!
+//! Possible values are `upnp` (the default), `natpmp`, `pcp` and `auto`. The
+//! latter will try UPnP first and fall back to PCP and then NAT-PMP if no UPnP
+//! gateway answers. The backend can also be chosen per mapping, see the `backend`
+//! field in the [config file format](#config-file-format).
+//!
+//! A PCP mapping can only be renewed or deleted with the random nonce it was
+//! created with. The nonces are kept in `pcp-nonces.json` in the state directory
+//! (`$XDG_STATE_HOME/upnp-daemon` or `~/.local/state/upnp-daemon`), so that a
+//! restarted daemon can still take care of its earlier mappings. The file is only
+//! readable by its owner, and ignored if it belongs to another user. If that file is lost, the gateway
+//! refuses those mappings with `NOT_AUTHORIZED` until they expire.
+//!
+//! ### Conflicts
+//!
+//! By default, a port that is already mapped to another client is taken over.
+//! Since this might break another host's service, the `conflict` option allows
+//! to skip such ports with a warning, to fail, or to only take over ports that
+//! were mapped by this daemon before:
+//!
+//! ```shell script
+//! upnp-daemon --conflict steal-only-if-ours --file ports.csv
+//! ```
+//!
+//! The policy can also be chosen per mapping, see the `conflict` field in the
+//! [config file format](#config-file-format).
+//!
+//! ### UPnP Discovery
+//!
+//! UPnP gateways are found by sending a search to the SSDP multicast group
+//! `239.255.255.250:1900` and waiting up to 10 seconds for answers. Both can be
+//! changed, for example to send the search directly to a router that does not
+//! answer multicast searches:
+//!
+//! ```shell script
+//! upnp-daemon --ssdp-address 192.168.0.1:1900 --discovery-timeout 3 --file ports.csv
+//! ```
+//!
+//! Gateways offering version 1 or 2 of the `WANIPConnection` service are
+//! supported, as well as the `WANPPPConnection` service.
+//!
+//! A gateway is searched only once per local address and then used for all
+//! mappings of that address, also in later iterations. It is searched again if it
+//! stops answering or the local address disappears. If no gateway answers, the
+//! search is not repeated for the other mappings of the same iteration.
+//!
+//! ### Parallelism
+//!
+//! Mappings via different gateways are added concurrently, so that a slow or
+//! unreachable gateway does not hold up the others. Mappings via the same gateway
+//! are still added one after another, in the order of the file. Gateways are
+//! searched concurrently as well, once per address and backend. By default, up to
+//! 4 gateways are contacted at the same time, which can be changed with the
+//! `parallelism` option:
+//!
+//! ```shell script
+//! upnp-daemon --parallelism 16 --file ports.csv
+//! ```
+//!
+//! After each iteration, the number of mapped, skipped and failed entries is
+//! logged.
+//!
+//! ### IPv6
+//!
+//! IPv6 hosts do not need port mappings, but the router's firewall usually still
+//! blocks inbound traffic. For rows with an IPv6 address, the `upnp` backend opens
+//! a pinhole in this firewall via the `WANIPv6FirewallControl` service of IGDv2
+//! routers instead of adding a port mapping. The pinhole is refreshed on every
+//! iteration. A pinhole lets the internal port through as it is, so the
+//! `external_port` of such a row is ignored with a warning. The `pcp` backend
+//! supports IPv6 addresses as well, `auto` tries a pinhole first and falls back to
+//! PCP. NAT-PMP is IPv4 only.
 //!
 //! ## Config File Format
 //!
@@ -110,6 +432,76 @@
 //! Please note that the first line is mandatory at the moment, it is needed to
 //! accurately map the fields to the internal options.
 //!
+//! The file is read again on every iteration. If a row is deleted, its mapping is
+//! removed from the router. If the address or protocol of a row changes, the old
+//! mapping is removed before the new one is added. While a row fails, for
+//! example because no gateway answers, the mappings it might have made are kept.
+//!
+//! On Linux, the file is also watched for changes, so new mappings do not have to
+//! wait for the next iteration. Shortly after the file was written, or replaced
+//! by an editor that saves to a new file and renames it, the mappings are updated
+//! right away. The regular iterations keep their schedule.
+//!
+//! ### Structured Formats
+//!
+//! Instead of CSV, the file can be written in TOML, YAML or JSON. The format is
+//! guessed from the file extension (`.toml`, `.yaml` or `.yml`, `.json`, anything
+//! else is read as CSV) or chosen with the `format` option. Besides the list of
+//! mappings, with the same fields as the CSV columns, a structured file can have
+//! a `daemon` section:
+//!
+//! ```toml
+//! [daemon]
+//! # Update interval in seconds, the `interval` option takes precedence.
+//! interval = 60
+//! # Where to write the PID in daemon mode.
+//! pid_file = "/tmp/upnp-daemon.pid"
+//! # Where to keep the nonces of PCP mappings, in the state directory by default.
+//! pcp_nonce_file = "/var/lib/upnp-daemon/pcp-nonces.json"
+//! # Log filter, in the same syntax as `RUST_LOG`.
+//! log_level = "info"
+//! # Where to write the output in daemon mode.
+//! log_file = "/var/log/upnp-daemon.log"
+//! # Where to listen for control commands, the `control-socket` option takes
+//! # precedence. The socket can only be set at startup.
+//! control_socket = "/tmp/upnp-daemon.sock"
+//! # The permissions of the control socket, in octal.
+//! control_socket_mode = "600"
+//! # Serve metrics on this port of localhost, the `metrics-port` option takes
+//! # precedence. The port can only be set at startup.
+//! metrics_port = 9184
+//! # Where to keep the last known external addresses, in the state directory by
+//! # default if there is a hook or dynamic DNS.
+//! external_ip_file = "/var/lib/upnp-daemon/external-ip.json"
+//! # A shell command to run when an external address changes.
+//! on_ip_change = "/usr/local/bin/notify-peers"
+//!
+//! # Update DNS records when an external address changes, see dynamic DNS.
+//! [daemon.ddns]
+//! server = "192.0.2.53:53"
+//! zone = "example.com"
+//! records = ["home"]
+//! key_name = "upnp-daemon"
+//! key_secret = "c2VjcmV0LWtleS1mb3ItdGhlLXRlc3Rz"
+//!
+//! [[mappings]]
+//! address = "192.168.0.10"
+//! port = 12345
+//! protocol = "UDP"
+//! duration = 60
+//! comment = "Test 1"
+//!
+//! [[mappings]]
+//! port = "50000-50100"
+//! protocol = "UDP"
+//! duration = 0
+//! comment = "Media"
+//! conflict = "skip-and-warn"
+//! ```
+//!
+//! The daemon section is only read at startup or on `SIGHUP` (see
+//! [signals](#signals)), the mappings on every iteration.
+//!
 //! ### Fields
 //!
 //! -   address
@@ -121,43 +513,129 @@
 //!
 //!     Fill in an IP address if you want to add a port mapping for a foreign
 //!     device, or if you know your machine's address and want to slightly speed
-//!     up the process.
+//!     up the process. IPv6 addresses are supported as well, see
+//!     [IPv6](#ipv6).
 //!
 //! -   port
 //!
-//!     The port number to open for the given IP address. Note that upnp-daemon is
-//!     greedy at the moment, if a port mapping is already in place, it will be
-//!     deleted and re-added with the given IP address. This might be configurable
-//!     in a future release.
+//!     The port number to open for the given IP address. If the port is already
+//!     mapped to another client, the `conflict` policy decides what happens.
+//!
+//!     The port is used both as the external port on the router and as the
+//!     internal port on the given address, unless one of them is overridden by
+//!     the following fields.
+//!
+//!     Instead of a single port, a range of ports like `50000-50100` can be
+//!     given, which is expanded into one mapping per port. A range is refreshed
+//!     and removed as a whole. If some ports of a range cannot be mapped, the
+//!     other ports are mapped anyway and the failed ports are reported
+//!     together. IGDv2 routers remove a whole range in one request, other
+//!     routers port by port.
+//!
+//! -   external_port
+//!
+//!     Optional. The port or range of ports on the router, if it differs from
+//!     `port`. If the column is missing or empty, `port` is used.
+//!
+//! -   internal_port
+//!
+//!     Optional. The port or range of ports on the given address, if it differs
+//!     from `port`, with as many ports as the external range. If the column is
+//!     missing or empty, `port` is used. For example, to expose
+//!     port 443 for a web server that listens on port 8443, use an empty `port`,
+//!     an `external_port` of 443 and an `internal_port` of 8443.
 //!
 //! -   protocol
 //!
 //!     The protocol for which the given port will be opened. Possible values are
-//!     `UDP` and `TCP`.
+//!     `UDP`, `TCP` and `BOTH` (or `TCP+UDP`). The latter opens the port for
+//!     both protocols, failures are reported per protocol. Both mappings are
+//!     removed together if the row is deleted.
 //!
 //! -   duration
 //!
 //!     The lease duration for the port mapping in seconds. Please note that some
 //!     UPnP capable routers might choose to ignore this value, so do not
-//!     exclusively rely on this.
+//!     exclusively rely on this. IPv6 pinholes can be open for at most one day,
+//!     this is also used if the duration is 0.
 //!
 //! -   comment
 //!
 //!     A comment about the reason for the port mapping. Will be stored together
 //!     with the mapping in the router.
+//!
+//! -   backend
+//!
+//!     Optional. The protocol to use for this mapping, one of `upnp`, `natpmp`,
+//!     `pcp` or `auto`. If the column is missing or empty, the value of the
+//!     `backend` option is used.
+//!
+//!     Please note that NAT-PMP and PCP can only map ports for the machine the
+//!     daemon is running on, so the address, if given, has to be a local one.
+//!     Since a duration of 0 would delete a NAT-PMP or PCP mapping, it is replaced
+//!     by a lifetime of two hours, which is renewed on every iteration.
+//!
+//! -   gateway
+//!
+//!     Optional. The IP address of the NAT-PMP or PCP gateway. If the column is
+//!     missing or empty, the default gateway of the machine is used, for the
+//!     address family of the mapping's address. This field is ignored for UPnP.
+//!
+//! -   conflict
+//!
+//!     Optional. What to do if the port is already mapped to another client. If
+//!     the column is missing or empty, the value of the `conflict` option is
+//!     used, which defaults to `steal`. Possible values are:
+//!
+//!     -   `steal`: delete the existing mapping and add this one.
+//!     -   `skip-and-warn`: leave the existing mapping alone and log a warning.
+//!     -   `fail`: leave the existing mapping alone and fail.
+//!     -   `steal-only-if-ours`: steal the port if the existing mapping has the
+//!         same comment, or points to an address this daemon mapped the port to
+//!         before. Otherwise, skip it with a warning.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
 
-use std::error::Error;
-use std::net::{SocketAddr, SocketAddrV4};
+use log::{debug, error, info, warn};
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize};
 
-use igd::{AddPortError, Gateway, SearchOptions};
-use log::debug;
-use serde::Deserialize;
+use crate::backend::{AddPortError, Backends, Mapping};
+use crate::error::Error;
 
 pub use cli::Cli;
 
+pub mod backend;
+pub mod check;
 mod cli;
+pub mod config;
+pub mod connection;
+pub mod control;
+pub mod ddns;
+pub mod discovery;
+pub mod error;
+pub mod external_ip;
+pub mod hmac;
+pub mod list;
+mod logger;
+pub mod metrics;
+pub mod natpmp;
+pub mod pcp;
+pub mod pinhole;
+mod soap;
+mod state;
+#[cfg(target_os = "linux")]
+mod watch;
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
 pub enum PortMappingProtocol {
     TCP,
     UDP,
@@ -172,91 +650,777 @@ impl From<PortMappingProtocol> for igd::PortMappingProtocol {
     }
 }
 
-#[derive(Debug, Deserialize)]
+/// The protocols of a row, which can map both TCP and UDP at once.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Protocols {
+    TCP,
+    UDP,
+    #[serde(alias = "TCP+UDP")]
+    BOTH,
+}
+
+impl Protocols {
+    pub fn protocols(self) -> &'static [PortMappingProtocol] {
+        match self {
+            Protocols::TCP => &[PortMappingProtocol::TCP],
+            Protocols::UDP => &[PortMappingProtocol::UDP],
+            Protocols::BOTH => &[PortMappingProtocol::TCP, PortMappingProtocol::UDP],
+        }
+    }
+
+    /// The protocols that are left when `removed` is taken away, none if nothing is left.
+    pub fn without(self, removed: Protocols) -> Option<Protocols> {
+        match (self, removed) {
+            (_, Protocols::BOTH) => None,
+            (Protocols::BOTH, Protocols::TCP) => Some(Protocols::UDP),
+            (Protocols::BOTH, Protocols::UDP) => Some(Protocols::TCP),
+            (protocols, removed) if protocols == removed => None,
+            (protocols, _) => Some(protocols),
+        }
+    }
+}
+
+impl FromStr for Protocols {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "TCP" => Ok(Protocols::TCP),
+            "UDP" => Ok(Protocols::UDP),
+            "BOTH" | "TCP+UDP" => Ok(Protocols::BOTH),
+            _ => Err(format!("Unknown protocol: {}", s)),
+        }
+    }
+}
+
+/// A single port or an inclusive range of ports, like `50000-50100`.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn single(port: u16) -> Self {
+        PortRange {
+            start: port,
+            end: port,
+        }
+    }
+
+    /// The number of ports in the range.
+    pub fn size(&self) -> usize {
+        usize::from(self.end - self.start) + 1
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u16> {
+        self.start..=self.end
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid port range: {}", s);
+
+        let (start, end) = match s.find('-') {
+            Some(index) => (&s[..index], &s[index + 1..]),
+            None => (s, s),
+        };
+        let start = start.trim().parse().map_err(|_| invalid())?;
+        let end = end.trim().parse().map_err(|_| invalid())?;
+
+        if start > end {
+            return Err(invalid());
+        }
+
+        Ok(PortRange { start, end })
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = PortRange;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a port or a range of ports")
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                u16::try_from(v)
+                    .map(PortRange::single)
+                    .map_err(|_| E::custom(format!("Invalid port: {}", v)))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                u16::try_from(v)
+                    .map(PortRange::single)
+                    .map_err(|_| E::custom(format!("Invalid port: {}", v)))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                v.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+/// The protocol that is used to talk to the router.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    /// UPnP Internet Gateway Device.
+    #[default]
+    Upnp,
+    /// NAT Port Mapping Protocol, RFC 6886.
+    NatPmp,
+    /// Port Control Protocol, RFC 6887.
+    Pcp,
+    /// UPnP, with PCP and NAT-PMP as fallbacks if no gateway answers.
+    Auto,
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "upnp" => Ok(BackendKind::Upnp),
+            "natpmp" => Ok(BackendKind::NatPmp),
+            "pcp" => Ok(BackendKind::Pcp),
+            "auto" => Ok(BackendKind::Auto),
+            _ => Err(format!("Unknown backend: {}", s)),
+        }
+    }
+}
+
+/// What to do if the external port of a mapping is already mapped to another client.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    /// Delete the existing mapping and add ours.
+    #[default]
+    Steal,
+    /// Leave the existing mapping alone and log a warning.
+    SkipAndWarn,
+    /// Leave the existing mapping alone and fail.
+    Fail,
+    /// Steal the port if the existing mapping has our comment or points to an address we mapped
+    /// the port to before, skip it with a warning otherwise.
+    StealOnlyIfOurs,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "steal" => Ok(ConflictPolicy::Steal),
+            "skip-and-warn" => Ok(ConflictPolicy::SkipAndWarn),
+            "fail" => Ok(ConflictPolicy::Fail),
+            "steal-only-if-ours" => Ok(ConflictPolicy::StealOnlyIfOurs),
+            _ => Err(format!("Unknown conflict policy: {}", s)),
+        }
+    }
+}
+
+/// How many gateways are talked to at the same time by default.
+pub const DEFAULT_PARALLELISM: usize = 4;
+
+/// Settings for all rows, which can partly be overridden per row.
+#[derive(Clone, Copy, Debug)]
+pub struct Settings {
+    pub conflict: ConflictPolicy,
+    /// How many gateways are talked to at the same time. Rows of the same gateway are processed
+    /// one after another.
+    pub parallelism: usize,
+    /// Stop at the first row that fails, instead of logging it and carrying on with the others.
+    pub fail_fast: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            conflict: ConflictPolicy::default(),
+            parallelism: DEFAULT_PARALLELISM,
+            fail_fast: false,
+        }
+    }
+}
+
+/// What happened to the rows of one cycle.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Ports that were mapped, counted per protocol.
+    pub mapped: usize,
+    /// Ports that were left to another client because of the conflict policy.
+    pub skipped: usize,
+    /// The rows that failed, by their number starting at 1, with the reason.
+    pub failed: Vec<(usize, Error)>,
+}
+
+impl Report {
+    fn merge(&mut self, other: Report) {
+        self.mapped += other.mapped;
+        self.skipped += other.skipped;
+        self.failed.extend(other.failed);
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ports mapped, {} skipped, {} rows failed",
+            self.mapped,
+            self.skipped,
+            self.failed.len()
+        )
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
 pub struct Options {
     pub address: Option<String>,
-    pub port: u16,
-    pub protocol: PortMappingProtocol,
+    #[serde(default)]
+    pub port: Option<PortRange>,
+    #[serde(default)]
+    pub external_port: Option<PortRange>,
+    #[serde(default)]
+    pub internal_port: Option<PortRange>,
+    pub protocol: Protocols,
     pub duration: u32,
     pub comment: String,
+    #[serde(default)]
+    pub backend: Option<BackendKind>,
+    #[serde(default)]
+    pub gateway: Option<IpAddr>,
+    #[serde(default)]
+    pub conflict: Option<ConflictPolicy>,
 }
 
-fn find_gateway_with_bind_addr(bind_addr: SocketAddr) -> Gateway {
-    let options = SearchOptions {
-        bind_addr,
-        ..Default::default()
-    };
-    igd::search_gateway(options).unwrap()
+/// Add the port mappings of a single row, and return those that were added, with the external
+/// ports that the gateway assigned. Ports that are skipped because of the conflict policy are
+/// left out.
+pub fn run(
+    options: Options,
+    backends: &mut Backends,
+    settings: &Settings,
+) -> Result<Vec<Mapping>, Error> {
+    let backend = options.backend;
+    let conflict = options.conflict.unwrap_or(settings.conflict);
+    let mappings = resolve(&options, backends)?;
+    add_all(
+        backend,
+        &mappings,
+        conflict,
+        backends,
+        &mut Report::default(),
+    )
 }
 
-fn find_gateway_and_addr() -> (Gateway, SocketAddr) {
-    let ifaces = get_if_addrs::get_if_addrs().unwrap();
-    ifaces
-        .iter()
-        .filter_map(|iface| {
-            if iface.is_loopback() || !iface.ip().is_ipv4() {
-                None
-            } else {
-                let options = SearchOptions {
-                    bind_addr: format!("{}:0", iface.addr.ip()).parse().unwrap(),
-                    ..Default::default()
-                };
-                igd::search_gateway(options).ok().and_then(|gateway| {
-                    if let get_if_addrs::IfAddr::V4(addr) = &iface.addr {
-                        Some((gateway, SocketAddr::V4(SocketAddrV4::new(addr.ip, 0))))
-                    } else {
-                        unreachable!()
-                    }
+/// The mappings that `remove` removes.
+#[derive(Clone, Debug)]
+pub enum Removal {
+    /// The given external ports, for each of the protocols.
+    Ports(PortRange, Protocols),
+    /// Every mapping with exactly this description.
+    Description(String),
+}
+
+/// Remove mappings from the gateway of `address`, or from the default gateway, and return them.
+/// Mappings that do not exist are left out, but stops at the first one that cannot be removed.
+pub fn remove(
+    address: Option<IpAddr>,
+    backend: Option<BackendKind>,
+    removal: &Removal,
+    backends: &mut Backends,
+) -> Result<Vec<Mapping>, Error> {
+    let local = backends.get(backend)?.discover(address, None)?;
+    let owned: Vec<_> = backends.owned().map(|(_, mapping)| mapping).collect();
+
+    // UPnP identifies a mapping by the gateway, the external port and the protocol. NAT-PMP and
+    // PCP identify it by the internal port, which is only known for the mappings that were added
+    // via `backends`.
+    let mappings: Vec<_> = match removal {
+        Removal::Ports(ports, protocols) => protocols
+            .protocols()
+            .iter()
+            .flat_map(|&protocol| {
+                let owned = &owned;
+                ports.iter().map(move |port| {
+                    owned
+                        .iter()
+                        .find(|mapping| {
+                            (
+                                mapping.internal.ip(),
+                                mapping.protocol,
+                                mapping.external_port,
+                            ) == (local, protocol, port)
+                        })
+                        .cloned()
+                        .unwrap_or_else(|| Mapping {
+                            internal: SocketAddr::new(local, port),
+                            external_port: port,
+                            protocol,
+                            duration: 0,
+                            comment: String::new(),
+                        })
                 })
+            })
+            .collect(),
+        Removal::Description(description) => backends
+            .get(backend)?
+            .list(local)?
+            .into_iter()
+            .filter(|entry| entry.description == *description)
+            .map(|entry| Mapping {
+                internal: SocketAddr::new(local, entry.internal_port),
+                external_port: entry.external_port,
+                protocol: entry.protocol,
+                duration: entry.lease_duration,
+                comment: entry.description,
+            })
+            .collect(),
+    };
+
+    // Of a port range or both protocols, often not every mapping exists. That is only an error
+    // if none of them does.
+    let mut removed = Vec::new();
+    let mut missing = None;
+    for mapping in mappings {
+        match backends.remove_port(backend, &mapping).map_err(Error::from) {
+            Ok(()) => {
+                info!(
+                    "Removed external port {} ({:?})",
+                    mapping.external_port, mapping.protocol
+                );
+                removed.push(mapping);
             }
+            Err(e) if e.code() == Some(714) => {
+                debug!(
+                    "External port {} ({:?}) was not mapped",
+                    mapping.external_port, mapping.protocol
+                );
+                missing = Some(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    match missing {
+        Some(e) if removed.is_empty() => Err(e),
+        _ => Ok(removed),
+    }
+}
+
+/// Ask every gateway with mappings of the daemon for its external address. A gateway is
+/// identified by its address, or by the local address if the backend does not know it.
+pub fn external_ips(backends: &Backends) -> Vec<(IpAddr, Result<IpAddr, Error>)> {
+    let mut gateways = BTreeMap::new();
+    for (kind, mapping) in backends.owned() {
+        // Pinholes make the IPv6 address itself reachable, there is no external one.
+        let internal = mapping.internal.ip();
+        if internal.is_ipv4() {
+            let gateway = backends.gateway(Some(kind), internal).unwrap_or(internal);
+            gateways.entry(gateway).or_insert((kind, internal));
+        }
+    }
+
+    gateways
+        .into_iter()
+        .map(|(gateway, (kind, internal))| {
+            let result = backends
+                .get(Some(kind))
+                .and_then(|backend| backend.external_ip(internal))
+                .map_err(Error::from);
+            (gateway, result)
         })
-        .next()
-        .unwrap()
+        .collect()
 }
 
-pub fn run(options: Options) -> Result<(), Box<dyn Error>> {
-    let port = options.port;
-    let protocol = options.protocol.into();
-    let duration = options.duration;
-    let comment = options.comment;
+/// Discover the gateway for `options` and build the mappings that are sent to it, one per port
+/// and protocol.
+fn resolve(options: &Options, backends: &Backends) -> Result<Vec<Mapping>, Error> {
+    let address = match &options.address {
+        None => None,
+        Some(addr) => Some(
+            addr.parse()
+                .map_err(|_| Error::InvalidAddress(addr.clone()))?,
+        ),
+    };
 
-    let (gateway, addr) = match options.address {
-        None => {
-            let (gateway, mut addr) = find_gateway_and_addr();
-            addr.set_port(port);
+    let external_ports = options
+        .external_port
+        .or(options.port)
+        .ok_or_else(|| Error::Config("Neither port nor external_port is given".into()))?;
+    let internal_ports = options
+        .internal_port
+        .or(options.port)
+        .ok_or_else(|| Error::Config("Neither port nor internal_port is given".into()))?;
+    if external_ports.size() != internal_ports.size() {
+        return Err(Error::Config(format!(
+            "External ports {} and internal ports {} differ in size",
+            external_ports, internal_ports
+        )));
+    }
 
-            let addr = match addr {
-                SocketAddr::V4(addr) => addr,
-                _ => panic!("No IPv4 given"),
-            };
+    let internal = backends
+        .get(options.backend)?
+        .discover(address, options.gateway)?;
 
-            (gateway, addr)
+    let mut mappings = Vec::new();
+    for &protocol in options.protocol.protocols() {
+        for (external_port, internal_port) in external_ports.iter().zip(internal_ports.iter()) {
+            mappings.push(Mapping {
+                internal: SocketAddr::new(internal, internal_port),
+                external_port,
+                protocol,
+                duration: options.duration,
+                comment: options.comment.clone(),
+            });
         }
+    }
 
-        Some(addr) => {
-            let addr = format!("{}:{}", addr, port).parse().unwrap();
+    Ok(mappings)
+}
 
-            let gateway = find_gateway_with_bind_addr(addr);
+/// Add the mappings of one row, count them in `report` and return those that were added, as the
+/// gateway made them. A failing port or protocol does not stop the others of the row, the
+/// failures are reported together.
+fn add_all(
+    backend: Option<BackendKind>,
+    mappings: &[Mapping],
+    conflict: ConflictPolicy,
+    backends: &Backends,
+    report: &mut Report,
+) -> Result<Vec<Mapping>, Error> {
+    let mut added = Vec::new();
+    let mut failures = Vec::new();
 
-            let addr = match addr {
-                SocketAddr::V4(addr) => addr,
-                _ => panic!("No IPv4 given"),
+    for mapping in mappings {
+        match add(backend, mapping, conflict, backends) {
+            Ok(Some(mapping)) => {
+                metrics::mapping_succeeded(&mapping);
+                report.mapped += 1;
+                added.push(mapping);
+            }
+            Ok(None) => report.skipped += 1,
+            Err(e) => {
+                metrics::mapping_failed(mapping);
+                if mappings.len() > 1 {
+                    warn!(
+                        "Failed to map port {} ({:?}): {}",
+                        mapping.external_port, mapping.protocol, e
+                    );
+                }
+                failures.push((mapping.external_port, mapping.protocol, e));
+            }
+        }
+    }
+
+    match failures.len() {
+        0 => Ok(added),
+        1 if mappings.len() == 1 => Err(failures.remove(0).2),
+        _ => Err(Error::Ports {
+            total: mappings.len(),
+            failed: failures,
+        }),
+    }
+}
+
+/// Add a single mapping and return it as the gateway made it. Returns `None` if it was skipped
+/// because of the conflict policy.
+fn add(
+    backend: Option<BackendKind>,
+    mapping: &Mapping,
+    conflict: ConflictPolicy,
+    backends: &Backends,
+) -> Result<Option<Mapping>, Error> {
+    let added = match backends.add_port(backend, mapping) {
+        Err(AddPortError::PortInUse) => {
+            let steal = match conflict {
+                ConflictPolicy::Steal => true,
+                ConflictPolicy::SkipAndWarn => false,
+                ConflictPolicy::Fail => return Err(igd::AddPortError::PortInUse.into()),
+                ConflictPolicy::StealOnlyIfOurs => is_ours(backend, mapping, backends)?,
             };
 
-            (gateway, addr)
-        }
-    };
+            if !steal {
+                warn!(
+                    "Port {} ({:?}) is already mapped to another client, skip it.",
+                    mapping.external_port, mapping.protocol
+                );
+                return Ok(None);
+            }
 
-    let f = || gateway.add_port(protocol, port, addr, duration, &comment);
-    f().or_else(|e| match e {
-        AddPortError::PortInUse => {
             debug!("Port already in use. Delete mapping.");
-            gateway.remove_port(protocol, port).unwrap();
+            backends.get(backend)?.remove_port(mapping)?;
             debug!("Retry port mapping.");
-            f()
+            let added = backends.add_port(backend, mapping)?;
+            metrics::port_stolen();
+            added
+        }
+        result => result?,
+    };
+
+    info!(
+        "Mapped external port {} ({:?}) to {}",
+        added.external_port, added.protocol, added.internal
+    );
+
+    Ok(Some(added))
+}
+
+/// Check whether the existing mapping of the external port of `mapping` was made by us: either
+/// it has the same comment, or it points to an address we mapped this port to before.
+fn is_ours(
+    backend: Option<BackendKind>,
+    mapping: &Mapping,
+    backends: &Backends,
+) -> Result<bool, Error> {
+    let entries = backends.get(backend)?.list(mapping.internal.ip())?;
+    let entry = entries
+        .iter()
+        .find(|e| e.protocol == mapping.protocol && e.external_port == mapping.external_port);
+
+    Ok(match entry {
+        // The mapping vanished in the meantime, there is nothing to protect.
+        None => true,
+        Some(entry) => {
+            entry.description == mapping.comment
+                || backends.owned().any(|(_, owned)| {
+                    owned.protocol == mapping.protocol
+                        && owned.external_port == mapping.external_port
+                        && owned.internal.ip().to_string() == entry.internal_client
+                })
         }
-        e => Err(e),
-    })?;
+    })
+}
+
+/// Read the port mappings from `reader`, in CSV format, and add each of them with `run_all`.
+pub fn run_csv<R: io::Read>(
+    reader: R,
+    backends: &mut Backends,
+    settings: &Settings,
+) -> Result<Report, Error> {
+    run_all(config::read_csv(reader)?, backends, settings)
+}
+
+/// Add the port mappings of all `rows`.
+///
+/// Mappings that were added by an earlier call, but are not in `rows` anymore, are removed
+/// before anything is added. This way, a row whose address or protocol changed does not collide
+/// with its old mapping.
+///
+/// Rows are resolved and their gateways discovered concurrently, and then the rows of different
+/// gateways are added concurrently, up to `settings.parallelism` at a time, so that a slow
+/// gateway does not hold up the others.
+///
+/// A row that fails is logged and counted in the returned report, the other rows are processed
+/// anyway. With `settings.fail_fast`, the first failure is returned as error instead, and no
+/// further rows are started.
+pub fn run_all(
+    rows: Vec<Options>,
+    backends: &mut Backends,
+    settings: &Settings,
+) -> Result<Report, Error> {
+    backends.start_cycle();
+    let backends = &*backends;
+    let parallelism = settings.parallelism.max(1);
+    let stop = AtomicBool::new(false);
 
-    Ok(())
+    // Rows that look for the same gateway are resolved one after another, so that it is only
+    // searched once.
+    let mut searches: BTreeMap<_, Vec<_>> = BTreeMap::new();
+    for (index, options) in rows.into_iter().enumerate() {
+        let search = (options.backend, options.address.clone(), options.gateway);
+        searches.entry(search).or_default().push((index, options));
+    }
+    let mut resolved = in_parallel(
+        searches.into_values().collect(),
+        parallelism,
+        &stop,
+        |(index, options)| {
+            info!("Processing: {:?}", options);
+            let result = resolve(&options, backends);
+            if result.is_err() && settings.fail_fast {
+                stop.store(true, Ordering::Relaxed);
+            }
+            (index, options, result)
+        },
+    );
+    resolved.sort_by_key(|(index, ..)| *index);
+
+    let mut report = Report::default();
+    let mut desired = Vec::new();
+    let mut failed = Vec::new();
+    for (index, options, result) in resolved {
+        match result {
+            Ok(mappings) => desired.push((
+                index,
+                options.backend,
+                options.conflict.unwrap_or(settings.conflict),
+                mappings,
+            )),
+            Err(e) if settings.fail_fast => return Err(e),
+            Err(e) => {
+                error!("Failed to map row {}: {}", index + 1, e);
+                report.failed.push((index + 1, e));
+                failed.push(options);
+            }
+        }
+    }
+
+    // The old mappings of a row that could not be resolved are unknown, so those it might have
+    // made are kept.
+    let kept: Vec<_> = failed
+        .iter()
+        .flat_map(|options| made_by(options, backends))
+        .collect();
+    backends.reconcile(
+        desired
+            .iter()
+            .flat_map(|(_, backend, _, mappings)| mappings.iter().map(move |m| (*backend, m)))
+            .chain(kept.iter().map(|(kind, mapping)| (Some(*kind), mapping))),
+    );
+
+    // All mappings of a row go to the same gateway.
+    let mut gateways: BTreeMap<Target, Vec<usize>> = BTreeMap::new();
+    for (position, (_, backend, _, mappings)) in desired.iter().enumerate() {
+        if let Some(mapping) = mappings.first() {
+            let target = Target::new(*backend, mapping.internal.ip(), backends);
+            gateways.entry(target).or_default().push(position);
+        }
+    }
+
+    let rows = in_parallel(
+        gateways.into_values().collect(),
+        parallelism,
+        &stop,
+        |position| {
+            let mut report = Report::default();
+            let (index, backend, conflict, mappings) = &desired[position];
+            if let Err(e) = add_all(*backend, mappings, *conflict, backends, &mut report) {
+                error!("Failed to map row {}: {}", index + 1, e);
+                report.failed.push((index + 1, e));
+                if settings.fail_fast {
+                    stop.store(true, Ordering::Relaxed);
+                }
+            }
+            report
+        },
+    );
+    for row in rows {
+        report.merge(row);
+    }
+    report.failed.sort_by_key(|(row, _)| *row);
+
+    metrics::cycle_finished();
+    info!("Cycle finished: {}", report);
+
+    if settings.fail_fast && !report.failed.is_empty() {
+        return Err(report.failed.remove(0).1);
+    }
+
+    Ok(report)
+}
+
+/// Process the items of `groups` with `work` on up to `parallelism` threads. The items of a group
+/// are processed one after another, in order. No further items are started once `stop` is set.
+fn in_parallel<T, R, F>(
+    groups: Vec<Vec<T>>,
+    parallelism: usize,
+    stop: &AtomicBool,
+    work: F,
+) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync,
+{
+    let queue = Mutex::new(groups.into_iter());
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..parallelism)
+            .map(|_| {
+                scope.spawn(|| {
+                    let next = || queue.lock().ok().and_then(|mut queue| queue.next());
+                    let mut results = Vec::new();
+                    while let Some(group) = next() {
+                        for item in group {
+                            if stop.load(Ordering::Relaxed) {
+                                return results;
+                            }
+                            results.push(work(item));
+                        }
+                    }
+                    results
+                })
+            })
+            .collect();
+
+        let mut results = Vec::new();
+        for worker in workers {
+            match worker.join() {
+                Ok(worker) => results.extend(worker),
+                Err(_) => error!("A worker stopped unexpectedly, its rows are not counted."),
+            }
+        }
+        results
+    })
+}
+
+/// The owned mappings that the row of `options` might have made: those of its backend, protocols
+/// and internal ports, and of its address if it is valid.
+fn made_by(options: &Options, backends: &Backends) -> Vec<(BackendKind, Mapping)> {
+    let address = options
+        .address
+        .as_ref()
+        .and_then(|address| address.parse::<IpAddr>().ok());
+    let internal_ports = options.internal_port.or(options.port);
+
+    backends
+        .owned()
+        .filter(|(kind, mapping)| {
+            options.backend.map_or(true, |backend| backend == *kind)
+                && options.protocol.protocols().contains(&mapping.protocol)
+                && address.map_or(true, |address| address == mapping.internal.ip())
+                && internal_ports.map_or(true, |ports| {
+                    (ports.start..=ports.end).contains(&mapping.internal.port())
+                })
+        })
+        .collect()
+}
+
+/// Where the requests of a row go to. Requests to the same target are never sent concurrently.
+#[derive(Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum Target {
+    Gateway(IpAddr),
+    /// The backend does not know the address of the gateway, so its internal address is used.
+    Internal(Option<BackendKind>, IpAddr),
+}
+
+impl Target {
+    fn new(backend: Option<BackendKind>, internal: IpAddr, backends: &Backends) -> Self {
+        match backends.gateway(backend, internal) {
+            Some(gateway) => Target::Gateway(gateway),
+            None => Target::Internal(backend, internal),
+        }
+    }
 }
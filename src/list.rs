@@ -0,0 +1,181 @@
+//! The mappings of the gateways, as the router reports them, for debugging without a separate
+//! tool like `upnpc -l`.
+
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::backend::{Backends, PortMappingEntry};
+use crate::error::Error;
+use crate::BackendKind;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Output {
+    Table,
+    Json,
+    /// The CSV format of the mapping file, so that the rows can be pasted into it.
+    Csv,
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(Output::Table),
+            "json" => Ok(Output::Json),
+            "csv" => Ok(Output::Csv),
+            _ => Err(format!("Unknown output format: {}", s)),
+        }
+    }
+}
+
+/// The mappings of the gateway that was discovered for a local address.
+#[derive(Debug)]
+pub struct Listing {
+    pub address: IpAddr,
+    /// The address of the gateway, if the backend knows it.
+    pub gateway: Option<IpAddr>,
+    pub entries: Vec<PortMappingEntry>,
+}
+
+/// List the mappings of the gateway of each of `addresses`, or of the default gateway if none
+/// is given. A gateway that is shared by several addresses is listed only once.
+pub fn list(
+    backends: &Backends,
+    kind: Option<BackendKind>,
+    addresses: &[IpAddr],
+) -> Result<Vec<Listing>, Error> {
+    let backend = backends.get(kind)?;
+    let addresses = match addresses {
+        [] => vec![None],
+        addresses => addresses.iter().copied().map(Some).collect(),
+    };
+
+    let mut listings: Vec<Listing> = Vec::new();
+    for address in addresses {
+        let address = backend.discover(address, None)?;
+        let gateway = backend.gateway(address);
+        if gateway.is_some() && listings.iter().any(|l| l.gateway == gateway) {
+            continue;
+        }
+
+        listings.push(Listing {
+            address,
+            gateway,
+            entries: backend.list(address)?,
+        });
+    }
+
+    Ok(listings)
+}
+
+/// A mapping in JSON output, with the gateway it belongs to.
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    gateway: Option<IpAddr>,
+    external_port: u16,
+    protocol: String,
+    internal_client: &'a str,
+    internal_port: u16,
+    /// Remaining lease time in seconds, 0 for a permanent mapping.
+    lease_duration: u32,
+    enabled: bool,
+    description: &'a str,
+}
+
+/// A mapping as a row of the mapping file.
+#[derive(Serialize)]
+struct CsvRow<'a> {
+    address: &'a str,
+    external_port: u16,
+    internal_port: u16,
+    protocol: String,
+    duration: u32,
+    comment: &'a str,
+}
+
+/// Write `listings` to `writer` in the given format.
+pub fn write<W: Write>(listings: &[Listing], output: Output, mut writer: W) -> io::Result<()> {
+    let entries = listings
+        .iter()
+        .flat_map(|listing| listing.entries.iter().map(move |entry| (listing, entry)));
+
+    match output {
+        Output::Table => {
+            for listing in listings {
+                match listing.gateway {
+                    Some(gateway) => writeln!(writer, "Gateway {} ({})", gateway, listing.address)?,
+                    None => writeln!(writer, "Gateway of {}", listing.address)?,
+                }
+                writeln!(
+                    writer,
+                    "{:>8}  {:<8}  {:<21}  {:>9}  {:<7}  Description",
+                    "External", "Protocol", "Internal", "Lease", "Enabled"
+                )?;
+                for entry in &listing.entries {
+                    writeln!(
+                        writer,
+                        "{:>8}  {:<8}  {:<21}  {:>9}  {:<7}  {}",
+                        entry.external_port,
+                        format!("{:?}", entry.protocol),
+                        format!("{}:{}", entry.internal_client, entry.internal_port),
+                        match entry.lease_duration {
+                            0 => "permanent".to_string(),
+                            lease => format!("{}s", lease),
+                        },
+                        if entry.enabled { "yes" } else { "no" },
+                        entry.description
+                    )?;
+                }
+            }
+        }
+        Output::Json => {
+            let entries: Vec<_> = entries
+                .map(|(listing, entry)| JsonEntry {
+                    gateway: listing.gateway,
+                    external_port: entry.external_port,
+                    protocol: format!("{:?}", entry.protocol),
+                    internal_client: &entry.internal_client,
+                    internal_port: entry.internal_port,
+                    lease_duration: entry.lease_duration,
+                    enabled: entry.enabled,
+                    description: &entry.description,
+                })
+                .collect();
+            serde_json::to_writer_pretty(&mut writer, &entries)?;
+            writeln!(writer)?;
+        }
+        Output::Csv => {
+            let mut csv = csv::WriterBuilder::new()
+                .delimiter(b';')
+                .has_headers(false)
+                .from_writer(writer);
+            csv.write_record([
+                "address",
+                "external_port",
+                "internal_port",
+                "protocol",
+                "duration",
+                "comment",
+            ])?;
+            for (_, entry) in entries {
+                csv.serialize(CsvRow {
+                    address: &entry.internal_client,
+                    external_port: entry.external_port,
+                    internal_port: entry.internal_port,
+                    protocol: format!("{:?}", entry.protocol),
+                    // Gateways only report the remaining lease, which would shrink with every
+                    // round trip through the mapping file. The requested one is not known.
+                    duration: 0,
+                    comment: &entry.description,
+                })?;
+            }
+            csv.flush()?;
+        }
+    }
+
+    Ok(())
+}
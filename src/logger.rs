@@ -0,0 +1,41 @@
+//! A logger whose filter can be replaced at runtime, so that the log level of a running daemon
+//! can be changed by reloading its configuration.
+
+use std::sync::RwLock;
+
+use log::{Log, Metadata, Record};
+
+struct Logger(RwLock<Option<env_logger::Logger>>);
+
+static LOGGER: Logger = Logger(RwLock::new(None));
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match &*self.0.read().unwrap() {
+            Some(logger) => logger.enabled(metadata),
+            None => false,
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if let Some(logger) = &*self.0.read().unwrap() {
+            logger.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(logger) = &*self.0.read().unwrap() {
+            logger.flush();
+        }
+    }
+}
+
+/// Log with the given filter, in the syntax of `RUST_LOG`, from now on.
+pub fn set_filter(filter: &str) {
+    let logger = env_logger::Builder::new().parse_filters(filter).build();
+    log::set_max_level(logger.filter());
+    *LOGGER.0.write().unwrap() = Some(logger);
+
+    // Only fails if the logger is already set, which is either this one or one of the caller.
+    log::set_logger(&LOGGER).ok();
+}
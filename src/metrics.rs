@@ -0,0 +1,329 @@
+//! Metrics of the daemon in the OpenMetrics text format, for Prometheus.
+//!
+//! The metrics are always collected. They are only served if the daemon is asked to listen for
+//! scrapes, with a minimal HTTP server that answers every `GET` with the metrics.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{debug, warn};
+
+use crate::backend::Mapping;
+
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics::new());
+
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+struct Metrics {
+    cycles: u64,
+    /// By protocol, external port and comment of the mapping.
+    mappings: BTreeMap<(String, u16, String), Attempts>,
+    stolen: u64,
+    discovery: Summary,
+    /// By SOAP action.
+    soap_calls: BTreeMap<String, Summary>,
+    /// By the address of the gateway.
+    external_ips: BTreeMap<IpAddr, IpAddr>,
+}
+
+#[derive(Default)]
+struct Attempts {
+    succeeded: u64,
+    failed: u64,
+    /// In seconds since the Unix epoch.
+    last_success: Option<f64>,
+}
+
+/// A summary without quantiles, enough to compute the average latency over time.
+#[derive(Default)]
+struct Summary {
+    count: u64,
+    sum: f64,
+}
+
+impl Summary {
+    fn observe(&mut self, duration: Duration) {
+        self.count += 1;
+        self.sum += duration.as_secs_f64();
+    }
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            cycles: 0,
+            mappings: BTreeMap::new(),
+            stolen: 0,
+            discovery: Summary { count: 0, sum: 0.0 },
+            soap_calls: BTreeMap::new(),
+            external_ips: BTreeMap::new(),
+        }
+    }
+}
+
+fn metrics() -> MutexGuard<'static, Metrics> {
+    // The counters stay usable even if a thread panicked while holding the lock.
+    METRICS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn attempts(mapping: &Mapping) -> (String, u16, String) {
+    (
+        format!("{:?}", mapping.protocol),
+        mapping.external_port,
+        mapping.comment.clone(),
+    )
+}
+
+pub(crate) fn cycle_finished() {
+    metrics().cycles += 1;
+}
+
+pub(crate) fn mapping_succeeded(mapping: &Mapping) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    let mut metrics = metrics();
+    let attempts = metrics.mappings.entry(attempts(mapping)).or_default();
+    attempts.succeeded += 1;
+    attempts.last_success = Some(now);
+}
+
+pub(crate) fn mapping_failed(mapping: &Mapping) {
+    metrics()
+        .mappings
+        .entry(attempts(mapping))
+        .or_default()
+        .failed += 1;
+}
+
+/// A port of another client was taken over because of the conflict policy.
+pub(crate) fn port_stolen() {
+    metrics().stolen += 1;
+}
+
+pub(crate) fn discovery(duration: Duration) {
+    metrics().discovery.observe(duration);
+}
+
+/// Call `action` with `call` and observe how long it took.
+pub(crate) fn soap_call<T>(action: &str, call: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = call();
+    metrics()
+        .soap_calls
+        .entry(action.to_string())
+        .or_default()
+        .observe(start.elapsed());
+    result
+}
+
+/// Remember the external address that `gateway` reported.
+pub fn set_external_ip(gateway: IpAddr, external_ip: IpAddr) {
+    metrics().external_ips.insert(gateway, external_ip);
+}
+
+/// Escape a label value.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// All metrics in the OpenMetrics text format.
+pub fn render() -> String {
+    let mut out = String::new();
+    // Writing to a string does not fail.
+    write_metrics(&mut out, &metrics()).unwrap();
+    out
+}
+
+fn write_metrics(out: &mut String, metrics: &Metrics) -> fmt::Result {
+    let mappings: Vec<_> = metrics
+        .mappings
+        .iter()
+        .map(|((protocol, port, comment), attempts)| {
+            let labels = format!(
+                "protocol=\"{}\",external_port=\"{}\",comment=\"{}\"",
+                protocol,
+                port,
+                label(comment)
+            );
+            (labels, attempts)
+        })
+        .collect();
+
+    family(
+        out,
+        "cycles",
+        "counter",
+        "Iterations over the mapping file.",
+    )?;
+    writeln!(out, "upnp_daemon_cycles_total {}", metrics.cycles)?;
+
+    family(
+        out,
+        "mapping_successes",
+        "counter",
+        "Ports that were mapped.",
+    )?;
+    for (labels, attempts) in &mappings {
+        writeln!(
+            out,
+            "upnp_daemon_mapping_successes_total{{{}}} {}",
+            labels, attempts.succeeded
+        )?;
+    }
+    family(
+        out,
+        "mapping_failures",
+        "counter",
+        "Ports that could not be mapped.",
+    )?;
+    for (labels, attempts) in &mappings {
+        writeln!(
+            out,
+            "upnp_daemon_mapping_failures_total{{{}}} {}",
+            labels, attempts.failed
+        )?;
+    }
+    family(
+        out,
+        "mapping_last_success_timestamp_seconds",
+        "gauge",
+        "When a port was last mapped.",
+    )?;
+    for (labels, attempts) in &mappings {
+        if let Some(last_success) = attempts.last_success {
+            writeln!(
+                out,
+                "upnp_daemon_mapping_last_success_timestamp_seconds{{{}}} {}",
+                labels, last_success
+            )?;
+        }
+    }
+
+    family(
+        out,
+        "conflicts_stolen",
+        "counter",
+        "Ports of other clients that were taken over.",
+    )?;
+    writeln!(out, "upnp_daemon_conflicts_stolen_total {}", metrics.stolen)?;
+
+    family(
+        out,
+        "ssdp_discovery_seconds",
+        "summary",
+        "Duration of SSDP searches.",
+    )?;
+    writeln!(
+        out,
+        "upnp_daemon_ssdp_discovery_seconds_count {}",
+        metrics.discovery.count
+    )?;
+    writeln!(
+        out,
+        "upnp_daemon_ssdp_discovery_seconds_sum {}",
+        metrics.discovery.sum
+    )?;
+
+    family(
+        out,
+        "soap_call_seconds",
+        "summary",
+        "Duration of SOAP calls.",
+    )?;
+    for (action, summary) in &metrics.soap_calls {
+        let action = label(action);
+        writeln!(
+            out,
+            "upnp_daemon_soap_call_seconds_count{{action=\"{}\"}} {}",
+            action, summary.count
+        )?;
+        writeln!(
+            out,
+            "upnp_daemon_soap_call_seconds_sum{{action=\"{}\"}} {}",
+            action, summary.sum
+        )?;
+    }
+
+    family(
+        out,
+        "external_ip",
+        "gauge",
+        "The external address that a gateway reported.",
+    )?;
+    for (gateway, external_ip) in &metrics.external_ips {
+        writeln!(
+            out,
+            "upnp_daemon_external_ip{{gateway=\"{}\",address=\"{}\"}} 1",
+            gateway, external_ip
+        )?;
+    }
+
+    writeln!(out, "# EOF")
+}
+
+/// The type and help of a metric family.
+fn family(out: &mut String, name: &str, kind: &str, help: &str) -> fmt::Result {
+    writeln!(out, "# TYPE upnp_daemon_{} {}", name, kind)?;
+    writeln!(out, "# HELP upnp_daemon_{} {}", name, help)
+}
+
+/// Answer HTTP requests on `address` with the metrics.
+pub fn serve(address: SocketAddr) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = respond(stream) {
+                        debug!("Metrics request failed: {}", e);
+                    }
+                }
+                Err(e) => warn!("Cannot accept a metrics request: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers are not needed, but have to be read before answering.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let (status, content_type, body) = match request_line.split_whitespace().next() {
+        Some("GET") => ("200 OK", CONTENT_TYPE, render()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain; charset=utf-8",
+            "Only GET is supported\n".to_string(),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
@@ -0,0 +1,257 @@
+//! A minimal NAT-PMP client, as specified in RFC 6886.
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::Duration;
+
+use log::debug;
+
+use crate::PortMappingProtocol;
+
+/// The well-known port NAT-PMP servers listen on.
+pub const PORT: u16 = 5351;
+
+/// The lifetime that RFC 6886 recommends for mappings, used if the configured duration is 0.
+pub const DEFAULT_LIFETIME: u32 = 7200;
+
+const VERSION: u8 = 0;
+const OP_EXTERNAL_ADDRESS: u8 = 0;
+const OP_MAP_UDP: u8 = 1;
+const OP_MAP_TCP: u8 = 2;
+const OP_RESPONSE: u8 = 128;
+
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+const DEFAULT_ATTEMPTS: u32 = 4;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Timeout,
+    InvalidResponse,
+    UnsupportedVersion,
+    NotAuthorized,
+    NetworkFailure,
+    OutOfResources,
+    UnsupportedOpcode,
+    Unknown(u16),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "NAT-PMP I/O error: {}", e),
+            Error::Timeout => write!(f, "NAT-PMP gateway did not answer"),
+            Error::InvalidResponse => write!(f, "NAT-PMP gateway sent an invalid response"),
+            Error::UnsupportedVersion => {
+                write!(f, "NAT-PMP version is not supported by the gateway")
+            }
+            Error::NotAuthorized => write!(f, "NAT-PMP gateway refused the request"),
+            Error::NetworkFailure => write!(f, "NAT-PMP gateway has no external address"),
+            Error::OutOfResources => write!(f, "NAT-PMP gateway is out of resources"),
+            Error::UnsupportedOpcode => write!(f, "NAT-PMP opcode is not supported by the gateway"),
+            Error::Unknown(code) => write!(f, "NAT-PMP gateway returned result code {}", code),
+        }
+    }
+}
+
+impl StdError for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Error::Timeout,
+            _ => Error::Io(e),
+        }
+    }
+}
+
+/// A mapping as confirmed by the gateway.
+#[derive(Debug, PartialEq)]
+pub struct Mapping {
+    pub internal_port: u16,
+    pub external_port: u16,
+    pub lifetime: u32,
+}
+
+pub struct Client {
+    socket: UdpSocket,
+    gateway: SocketAddr,
+    client_address: Ipv4Addr,
+    attempts: u32,
+}
+
+impl Client {
+    /// Create a client that sends its requests from `bind_addr` to `gateway`.
+    ///
+    /// NAT-PMP always maps ports for the address the request originates from, so `bind_addr`
+    /// has to be a local address. Use `Ipv4Addr::UNSPECIFIED` to let the system decide.
+    pub fn new(bind_addr: Ipv4Addr, gateway: SocketAddr) -> io::Result<Self> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(bind_addr, 0))?;
+        socket.connect(gateway)?;
+
+        let client_address = match socket.local_addr()? {
+            SocketAddr::V4(addr) => *addr.ip(),
+            SocketAddr::V6(_) => unreachable!(),
+        };
+
+        Ok(Client {
+            socket,
+            gateway,
+            client_address,
+            attempts: DEFAULT_ATTEMPTS,
+        })
+    }
+
+    /// Set how often a request is sent before giving up. The waiting time doubles with each
+    /// attempt, starting with 250 ms.
+    pub fn attempts(mut self, attempts: u32) -> Self {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    /// The address the mappings are created for.
+    pub fn client_address(&self) -> Ipv4Addr {
+        self.client_address
+    }
+
+    pub fn external_address(&self) -> Result<Ipv4Addr, Error> {
+        let response = self.request(&[VERSION, OP_EXTERNAL_ADDRESS], 12)?;
+        Ok(Ipv4Addr::new(
+            response[8],
+            response[9],
+            response[10],
+            response[11],
+        ))
+    }
+
+    /// Request a mapping from `external_port` to `internal_port` of the local machine.
+    ///
+    /// The gateway is free to assign a different external port or lifetime, the actual values
+    /// are returned.
+    pub fn add_port(
+        &self,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+    ) -> Result<Mapping, Error> {
+        self.map(protocol, internal_port, external_port, lifetime)
+    }
+
+    pub fn remove_port(
+        &self,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+    ) -> Result<(), Error> {
+        self.map(protocol, internal_port, 0, 0).map(|_| ())
+    }
+
+    fn map(
+        &self,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+    ) -> Result<Mapping, Error> {
+        let opcode = match protocol {
+            PortMappingProtocol::UDP => OP_MAP_UDP,
+            PortMappingProtocol::TCP => OP_MAP_TCP,
+        };
+
+        let mut request = vec![VERSION, opcode, 0, 0];
+        request.extend_from_slice(&internal_port.to_be_bytes());
+        request.extend_from_slice(&external_port.to_be_bytes());
+        request.extend_from_slice(&lifetime.to_be_bytes());
+
+        let response = self.request(&request, 16)?;
+
+        let mapping = Mapping {
+            internal_port: u16::from_be_bytes([response[8], response[9]]),
+            external_port: u16::from_be_bytes([response[10], response[11]]),
+            lifetime: u32::from_be_bytes([response[12], response[13], response[14], response[15]]),
+        };
+
+        if mapping.internal_port != internal_port {
+            return Err(Error::InvalidResponse);
+        }
+
+        Ok(mapping)
+    }
+
+    fn request(&self, request: &[u8], len: usize) -> Result<Vec<u8>, Error> {
+        let opcode = request[1];
+        let mut timeout = INITIAL_TIMEOUT;
+
+        for _ in 0..self.attempts {
+            debug!(
+                "Send NAT-PMP request with opcode {} to {}",
+                opcode, self.gateway
+            );
+            self.socket.send(request)?;
+            self.socket.set_read_timeout(Some(timeout))?;
+
+            loop {
+                let mut buf = [0u8; 16];
+                let read = match self.socket.recv(&mut buf) {
+                    Ok(r) => r,
+                    Err(e) => match Error::from(e) {
+                        Error::Timeout => break,
+                        e => return Err(e),
+                    },
+                };
+
+                // Responses for other requests are silently discarded.
+                if read < 4 || buf[1] != OP_RESPONSE + opcode {
+                    continue;
+                }
+
+                if buf[0] != VERSION {
+                    return Err(Error::UnsupportedVersion);
+                }
+
+                match u16::from_be_bytes([buf[2], buf[3]]) {
+                    0 => {}
+                    1 => return Err(Error::UnsupportedVersion),
+                    2 => return Err(Error::NotAuthorized),
+                    3 => return Err(Error::NetworkFailure),
+                    4 => return Err(Error::OutOfResources),
+                    5 => return Err(Error::UnsupportedOpcode),
+                    code => return Err(Error::Unknown(code)),
+                }
+
+                if read < len {
+                    return Err(Error::InvalidResponse);
+                }
+
+                return Ok(buf[..len].to_vec());
+            }
+
+            timeout *= 2;
+        }
+
+        Err(Error::Timeout)
+    }
+}
+
+/// Determine the IPv4 default gateway from the kernel routing table.
+pub fn default_gateway() -> io::Result<Ipv4Addr> {
+    let routes = fs::read_to_string("/proc/net/route")?;
+
+    routes
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            if fields.len() < 3 || fields[1] != "00000000" {
+                return None;
+            }
+            u32::from_str_radix(fields[2], 16)
+                .ok()
+                .map(|gateway| Ipv4Addr::from(gateway.to_ne_bytes()))
+        })
+        .find(|gateway| !gateway.is_unspecified())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No default gateway found"))
+}
@@ -0,0 +1,423 @@
+//! A minimal Port Control Protocol client, as specified in RFC 6887.
+//!
+//! Only the MAP opcode is implemented, which is all that is needed to open inbound ports. Since
+//! PCP works with IPv6 addresses throughout, mappings for IPv6 hosts are supported as well.
+
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{natpmp, state, PortMappingProtocol};
+
+/// The well-known port PCP servers listen on, shared with NAT-PMP.
+pub const PORT: u16 = 5351;
+
+/// The lifetime used if the configured duration is 0, since a lifetime of 0 deletes a mapping.
+pub const DEFAULT_LIFETIME: u32 = 7200;
+
+/// The mapping nonce, which has to stay the same for renewals and deletion of a mapping.
+pub type Nonce = [u8; 12];
+
+const VERSION: u8 = 2;
+const OP_ANNOUNCE: u8 = 0;
+const OP_MAP: u8 = 1;
+const OP_RESPONSE: u8 = 0x80;
+
+const HEADER_LEN: usize = 24;
+const MAP_LEN: usize = HEADER_LEN + 36;
+
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+const DEFAULT_ATTEMPTS: u32 = 4;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Timeout,
+    InvalidResponse,
+    /// The server does not speak PCP. It might still understand NAT-PMP, though.
+    UnsupportedVersion,
+    NotAuthorized,
+    MalformedRequest,
+    UnsupportedOpcode,
+    UnsupportedOption,
+    MalformedOption,
+    NetworkFailure,
+    NoResources,
+    UnsupportedProtocol,
+    UserExceededQuota,
+    CannotProvideExternal,
+    AddressMismatch,
+    ExcessiveRemotePeers,
+    Unknown(u8),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "PCP I/O error: {}", e),
+            Error::Timeout => write!(f, "PCP server did not answer"),
+            Error::InvalidResponse => write!(f, "PCP server sent an invalid response"),
+            Error::UnsupportedVersion => write!(f, "PCP version is not supported by the server"),
+            Error::NotAuthorized => write!(f, "PCP server refused the request"),
+            Error::MalformedRequest => write!(f, "PCP server could not parse the request"),
+            Error::UnsupportedOpcode => write!(f, "PCP opcode is not supported by the server"),
+            Error::UnsupportedOption => write!(f, "PCP option is not supported by the server"),
+            Error::MalformedOption => write!(f, "PCP server could not parse an option"),
+            Error::NetworkFailure => write!(f, "PCP server has a network failure"),
+            Error::NoResources => write!(f, "PCP server is out of resources"),
+            Error::UnsupportedProtocol => write!(f, "PCP protocol is not supported by the server"),
+            Error::UserExceededQuota => write!(f, "PCP mapping quota is exceeded"),
+            Error::CannotProvideExternal => {
+                write!(f, "PCP server cannot provide the external port")
+            }
+            Error::AddressMismatch => write!(f, "PCP client address does not match the source"),
+            Error::ExcessiveRemotePeers => write!(f, "PCP server cannot handle that many peers"),
+            Error::Unknown(code) => write!(f, "PCP server returned result code {}", code),
+        }
+    }
+}
+
+impl StdError for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Error::Timeout,
+            _ => Error::Io(e),
+        }
+    }
+}
+
+/// A mapping as confirmed by the server.
+#[derive(Debug, PartialEq)]
+pub struct Mapping {
+    pub internal_port: u16,
+    pub external_address: IpAddr,
+    pub external_port: u16,
+    pub lifetime: u32,
+    /// The server's epoch, a decreasing value tells that the server lost its state.
+    pub epoch: u32,
+}
+
+pub struct Client {
+    socket: UdpSocket,
+    client_address: IpAddr,
+    attempts: u32,
+}
+
+impl Client {
+    /// Create a client that sends its requests from `bind_addr` to `server`.
+    ///
+    /// Like with NAT-PMP, mappings are always created for the address the request originates
+    /// from. Use an unspecified address to let the system decide.
+    pub fn new(bind_addr: IpAddr, server: SocketAddr) -> io::Result<Self> {
+        let socket = UdpSocket::bind(SocketAddr::new(bind_addr, 0))?;
+        socket.connect(server)?;
+        let client_address = socket.local_addr()?.ip();
+
+        Ok(Client {
+            socket,
+            client_address,
+            attempts: DEFAULT_ATTEMPTS,
+        })
+    }
+
+    /// Set how often a request is sent before giving up. The waiting time doubles with each
+    /// attempt, starting with 250 ms.
+    pub fn attempts(mut self, attempts: u32) -> Self {
+        self.attempts = attempts.max(1);
+        self
+    }
+
+    /// The address the mappings are created for.
+    pub fn client_address(&self) -> IpAddr {
+        self.client_address
+    }
+
+    /// Check whether the server speaks PCP, returning its epoch.
+    pub fn announce(&self) -> Result<u32, Error> {
+        let mut request = Vec::with_capacity(HEADER_LEN);
+        request.extend_from_slice(&[VERSION, OP_ANNOUNCE, 0, 0, 0, 0, 0, 0]);
+        request.extend_from_slice(&to_ipv6(self.client_address).octets());
+
+        let response = self.request(&request, None)?;
+
+        Ok(u32::from_be_bytes([
+            response[8],
+            response[9],
+            response[10],
+            response[11],
+        ]))
+    }
+
+    /// Request a mapping from `external_port` to `internal_port` of the local machine.
+    ///
+    /// The nonce has to be the same for every renewal of the mapping, see `Nonces` for an easy
+    /// way to keep track of it.
+    pub fn add_port(
+        &self,
+        nonce: &Nonce,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+    ) -> Result<Mapping, Error> {
+        self.map(nonce, protocol, internal_port, external_port, lifetime)
+    }
+
+    pub fn remove_port(
+        &self,
+        nonce: &Nonce,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+    ) -> Result<(), Error> {
+        self.map(nonce, protocol, internal_port, 0, 0).map(|_| ())
+    }
+
+    fn map(
+        &self,
+        nonce: &Nonce,
+        protocol: PortMappingProtocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+    ) -> Result<Mapping, Error> {
+        let protocol = match protocol {
+            PortMappingProtocol::TCP => PROTOCOL_TCP,
+            PortMappingProtocol::UDP => PROTOCOL_UDP,
+        };
+
+        let suggested_address = match self.client_address {
+            IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.to_ipv6_mapped(),
+            IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED,
+        };
+
+        let mut request = Vec::with_capacity(MAP_LEN);
+        request.extend_from_slice(&[VERSION, OP_MAP, 0, 0]);
+        request.extend_from_slice(&lifetime.to_be_bytes());
+        request.extend_from_slice(&to_ipv6(self.client_address).octets());
+        request.extend_from_slice(nonce);
+        request.extend_from_slice(&[protocol, 0, 0, 0]);
+        request.extend_from_slice(&internal_port.to_be_bytes());
+        request.extend_from_slice(&external_port.to_be_bytes());
+        request.extend_from_slice(&suggested_address.octets());
+
+        let response = self.request(&request, Some(nonce))?;
+
+        if response[36] != protocol || response[40..42] != internal_port.to_be_bytes() {
+            return Err(Error::InvalidResponse);
+        }
+
+        let mut external_address = [0u8; 16];
+        external_address.copy_from_slice(&response[44..60]);
+        let external_address = Ipv6Addr::from(external_address);
+
+        Ok(Mapping {
+            internal_port,
+            external_address: match external_address.to_ipv4_mapped() {
+                Some(addr) => IpAddr::V4(addr),
+                None => IpAddr::V6(external_address),
+            },
+            external_port: u16::from_be_bytes([response[42], response[43]]),
+            lifetime: u32::from_be_bytes([response[4], response[5], response[6], response[7]]),
+            epoch: u32::from_be_bytes([response[8], response[9], response[10], response[11]]),
+        })
+    }
+
+    fn request(&self, request: &[u8], nonce: Option<&Nonce>) -> Result<Vec<u8>, Error> {
+        let opcode = request[1];
+        let len = if nonce.is_some() { MAP_LEN } else { HEADER_LEN };
+
+        let mut timeout = INITIAL_TIMEOUT;
+
+        for _ in 0..self.attempts {
+            debug!("Send PCP request to {:?}", self.socket.peer_addr());
+            self.socket.send(request)?;
+            self.socket.set_read_timeout(Some(timeout))?;
+
+            loop {
+                let mut buf = [0u8; 1100];
+                let read = match self.socket.recv(&mut buf) {
+                    Ok(r) => r,
+                    Err(e) => match Error::from(e) {
+                        Error::Timeout => break,
+                        e => return Err(e),
+                    },
+                };
+
+                if read < 4 {
+                    continue;
+                }
+
+                // A NAT-PMP only server answers with its own version and an error code.
+                if buf[0] != VERSION {
+                    return Err(Error::UnsupportedVersion);
+                }
+
+                // Responses for other requests are silently discarded.
+                if read < len
+                    || buf[1] != OP_RESPONSE | opcode
+                    || nonce.map_or(false, |nonce| buf[24..36] != nonce[..])
+                {
+                    continue;
+                }
+
+                match buf[3] {
+                    0 => {}
+                    1 => return Err(Error::UnsupportedVersion),
+                    2 => return Err(Error::NotAuthorized),
+                    3 => return Err(Error::MalformedRequest),
+                    4 => return Err(Error::UnsupportedOpcode),
+                    5 => return Err(Error::UnsupportedOption),
+                    6 => return Err(Error::MalformedOption),
+                    7 => return Err(Error::NetworkFailure),
+                    8 => return Err(Error::NoResources),
+                    9 => return Err(Error::UnsupportedProtocol),
+                    10 => return Err(Error::UserExceededQuota),
+                    11 => return Err(Error::CannotProvideExternal),
+                    12 => return Err(Error::AddressMismatch),
+                    13 => return Err(Error::ExcessiveRemotePeers),
+                    code => return Err(Error::Unknown(code)),
+                }
+
+                return Ok(buf[..len].to_vec());
+            }
+
+            timeout *= 2;
+        }
+
+        Err(Error::Timeout)
+    }
+}
+
+fn to_ipv6(addr: IpAddr) -> Ipv6Addr {
+    match addr {
+        IpAddr::V4(addr) => addr.to_ipv6_mapped(),
+        IpAddr::V6(addr) => addr,
+    }
+}
+
+/// Keeps track of the nonces of mappings.
+///
+/// A new random nonce is generated the first time a mapping is seen, after that, the same nonce
+/// is returned, so that leases can be renewed and mappings deleted.
+///
+/// Without a file, the nonces are lost when the process ends. Mappings of an earlier run can then
+/// neither be renewed nor deleted until they expire, the server answers `NOT_AUTHORIZED`.
+#[derive(Debug, Default)]
+pub struct Nonces {
+    nonces: BTreeMap<(PortMappingProtocol, SocketAddr), Nonce>,
+    /// Where the nonces are kept between runs.
+    file: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Entry {
+    protocol: PortMappingProtocol,
+    internal: SocketAddr,
+    nonce: Nonce,
+}
+
+impl Nonces {
+    /// Keep the nonces in `file`, starting with those of an earlier run.
+    pub fn load(file: PathBuf) -> Self {
+        let entries = match state::read(&file) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!("Ignoring {}: {}", file.display(), e);
+                Vec::new()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                warn!("Cannot read {}: {}", file.display(), e);
+                Vec::new()
+            }
+        };
+
+        Nonces {
+            nonces: entries
+                .into_iter()
+                .map(|entry: Entry| ((entry.protocol, entry.internal), entry.nonce))
+                .collect(),
+            file: Some(file),
+        }
+    }
+
+    pub fn get(&mut self, protocol: PortMappingProtocol, internal: SocketAddr) -> Nonce {
+        if let Some(nonce) = self.nonces.get(&(protocol, internal)) {
+            return *nonce;
+        }
+
+        let nonce = rand::random();
+        self.nonces.insert((protocol, internal), nonce);
+        if let Err(e) = self.save() {
+            warn!("Cannot save the PCP nonces: {}", e);
+        }
+        nonce
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+
+        let entries: Vec<_> = self
+            .nonces
+            .iter()
+            .map(|(&(protocol, internal), &nonce)| Entry {
+                protocol,
+                internal,
+                nonce,
+            })
+            .collect();
+        // The nonces authorize changes of the mappings, so only the owner may read them.
+        let content = serde_json::to_string_pretty(&entries)?;
+        state::write(file, &(content + "\n"))
+    }
+}
+
+/// Determine the default gateway for the given address family from the kernel routing table.
+pub fn default_gateway(ipv6: bool) -> io::Result<SocketAddr> {
+    if !ipv6 {
+        return natpmp::default_gateway().map(|gateway| SocketAddr::new(gateway.into(), PORT));
+    }
+
+    let routes = fs::read_to_string("/proc/net/ipv6_route")?;
+
+    routes
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let is_zero = |field: &str| field.chars().all(|c| c == '0');
+            if fields.len() < 10 || !is_zero(fields[0]) || !is_zero(fields[1]) || is_zero(fields[4])
+            {
+                return None;
+            }
+
+            let gateway = u128::from_str_radix(fields[4], 16).ok()?;
+            let gateway = Ipv6Addr::from(gateway);
+
+            // Link local gateways need the interface they are reachable on.
+            let scope_id = fs::read_to_string(format!("/sys/class/net/{}/ifindex", fields[9]))
+                .ok()
+                .and_then(|index| index.trim().parse().ok())
+                .unwrap_or(0);
+
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                gateway, PORT, 0, scope_id,
+            )))
+        })
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No default gateway found"))
+}
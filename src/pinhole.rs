@@ -0,0 +1,221 @@
+//! IPv6 firewall pinholes via the `WANIPv6FirewallControl` service of UPnP IGDv2.
+//!
+//! IPv6 hosts do not need NAT, but the router's firewall still blocks inbound traffic. IGDv2
+//! routers offer a separate service to open holes into that firewall, which are identified by a
+//! unique ID that is needed to refresh or delete them later.
+
+use std::collections::BTreeMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::net::SocketAddrV6;
+
+use igd::Gateway;
+use log::debug;
+use xmltree::Element;
+
+use crate::discovery::{find, find_control_url};
+use crate::soap;
+use crate::PortMappingProtocol;
+
+pub const SERVICE_TYPE: &str = "urn:schemas-upnp-org:service:WANIPv6FirewallControl:1";
+
+/// The maximum lease time the service accepts, also used if the configured duration is 0.
+pub const MAX_LEASE_TIME: u32 = 86400;
+
+/// UPnP error code for an unknown pinhole ID, for example after a router reboot.
+const NO_SUCH_ENTRY: u16 = 704;
+
+#[derive(Debug)]
+pub enum Error {
+    Http(attohttpc::Error),
+    Xml(xmltree::ParseError),
+    /// The gateway does not offer the `WANIPv6FirewallControl` service.
+    ServiceNotFound,
+    InvalidResponse,
+    /// The gateway refused the request with the given UPnP error code.
+    Upnp(u16, String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Http(e) => write!(f, "HTTP error: {}", e),
+            Error::Xml(e) => write!(f, "XML error: {}", e),
+            Error::ServiceNotFound => write!(f, "Gateway does not support IPv6 firewall control"),
+            Error::InvalidResponse => write!(f, "Gateway sent an invalid response"),
+            Error::Upnp(code, description) => {
+                write!(f, "Gateway returned error {}: {}", code, description)
+            }
+        }
+    }
+}
+
+impl StdError for Error {}
+
+impl From<attohttpc::Error> for Error {
+    fn from(e: attohttpc::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+impl From<xmltree::ParseError> for Error {
+    fn from(e: xmltree::ParseError) -> Self {
+        Error::Xml(e)
+    }
+}
+
+impl From<soap::Error> for Error {
+    fn from(e: soap::Error) -> Self {
+        match e {
+            soap::Error::Http(e) => Error::Http(e),
+            soap::Error::Xml(e) => Error::Xml(e),
+            soap::Error::InvalidResponse => Error::InvalidResponse,
+            soap::Error::Upnp(code, description) => Error::Upnp(code, description),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Firewall {
+    control_url: String,
+}
+
+impl Firewall {
+    /// Look up the firewall control service in the device description of `gateway`.
+    pub fn from_gateway(gateway: &Gateway) -> Result<Self, Error> {
+        let base = format!("http://{}", gateway.addr);
+        let response = attohttpc::get(format!("{}{}", base, gateway.root_url)).send()?;
+        let description = Element::parse(&response.bytes()?[..])?;
+
+        let control_url =
+            find_control_url(&description, SERVICE_TYPE).ok_or(Error::ServiceNotFound)?;
+        let control_url = if control_url.starts_with("http://") {
+            control_url
+        } else if control_url.starts_with('/') {
+            format!("{}{}", base, control_url)
+        } else {
+            format!("{}/{}", base, control_url)
+        };
+
+        Ok(Firewall { control_url })
+    }
+
+    pub fn add_pinhole(
+        &self,
+        protocol: PortMappingProtocol,
+        internal: SocketAddrV6,
+        lease_time: u32,
+    ) -> Result<u16, Error> {
+        let response = self.call(
+            "AddPinhole",
+            &[
+                ("RemoteHost", String::new()),
+                ("RemotePort", "0".to_string()),
+                ("InternalClient", internal.ip().to_string()),
+                ("InternalPort", internal.port().to_string()),
+                ("Protocol", protocol_number(protocol).to_string()),
+                ("LeaseTime", lease_time.to_string()),
+            ],
+        )?;
+
+        find(&response, "UniqueID")
+            .and_then(|id| id.get_text())
+            .and_then(|id| id.trim().parse().ok())
+            .ok_or(Error::InvalidResponse)
+    }
+
+    pub fn update_pinhole(&self, id: u16, lease_time: u32) -> Result<(), Error> {
+        self.call(
+            "UpdatePinhole",
+            &[
+                ("UniqueID", id.to_string()),
+                ("NewLeaseTime", lease_time.to_string()),
+            ],
+        )
+        .map(|_| ())
+    }
+
+    pub fn delete_pinhole(&self, id: u16) -> Result<(), Error> {
+        self.call("DeletePinhole", &[("UniqueID", id.to_string())])
+            .map(|_| ())
+    }
+
+    fn call(&self, action: &str, arguments: &[(&str, String)]) -> Result<Element, Error> {
+        Ok(soap::call(
+            &self.control_url,
+            SERVICE_TYPE,
+            action,
+            arguments,
+        )?)
+    }
+}
+
+fn protocol_number(protocol: PortMappingProtocol) -> u8 {
+    match protocol {
+        PortMappingProtocol::TCP => 6,
+        PortMappingProtocol::UDP => 17,
+    }
+}
+
+/// Keeps track of the IDs of the pinholes opened by this process.
+#[derive(Debug, Default)]
+pub struct Pinholes {
+    ids: BTreeMap<(PortMappingProtocol, SocketAddrV6), u16>,
+}
+
+impl Pinholes {
+    /// Open a pinhole for `internal`, or refresh it if it was opened before.
+    ///
+    /// If the gateway forgot about a known pinhole, it is opened again. Returns the pinhole ID.
+    pub fn open(
+        &mut self,
+        firewall: &Firewall,
+        protocol: PortMappingProtocol,
+        internal: SocketAddrV6,
+        lease_time: u32,
+    ) -> Result<u16, Error> {
+        if let Some(&id) = self.ids.get(&(protocol, internal)) {
+            match firewall.update_pinhole(id, lease_time) {
+                Ok(()) => return Ok(id),
+                Err(Error::Upnp(NO_SUCH_ENTRY, _)) => {
+                    debug!("Pinhole {} vanished, open it again.", id);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let id = firewall.add_pinhole(protocol, internal, lease_time)?;
+        self.ids.insert((protocol, internal), id);
+
+        Ok(id)
+    }
+
+    /// Delete the pinhole for `internal`, if it was opened before.
+    pub fn close(
+        &mut self,
+        firewall: &Firewall,
+        protocol: PortMappingProtocol,
+        internal: SocketAddrV6,
+    ) -> Result<(), Error> {
+        let id = match self.ids.get(&(protocol, internal)) {
+            Some(&id) => id,
+            None => return Ok(()),
+        };
+
+        // Keep the ID if the deletion failed, so that it can be retried.
+        match firewall.delete_pinhole(id) {
+            Ok(()) | Err(Error::Upnp(NO_SUCH_ENTRY, _)) => {
+                self.ids.remove(&(protocol, internal));
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The pinholes that are currently known, with their IDs.
+    pub fn iter(&self) -> impl Iterator<Item = (PortMappingProtocol, SocketAddrV6, u16)> + '_ {
+        self.ids
+            .iter()
+            .map(|(&(protocol, internal), &id)| (protocol, internal, id))
+    }
+}
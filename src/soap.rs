@@ -0,0 +1,120 @@
+//! Minimal SOAP client for UPnP actions that `igd` does not offer.
+
+use std::error::Error as StdError;
+use std::fmt;
+
+use log::debug;
+use xmltree::Element;
+
+use crate::discovery::find;
+use crate::metrics;
+
+#[derive(Debug)]
+pub enum Error {
+    Http(attohttpc::Error),
+    Xml(xmltree::ParseError),
+    InvalidResponse,
+    /// The gateway refused the request with the given UPnP error code.
+    Upnp(u16, String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Http(e) => write!(f, "HTTP error: {}", e),
+            Error::Xml(e) => write!(f, "XML error: {}", e),
+            Error::InvalidResponse => write!(f, "Gateway sent an invalid response"),
+            Error::Upnp(code, description) => {
+                write!(f, "Gateway returned error {}: {}", code, description)
+            }
+        }
+    }
+}
+
+impl StdError for Error {}
+
+impl From<attohttpc::Error> for Error {
+    fn from(e: attohttpc::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+impl From<xmltree::ParseError> for Error {
+    fn from(e: xmltree::ParseError) -> Self {
+        Error::Xml(e)
+    }
+}
+
+/// Call `action` of the service with the given type at `control_url` and return the response
+/// element.
+pub fn call(
+    control_url: &str,
+    service_type: &str,
+    action: &str,
+    arguments: &[(&str, String)],
+) -> Result<Element, Error> {
+    metrics::soap_call(action, || {
+        request(control_url, service_type, action, arguments)
+    })
+}
+
+fn request(
+    control_url: &str,
+    service_type: &str,
+    action: &str,
+    arguments: &[(&str, String)],
+) -> Result<Element, Error> {
+    let arguments: String = arguments
+        .iter()
+        .map(|(name, value)| format!("<{0}>{1}</{0}>", name, escape(value)))
+        .collect();
+
+    let body = format!(
+        r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
+<s:Body><u:{0} xmlns:u="{1}">{2}</u:{0}></s:Body>
+</s:Envelope>"#,
+        action, service_type, arguments
+    );
+
+    debug!("Call {} on {}", action, control_url);
+
+    let response = attohttpc::post(control_url)
+        .header("SOAPAction", format!("\"{}#{}\"", service_type, action))
+        .header("Content-Type", "text/xml")
+        .text(body)
+        .send()?;
+    let envelope = Element::parse(&response.bytes()?[..])?;
+
+    if let Some(fault) = find(&envelope, "UPnPError") {
+        let code = find(fault, "errorCode")
+            .and_then(|code| code.get_text())
+            .and_then(|code| code.trim().parse().ok())
+            .ok_or(Error::InvalidResponse)?;
+        let description = find(fault, "errorDescription")
+            .and_then(|description| description.get_text())
+            .unwrap_or_default()
+            .into_owned();
+        return Err(Error::Upnp(code, description));
+    }
+
+    find(&envelope, &format!("{}Response", action))
+        .cloned()
+        .ok_or(Error::InvalidResponse)
+}
+
+/// Escape the characters of `value` that have a meaning in XML text.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,108 @@
+//! Files that the daemon keeps between runs.
+//!
+//! They decide which mappings the daemon may change, so nobody else may plant them: they are
+//! written to a fresh file that is renamed into place, and only read if they belong to the
+//! current user.
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use clap::crate_name;
+use log::warn;
+
+/// The directory for the state of the daemon, `$XDG_STATE_HOME/upnp-daemon` or
+/// `~/.local/state/upnp-daemon`, which only the current user may access.
+pub fn dir() -> io::Result<PathBuf> {
+    let base = match env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => match env::var_os("HOME").filter(|dir| !dir.is_empty()) {
+            Some(home) => Path::new(&home).join(".local/state"),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "Neither XDG_STATE_HOME nor HOME is set",
+                ))
+            }
+        },
+    };
+
+    let dir = base.join(crate_name!());
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)?;
+    let metadata = fs::symlink_metadata(&dir)?;
+    if !metadata.is_dir() || metadata.uid() != euid() || metadata.mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is not private to the current user", dir.display()),
+        ));
+    }
+    Ok(dir)
+}
+
+/// The file `name` in the state directory, if there is one.
+pub fn file(name: &str) -> Option<PathBuf> {
+    match dir() {
+        Ok(dir) => Some(dir.join(name)),
+        Err(e) => {
+            warn!("Cannot use the state directory: {}", e);
+            None
+        }
+    }
+}
+
+/// Read `file`, but only if it is a regular file of the current user. A missing file is
+/// reported with `NotFound`.
+pub fn read(file: &Path) -> io::Result<String> {
+    let mut input = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(file)?;
+    let metadata = input.metadata()?;
+    if !metadata.is_file() || metadata.uid() != euid() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "not a file of the current user",
+        ));
+    }
+
+    let mut content = String::new();
+    input.read_to_string(&mut content)?;
+    Ok(content)
+}
+
+/// Replace `file` with `content`, readable only by the current user.
+pub fn write(file: &Path, content: &str) -> io::Result<()> {
+    let name = file
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file name"))?;
+    let mut temporary = file.to_path_buf();
+    temporary.set_file_name(format!(
+        ".{}.{:016x}",
+        name.to_string_lossy(),
+        rand::random::<u64>()
+    ));
+
+    // Never follow or reuse what is already there, somebody else may have put it there.
+    let mut out = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .mode(0o600)
+        .open(&temporary)?;
+    let result = out
+        .write_all(content.as_bytes())
+        .and_then(|_| fs::rename(&temporary, file));
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result
+}
+
+fn euid() -> u32 {
+    unsafe { libc::geteuid() }
+}
@@ -0,0 +1,149 @@
+//! Watching the mapping file for changes with inotify.
+//!
+//! Editors often do not write a file in place, but write a new file and rename it over the old
+//! one, or move the old file away first. Therefore, the directory of the file is watched instead
+//! of the file itself, and every event that concerns the file's name counts as a change.
+
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::ptr;
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, warn};
+
+/// How long the file has to stay untouched before a change is reported, so that an editor that
+/// writes a file in several steps causes only one reload.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+const EVENTS: u32 = libc::IN_CLOSE_WRITE | libc::IN_MODIFY | libc::IN_CREATE | libc::IN_MOVED_TO;
+
+struct Inotify(RawFd);
+
+impl Inotify {
+    fn watch(directory: &Path) -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let inotify = Inotify(fd);
+
+        let directory = CString::new(directory.as_os_str().as_bytes())?;
+        if unsafe { libc::inotify_add_watch(fd, directory.as_ptr(), EVENTS) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(inotify)
+    }
+
+    /// Wait up to `timeout` for events, or forever if `None`. Returns whether there are any.
+    fn poll(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        let mut fds = libc::pollfd {
+            fd: self.0,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.map_or(-1, |timeout| timeout.as_millis() as libc::c_int);
+
+        match unsafe { libc::poll(&mut fds, 1, timeout) } {
+            n if n < 0 => {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    Ok(false)
+                } else {
+                    Err(e)
+                }
+            }
+            n => Ok(n > 0),
+        }
+    }
+
+    /// Read the pending events and return the names of the files they concern.
+    fn read(&self) -> io::Result<Vec<OsString>> {
+        // Aligned for the event headers, and large enough for at least one event with a name.
+        let mut buffer = [0u32; 1024];
+        let length = unsafe {
+            libc::read(
+                self.0,
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                mem::size_of_val(&buffer),
+            )
+        };
+        if length < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let bytes =
+            unsafe { std::slice::from_raw_parts(buffer.as_ptr() as *const u8, length as usize) };
+        let mut names = Vec::new();
+        let mut offset = 0;
+        while offset + mem::size_of::<libc::inotify_event>() <= bytes.len() {
+            let event: libc::inotify_event =
+                unsafe { ptr::read_unaligned(bytes[offset..].as_ptr() as *const _) };
+            offset += mem::size_of::<libc::inotify_event>();
+
+            let name = &bytes[offset..offset + event.len as usize];
+            offset += event.len as usize;
+
+            if event.len > 0 {
+                let name = unsafe { CStr::from_ptr(name.as_ptr() as *const libc::c_char) };
+                names.push(OsStr::from_bytes(name.to_bytes()).to_owned());
+            }
+        }
+
+        Ok(names)
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+/// Watch `file` in a background thread and call `changed` after it has been written. The thread
+/// stops when `changed` returns `false`.
+pub fn spawn<F>(file: &Path, changed: F) -> io::Result<()>
+where
+    F: FnMut() -> bool + Send + 'static,
+{
+    let (directory, name) = match (file.parent(), file.file_name()) {
+        (Some(directory), Some(name)) => (directory, name.to_owned()),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Cannot watch {}", file.display()),
+            ))
+        }
+    };
+    let inotify = Inotify::watch(directory)?;
+
+    thread::spawn(move || {
+        if let Err(e) = run(&inotify, &name, changed) {
+            warn!("Stopped watching the mapping file: {}", e);
+        }
+    });
+
+    Ok(())
+}
+
+fn run<F: FnMut() -> bool>(inotify: &Inotify, name: &OsStr, mut changed: F) -> io::Result<()> {
+    loop {
+        if !inotify.poll(None)? || !inotify.read()?.iter().any(|n| n == name) {
+            continue;
+        }
+
+        while inotify.poll(Some(DEBOUNCE))? {
+            inotify.read()?;
+        }
+
+        debug!("Mapping file changed");
+        if !changed() {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+use upnp_daemon::check::{check, Diagnostic};
+use upnp_daemon::config::Format;
+
+/// Write `content` to a file named after the test and return its path.
+fn file(name: &str, content: &str) -> PathBuf {
+    let file = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(format!("check_{}", name));
+    fs::write(&file, content).unwrap();
+    file
+}
+
+fn at(line: usize, column: usize, mapping: usize, message: &str) -> Diagnostic {
+    Diagnostic {
+        position: Some((line, column)),
+        mapping: Some(mapping),
+        message: message.to_string(),
+    }
+}
+
+#[test]
+fn valid_file() {
+    let file = file(
+        "valid.csv",
+        "\
+address;port;protocol;duration;comment
+192.168.0.10;8080;TCP;60;Web
+;8080;UDP;60;DNS
+fd00::10;443;TCP;60;HTTPS
+",
+    );
+
+    assert_eq!(check(&file, Format::Csv), vec![]);
+}
+
+#[test]
+fn invalid_values() {
+    let file = file(
+        "invalid_values.csv",
+        "\
+address;port;protocol;duration;comment
+192.168.0.2;80;XYZ;60;Protocol
+192.168.0.2;abc;TCP;60;Port
+192.168.0.2;81;TCP;x;Duration
+",
+    );
+
+    assert_eq!(
+        check(&file, Format::Csv),
+        vec![
+            at(
+                2,
+                16,
+                1,
+                "protocol: unknown variant `XYZ`, expected one of `TCP`, `UDP`, `BOTH`"
+            ),
+            at(3, 13, 2, "port: Invalid port range: abc"),
+            at(4, 20, 3, "duration: invalid digit found in string"),
+        ]
+    );
+}
+
+#[test]
+fn invalid_mappings() {
+    let file = file(
+        "invalid_mappings.csv",
+        "\
+address;port;protocol;duration;comment
+8.8.8.8;80;TCP;60;Public
+192.168.0.300;81;TCP;60;Invalid
+::1;82;TCP;60;Loopback
+192.168.0.2;0;TCP;60;Zero
+2001:db8::10;83;TCP;60;Other host
+fe80::10;84;TCP;60;Link-local
+",
+    );
+
+    assert_eq!(
+        check(&file, Format::Csv),
+        vec![
+            at(2, 1, 1, "8.8.8.8 is not an address of a local network"),
+            at(3, 1, 2, "192.168.0.300 is not a valid IP address"),
+            at(4, 1, 3, "::1 is not an address of a local network"),
+            at(5, 13, 4, "Port 0 cannot be mapped"),
+            at(6, 1, 5, "2001:db8::10 is not an address of a local network"),
+        ]
+    );
+}
+
+#[test]
+fn duplicate_external_ports() {
+    let file = file(
+        "duplicate_external_ports.csv",
+        "\
+address;port;external_port;internal_port;protocol;duration;comment
+192.168.0.2;80;;;TCP;60;Web
+192.168.0.3;;80;8080;BOTH;60;Other web
+192.168.0.4;;;;UDP;60;Missing
+192.168.0.5;50000-50010;;;UDP;60;Range
+192.168.0.6;;50010;5000-5001;UDP;60;Overlap
+",
+    );
+
+    assert_eq!(
+        check(&file, Format::Csv),
+        vec![
+            at(
+                3,
+                14,
+                2,
+                "External port 80 (TCP) is already used by mapping 1"
+            ),
+            at(
+                4,
+                13,
+                3,
+                "Either port or both external_port and internal_port are needed"
+            ),
+            at(
+                6,
+                20,
+                5,
+                "External ports 50010 and internal ports 5000-5001 differ in size"
+            ),
+            at(
+                6,
+                14,
+                5,
+                "External port 50010 (UDP) is already used by mapping 4"
+            ),
+        ]
+    );
+}
+
+#[test]
+fn structured_syntax_error() {
+    let file = file(
+        "syntax_error.toml",
+        "\
+[[mappings]]
+port = 80
+protocol = TCP
+",
+    );
+
+    let diagnostics = check(&file, Format::Toml);
+
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].position, Some((3, 12)));
+}
+
+#[test]
+fn structured_mappings() {
+    let file = file(
+        "mappings.yaml",
+        "\
+mappings:
+  - port: 80
+    protocol: TCP
+    duration: 60
+    comment: Web
+  - port: 80
+    protocol: TCP
+    duration: 60
+    comment: Again
+",
+    );
+
+    assert_eq!(
+        check(&file, Format::Yaml),
+        vec![Diagnostic {
+            position: None,
+            mapping: Some(2),
+            message: "External port 80 (TCP) is already used by mapping 1".to_string(),
+        }]
+    );
+}
+
+#[test]
+fn exit_code() {
+    let valid = file(
+        "exit_code_valid.csv",
+        "address;port;protocol;duration;comment\n;80;TCP;60;Web\n",
+    );
+    let invalid = file(
+        "exit_code_invalid.csv",
+        "address;port;protocol;duration;comment\n;0;TCP;60;Web\n",
+    );
+
+    let status = Command::new(env!("CARGO_BIN_EXE_upnp-daemon"))
+        .args(["check", "--file"])
+        .arg(&valid)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_upnp-daemon"))
+        .args(["check", "--file"])
+        .arg(&invalid)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!("{}:2:2: Port 0 cannot be mapped\n", invalid.display())
+    );
+}
@@ -0,0 +1,1122 @@
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+use upnp_daemon::config::read_csv;
+use upnp_daemon::control;
+use upnp_daemon::Cli;
+
+use common::{Call, Entry, FakeDns, FakeIgd};
+
+mod common;
+
+/// Write `csv` to a file named after the test and return its path.
+fn csv_file(name: &str, csv: &str) -> PathBuf {
+    let file = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(format!("{}.csv", name));
+    fs::write(&file, csv).unwrap();
+    file
+}
+
+/// Run the daemon once against `ssdp_address`.
+fn run_once(name: &str, ssdp_address: SocketAddr, csv: &str) -> Result<(), Box<dyn Error>> {
+    run_once_with(name, ssdp_address, csv, &[])
+}
+
+fn run_once_with(
+    name: &str,
+    ssdp_address: SocketAddr,
+    csv: &str,
+    options: &[&str],
+) -> Result<(), Box<dyn Error>> {
+    let file = csv_file(name, csv);
+    let ssdp_address = ssdp_address.to_string();
+
+    let mut args: Vec<&OsStr> = vec![
+        "upnp-daemon".as_ref(),
+        "--foreground".as_ref(),
+        "--oneshot".as_ref(),
+        "--file".as_ref(),
+        file.as_os_str(),
+        "--ssdp-address".as_ref(),
+        ssdp_address.as_ref(),
+        "--discovery-timeout".as_ref(),
+        "1".as_ref(),
+    ];
+    args.extend(options.iter().map(OsStr::new));
+    Cli::run_from(args)
+}
+
+fn add(port: u16, protocol: &str, internal_port: u16, comment: &str) -> Call {
+    Call::Add {
+        protocol: protocol.to_string(),
+        external_port: port,
+        internal_client: "127.0.0.1".to_string(),
+        internal_port,
+        lease_duration: 60,
+        description: comment.to_string(),
+    }
+}
+
+fn delete(port: u16, protocol: &str) -> Call {
+    Call::Delete {
+        protocol: protocol.to_string(),
+        external_port: port,
+    }
+}
+
+#[test]
+fn happy_path() {
+    let igd = FakeIgd::start(1);
+
+    run_once(
+        "happy_path",
+        igd.ssdp_address,
+        "\
+address;port;protocol;duration;comment
+127.0.0.1;8080;TCP;60;Web
+127.0.0.1;5353;UDP;60;DNS
+",
+    )
+    .unwrap();
+
+    assert_eq!(
+        igd.calls(),
+        vec![add(8080, "TCP", 8080, "Web"), add(5353, "UDP", 5353, "DNS")]
+    );
+    assert_eq!(igd.mappings().len(), 2);
+}
+
+#[test]
+fn separate_ports() {
+    let igd = FakeIgd::start(1);
+
+    run_once(
+        "separate_ports",
+        igd.ssdp_address,
+        "\
+address;external_port;internal_port;protocol;duration;comment
+127.0.0.1;443;8443;TCP;60;HTTPS
+",
+    )
+    .unwrap();
+
+    assert_eq!(igd.calls(), vec![add(443, "TCP", 8443, "HTTPS")]);
+}
+
+#[test]
+fn structured_config() {
+    let igd = FakeIgd::start(1);
+    let file = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("structured_config.conf");
+    fs::write(
+        &file,
+        "\
+daemon:
+  interval: 5
+mappings:
+  - address: 127.0.0.1
+    port: 8080
+    protocol: TCP
+    duration: 60
+    comment: Web
+",
+    )
+    .unwrap();
+
+    Cli::run_from([
+        "upnp-daemon".as_ref(),
+        "--foreground".as_ref(),
+        "--oneshot".as_ref(),
+        "--file".as_ref(),
+        file.as_os_str(),
+        "--format".as_ref(),
+        "yaml".as_ref(),
+        "--ssdp-address".as_ref(),
+        igd.ssdp_address.to_string().as_ref(),
+    ])
+    .unwrap();
+
+    assert_eq!(igd.calls(), vec![add(8080, "TCP", 8080, "Web")]);
+}
+
+#[test]
+fn igd_version_2() {
+    let igd = FakeIgd::start(2);
+
+    run_once(
+        "igd_version_2",
+        igd.ssdp_address,
+        "\
+address;port;protocol;duration;comment
+127.0.0.1;8080;TCP;60;Web
+",
+    )
+    .unwrap();
+
+    assert_eq!(igd.calls(), vec![add(8080, "TCP", 8080, "Web")]);
+}
+
+#[test]
+fn ppp_connection() {
+    let igd = FakeIgd::start_with("WANPPPConnection", 1);
+
+    run_once(
+        "ppp_connection",
+        igd.ssdp_address,
+        "\
+address;port;protocol;duration;comment
+127.0.0.1;8080;TCP;60;Web
+",
+    )
+    .unwrap();
+
+    assert_eq!(igd.calls(), vec![add(8080, "TCP", 8080, "Web")]);
+}
+
+#[test]
+fn port_in_use() {
+    let igd = FakeIgd::start(1);
+    igd.map("TCP", 8080, "127.0.0.2", 80);
+
+    run_once(
+        "port_in_use",
+        igd.ssdp_address,
+        "\
+address;port;protocol;duration;comment
+127.0.0.1;8080;TCP;60;Web
+",
+    )
+    .unwrap();
+
+    assert_eq!(
+        igd.calls(),
+        vec![
+            add(8080, "TCP", 8080, "Web"),
+            delete(8080, "TCP"),
+            add(8080, "TCP", 8080, "Web"),
+        ]
+    );
+    assert_eq!(
+        igd.mappings()[&("TCP".to_string(), 8080)],
+        Entry {
+            internal_client: "127.0.0.1".to_string(),
+            internal_port: 8080,
+            description: "Web".to_string(),
+        }
+    );
+}
+
+#[test]
+fn conflict_in_mapping_entry() {
+    let igd = FakeIgd::start(1);
+    igd.reserve(443);
+
+    let result = run_once(
+        "conflict_in_mapping_entry",
+        igd.ssdp_address,
+        "\
+address;port;protocol;duration;comment
+127.0.0.1;443;TCP;60;HTTPS
+",
+    );
+
+    assert!(result.is_err());
+    assert_eq!(
+        igd.calls(),
+        vec![add(443, "TCP", 443, "HTTPS"), delete(443, "TCP")]
+    );
+    assert!(igd.mappings().is_empty());
+}
+
+#[test]
+fn discovery_timeout() {
+    let ssdp_address = common::silent_ssdp_address();
+    let start = Instant::now();
+
+    let result = run_once(
+        "discovery_timeout",
+        ssdp_address,
+        "\
+address;port;protocol;duration;comment
+127.0.0.1;8080;TCP;60;Web
+",
+    );
+
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        "0 ports mapped, 0 skipped, 1 rows failed"
+    );
+    assert!(start.elapsed() >= Duration::from_secs(1));
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn failing_row_does_not_stop_the_others() {
+    let igd = FakeIgd::start(1);
+
+    let result = run_once(
+        "failing_row_does_not_stop_the_others",
+        igd.ssdp_address,
+        "\
+address;port;protocol;duration;comment
+nowhere;8080;TCP;60;Invalid
+127.0.0.1;8080;TCP;60;Web
+",
+    );
+
+    assert!(result.is_err());
+    assert_eq!(igd.mappings().len(), 1);
+}
+
+#[test]
+fn fail_fast() {
+    let ssdp_address = common::silent_ssdp_address();
+
+    let result = run_once_with(
+        "fail_fast",
+        ssdp_address,
+        "\
+address;port;protocol;duration;comment
+127.0.0.1;8080;TCP;60;Web
+",
+        &["--fail-fast"],
+    );
+
+    assert_eq!(result.unwrap_err().to_string(), "No UPnP gateway answered");
+}
+
+#[test]
+fn fail_fast_cleans_up() {
+    let igd = FakeIgd::start(1);
+    igd.map("TCP", 9090, "127.0.0.2", 9090);
+    let socket = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("fail_fast_cleans_up.sock");
+
+    let mut daemon = spawn(
+        "fail_fast_cleans_up",
+        igd.ssdp_address,
+        "\
+address;port;protocol;duration;comment
+127.0.0.1;8080;TCP;0;Web
+127.0.0.1;9090;TCP;0;Taken
+",
+        &[
+            "--fail-fast",
+            "--conflict",
+            "fail",
+            "--control-socket",
+            socket.to_str().unwrap(),
+        ],
+    );
+
+    assert!(!daemon.wait().unwrap().success());
+    assert_eq!(igd.calls().last(), Some(&delete(8080, "TCP")));
+    assert_eq!(igd.mappings().len(), 1);
+    assert!(!socket.exists());
+}
+
+/// Start the daemon as a separate process in the foreground, so it can be sent signals.
+fn spawn(name: &str, ssdp_address: SocketAddr, csv: &str, extra: &[&str]) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_upnp-daemon"))
+        .arg("--foreground")
+        .arg("--file")
+        .arg(csv_file(name, csv))
+        .args(["--ssdp-address", &ssdp_address.to_string()])
+        .args(["--discovery-timeout", "1"])
+        .args(extra)
+        .spawn()
+        .unwrap()
+}
+
+fn wait_until(condition: impl Fn() -> bool) {
+    let start = Instant::now();
+    while !condition() {
+        assert!(start.elapsed() < Duration::from_secs(10), "Timed out");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn signal(daemon: &Child, signal: &str) {
+    let status = Command::new("kill")
+        .args([&format!("-{}", signal), &daemon.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+fn terminate(mut daemon: Child) {
+    signal(&daemon, "TERM");
+    assert!(daemon.wait().unwrap().success());
+}
+
+#[test]
+fn shutdown_removes_mappings() {
+    let igd = FakeIgd::start(1);
+    igd.map("UDP", 27015, "127.0.0.2", 27015);
+
+    let daemon = spawn(
+        "shutdown_removes_mappings",
+        igd.ssdp_address,
+        "\
+address;port;protocol;duration;comment
+127.0.0.1;8080;TCP;0;Web
+",
+        &[],
+    );
+    wait_until(|| igd.mappings().len() == 2);
+    terminate(daemon);
+
+    assert_eq!(igd.calls().last(), Some(&delete(8080, "TCP")));
+    assert_eq!(
+        igd.mappings().keys().collect::<Vec<_>>(),
+        vec![&("UDP".to_string(), 27015)]
+    );
+}
+
+#[test]
+fn shutdown_keeps_mappings() {
+    let igd = FakeIgd::start(1);
+
+    let daemon = spawn(
+        "shutdown_keeps_mappings",
+        igd.ssdp_address,
+        "\
+address;port;protocol;duration;comment
+127.0.0.1;8080;TCP;0;Web
+",
+        &["--keep-mappings"],
+    );
+    wait_until(|| igd.mappings().len() == 1);
+    terminate(daemon);
+
+    assert!(igd
+        .calls()
+        .iter()
+        .all(|call| matches!(call, Call::Add { .. })));
+    assert_eq!(igd.mappings().len(), 1);
+}
+
+fn delete_range(start: u16, end: u16, protocol: &str) -> Call {
+    Call::DeleteRange {
+        protocol: protocol.to_string(),
+        start,
+        end,
+        manage: false,
+    }
+}
+
+const RANGE: &str = "\
+address;port;protocol;duration;comment
+127.0.0.1;50000-50002;UDP;0;Media
+";
+
+#[test]
+fn remove_range_at_once() {
+    let igd = FakeIgd::start(2);
+
+    let daemon = spawn("remove_range_at_once", igd.ssdp_address, RANGE, &[]);
+    wait_until(|| igd.mappings().len() == 3);
+    terminate(daemon);
+
+    assert_eq!(igd.calls().last(), Some(&delete_range(50000, 50002, "UDP")));
+    assert!(igd.mappings().is_empty());
+}
+
+#[test]
+fn remove_range_per_port() {
+    let igd = FakeIgd::start(1);
+
+    let daemon = spawn("remove_range_per_port", igd.ssdp_address, RANGE, &[]);
+    wait_until(|| igd.mappings().len() == 3);
+    terminate(daemon);
+
+    assert_eq!(
+        igd.calls()[igd.calls().len() - 3..],
+        [
+            delete(50000, "UDP"),
+            delete(50001, "UDP"),
+            delete(50002, "UDP")
+        ]
+    );
+    assert!(igd.mappings().is_empty());
+}
+
+const WEB: &str = "\
+address;port;protocol;duration;comment
+127.0.0.1;8080;TCP;0;Web
+";
+
+const WEB_AND_DNS: &str = "\
+address;port;protocol;duration;comment
+127.0.0.1;8080;TCP;0;Web
+127.0.0.1;5353;UDP;0;DNS
+";
+
+#[test]
+fn reload_on_change() {
+    let igd = FakeIgd::start(1);
+
+    let daemon = spawn(
+        "reload_on_change",
+        igd.ssdp_address,
+        WEB,
+        &["--interval", "3600"],
+    );
+    wait_until(|| igd.mappings().len() == 1);
+
+    csv_file("reload_on_change", WEB_AND_DNS);
+    wait_until(|| igd.mappings().len() == 2);
+    terminate(daemon);
+}
+
+#[test]
+fn reload_on_rename() {
+    let igd = FakeIgd::start(1);
+
+    let daemon = spawn(
+        "reload_on_rename",
+        igd.ssdp_address,
+        WEB,
+        &["--interval", "3600"],
+    );
+    wait_until(|| igd.mappings().len() == 1);
+
+    // Like editors that write a new file and replace the old one with it.
+    let new = csv_file("reload_on_rename.new", WEB_AND_DNS);
+    fs::rename(&new, new.with_file_name("reload_on_rename.csv")).unwrap();
+    wait_until(|| igd.mappings().len() == 2);
+    terminate(daemon);
+
+    // Writing and renaming the new file is debounced into a single reload.
+    assert_eq!(
+        igd.calls()
+            .iter()
+            .filter(|call| matches!(
+                call,
+                Call::Add {
+                    external_port: 8080,
+                    ..
+                }
+            ))
+            .count(),
+        2
+    );
+}
+
+fn adds(igd: &FakeIgd) -> usize {
+    igd.calls()
+        .iter()
+        .filter(|call| matches!(call, Call::Add { .. }))
+        .count()
+}
+
+fn daemon_config(interval: u64, log_level: &str) -> String {
+    format!(
+        "\
+daemon:
+  interval: {}
+  log_level: {}
+mappings:
+  - address: 127.0.0.1
+    port: 8080
+    protocol: TCP
+    duration: 0
+    comment: Web
+",
+        interval, log_level
+    )
+}
+
+#[test]
+fn signals() {
+    let igd = FakeIgd::start(1);
+    let file = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("signals.yaml");
+    fs::write(&file, daemon_config(3600, "warn")).unwrap();
+
+    let daemon = Command::new(env!("CARGO_BIN_EXE_upnp-daemon"))
+        .arg("--foreground")
+        .arg("--file")
+        .arg(&file)
+        .args(["--ssdp-address", &igd.ssdp_address.to_string()])
+        .env_remove("RUST_LOG")
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    wait_until(|| adds(&igd) == 1);
+
+    signal(&daemon, "USR1");
+    wait_until(|| adds(&igd) == 2);
+
+    // Not logged with the initial log level.
+    signal(&daemon, "USR2");
+
+    // Writing the file refreshes the mappings, but the new settings need a reload.
+    fs::write(&file, daemon_config(1, "info")).unwrap();
+    wait_until(|| adds(&igd) == 3);
+    signal(&daemon, "HUP");
+    wait_until(|| adds(&igd) >= 6);
+
+    signal(&daemon, "USR2");
+    thread::sleep(Duration::from_millis(200));
+    signal(&daemon, "TERM");
+    let output = daemon.wait_with_output().unwrap();
+    assert!(output.status.success());
+
+    let log = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(log.matches("Owned mappings: 1").count(), 1);
+    assert!(log.contains("8080 (TCP) to 127.0.0.1:8080 via Upnp, duration 0, comment \"Web\""));
+}
+
+/// Run the `list` subcommand against `igd` and return what it printed.
+fn list(igd: &FakeIgd, output: &str) -> String {
+    let result = Command::new(env!("CARGO_BIN_EXE_upnp-daemon"))
+        .args(["list", "--address", "127.0.0.1", "--output", output])
+        .args(["--ssdp-address", &igd.ssdp_address.to_string()])
+        .args(["--discovery-timeout", "1"])
+        .output()
+        .unwrap();
+    assert!(result.status.success());
+    String::from_utf8(result.stdout).unwrap()
+}
+
+#[test]
+fn list_mappings() {
+    let igd = FakeIgd::start(2);
+    igd.map("TCP", 8080, "127.0.0.1", 80);
+    igd.map("UDP", 5353, "127.0.0.1", 5353);
+    igd.set_lease("UDP", 5353, 1800);
+
+    let table = list(&igd, "table");
+    let lines: Vec<_> = table.lines().collect();
+    assert!(lines[0].starts_with("Gateway 127.0.0.1 "));
+    assert_eq!(lines.len(), 4);
+    assert!(lines[2].contains("8080  TCP"));
+    assert!(lines[2].contains("127.0.0.1:80"));
+    assert!(lines[2].ends_with("permanent  yes      Someone else"));
+
+    let json: serde_json::Value = serde_json::from_str(&list(&igd, "json")).unwrap();
+    assert_eq!(json.as_array().unwrap().len(), 2);
+    assert_eq!(json[1]["external_port"], 5353);
+    assert_eq!(json[1]["protocol"], "UDP");
+    assert_eq!(json[1]["enabled"], true);
+    assert_eq!(json[1]["lease_duration"], 1800);
+
+    let csv = list(&igd, "csv");
+    assert_eq!(
+        csv,
+        "\
+address;external_port;internal_port;protocol;duration;comment
+127.0.0.1;8080;80;TCP;0;Someone else
+127.0.0.1;5353;5353;UDP;0;Someone else
+"
+    );
+    // The rows are valid rows of the mapping file, without the remaining lease as duration.
+    assert_eq!(read_csv(csv.as_bytes()).unwrap().len(), 2);
+}
+
+#[test]
+fn subcommands_log() {
+    let igd = FakeIgd::start(1);
+    let output = Command::new(env!("CARGO_BIN_EXE_upnp-daemon"))
+        .env("RUST_LOG", "debug")
+        .args(["list", "--address", "127.0.0.1"])
+        .args(["--ssdp-address", &igd.ssdp_address.to_string()])
+        .args(["--discovery-timeout", "1"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let log = String::from_utf8(output.stderr).unwrap();
+    assert!(log.contains("Call GetGenericPortMappingEntry on "));
+}
+
+/// Run a subcommand that talks to `igd`, with the given arguments.
+fn subcommand(igd: &FakeIgd, name: &str, args: &[&str]) -> Result<(), Box<dyn Error>> {
+    let ssdp_address = igd.ssdp_address.to_string();
+    let mut command = vec![
+        "upnp-daemon",
+        name,
+        "--address",
+        "127.0.0.1",
+        "--ssdp-address",
+        &ssdp_address,
+        "--discovery-timeout",
+        "1",
+    ];
+    command.extend(args);
+    Cli::run_from(command)
+}
+
+#[test]
+fn add_and_remove_by_port() {
+    let igd = FakeIgd::start(1);
+
+    let args = ["--port", "8080", "--protocol", "tcp", "--comment", "Test"];
+    subcommand(&igd, "add", &args).unwrap();
+    assert_eq!(igd.mappings().len(), 1);
+    assert!(igd.calls().contains(&Call::Add {
+        protocol: "TCP".to_string(),
+        external_port: 8080,
+        internal_client: "127.0.0.1".to_string(),
+        internal_port: 8080,
+        lease_duration: 3600,
+        description: "Test".to_string(),
+    }));
+
+    subcommand(&igd, "remove", &["--port", "8080", "--protocol", "TCP"]).unwrap();
+    assert!(igd.mappings().is_empty());
+}
+
+#[test]
+fn added_file_is_private() {
+    let igd = FakeIgd::start(1);
+    let state = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("added_file_is_private");
+    let _ = fs::remove_dir_all(&state);
+    let dir = state.join("upnp-daemon");
+    fs::create_dir_all(&dir).unwrap();
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)).unwrap();
+
+    // Somebody planted a link to another file.
+    let victim = state.join("victim");
+    fs::write(&victim, "untouched").unwrap();
+    std::os::unix::fs::symlink(&victim, dir.join("added.json")).unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_upnp-daemon"))
+        .env("XDG_STATE_HOME", &state)
+        .args([
+            "add",
+            "--address",
+            "127.0.0.1",
+            "--port",
+            "8080",
+            "--protocol",
+            "TCP",
+        ])
+        .args(["--ssdp-address", &igd.ssdp_address.to_string()])
+        .args(["--discovery-timeout", "1"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    assert_eq!(fs::read_to_string(&victim).unwrap(), "untouched");
+    let metadata = fs::symlink_metadata(dir.join("added.json")).unwrap();
+    assert!(metadata.is_file());
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+}
+
+#[test]
+fn remove_by_comment() {
+    let igd = FakeIgd::start(1);
+    igd.map("UDP", 5353, "127.0.0.1", 5353);
+
+    let args = [
+        "--external-port",
+        "50000-50001",
+        "--internal-port",
+        "60000-60001",
+        "--protocol",
+        "BOTH",
+        "--comment",
+        "Temporary",
+    ];
+    subcommand(&igd, "add", &args).unwrap();
+    assert_eq!(igd.mappings().len(), 5);
+
+    subcommand(&igd, "remove", &["--comment", "Temporary"]).unwrap();
+    assert_eq!(igd.mappings().len(), 1);
+
+    // Nothing is left to remove.
+    assert!(subcommand(&igd, "remove", &["--comment", "Temporary"]).is_err());
+}
+
+/// Run the `control` subcommand against the daemon listening on `socket`.
+fn control(socket: &Path, args: &[&str]) -> Result<(), Box<dyn Error>> {
+    let mut command = vec![
+        "upnp-daemon".as_ref(),
+        "control".as_ref(),
+        "--socket".as_ref(),
+    ];
+    command.push(socket.as_os_str());
+    command.extend(args.iter().map(OsStr::new));
+    Cli::run_from(command)
+}
+
+#[test]
+fn control_socket() {
+    let igd = FakeIgd::start(1);
+    igd.map("TCP", 9090, "127.0.0.2", 9090);
+    let socket = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("control_socket.sock");
+
+    let mut daemon = spawn(
+        "control_socket",
+        igd.ssdp_address,
+        "\
+address;port;protocol;duration;comment
+127.0.0.1;8080;TCP;0;Web
+127.0.0.1;9090;TCP;0;Taken
+",
+        &[
+            "--conflict",
+            "fail",
+            "--control-socket",
+            socket.to_str().unwrap(),
+            "--control-socket-mode",
+            "660",
+        ],
+    );
+    wait_until(|| socket.exists() && adds(&igd) == 2);
+    assert_eq!(
+        fs::metadata(&socket).unwrap().permissions().mode() & 0o777,
+        0o660
+    );
+
+    // A client that does not send anything does not hold up the others.
+    let _idle = UnixStream::connect(&socket).unwrap();
+    let status = control::send(&socket, &json!({ "command": "status" })).unwrap();
+    assert_eq!(status.error, None);
+    assert_eq!(status.mappings.len(), 2);
+    assert_eq!(status.mappings[0].row, Some(1));
+    assert_eq!(status.mappings[0].error, None);
+    assert_eq!(status.mappings[1].ports, "9090");
+    assert!(status.mappings[1].error.is_some());
+
+    let temporary = [
+        "open",
+        "--address",
+        "127.0.0.1",
+        "--port",
+        "9000",
+        "--protocol",
+        "udp",
+        "--duration",
+        "1",
+    ];
+    control(&socket, &temporary).unwrap();
+    assert!(igd.mappings().contains_key(&("UDP".to_string(), 9000)));
+    let status = control::send(&socket, &json!({ "command": "status" })).unwrap();
+    assert_eq!(status.mappings.len(), 3);
+    assert_eq!(status.mappings[2].row, None);
+    assert!(status.mappings[2].expires.is_some());
+
+    // Removed once its duration is over.
+    wait_until(|| !igd.mappings().contains_key(&("UDP".to_string(), 9000)));
+
+    let permanent = [
+        "open",
+        "--address",
+        "127.0.0.1",
+        "--port",
+        "9001",
+        "--protocol",
+        "tcp",
+        "--duration",
+        "0",
+    ];
+    control(&socket, &permanent).unwrap();
+    assert!(igd.mappings().contains_key(&("TCP".to_string(), 9001)));
+    let close = [
+        "close",
+        "--address",
+        "127.0.0.1",
+        "--port",
+        "9001",
+        "--protocol",
+        "TCP",
+    ];
+    control(&socket, &close).unwrap();
+    assert!(!igd.mappings().contains_key(&("TCP".to_string(), 9001)));
+
+    let before = adds(&igd);
+    control(&socket, &["refresh"]).unwrap();
+    assert!(adds(&igd) > before);
+
+    let response = control::send(&socket, &json!({ "command": "unknown" })).unwrap();
+    assert!(response.error.unwrap().starts_with("Invalid request"));
+
+    control(&socket, &["shutdown"]).unwrap();
+    assert!(daemon.wait().unwrap().success());
+    assert!(!socket.exists());
+    assert_eq!(
+        igd.mappings().keys().collect::<Vec<_>>(),
+        vec![&("TCP".to_string(), 9090)]
+    );
+}
+
+/// Fetch the metrics of a daemon, with the HTTP status line and headers.
+#[test]
+fn close_one_protocol() {
+    let igd = FakeIgd::start(1);
+    let socket = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("close_one_protocol.sock");
+    let mut daemon = spawn(
+        "close_one_protocol",
+        igd.ssdp_address,
+        "\
+address;port;protocol;duration;comment
+127.0.0.1;8080;TCP;0;Web
+",
+        &["--control-socket", socket.to_str().unwrap()],
+    );
+    wait_until(|| socket.exists() && adds(&igd) == 1);
+
+    let open = [
+        "open",
+        "--address",
+        "127.0.0.1",
+        "--port",
+        "9002",
+        "--protocol",
+        "BOTH",
+        "--duration",
+        "0",
+    ];
+    let close = |protocol| {
+        let args = ["close", "--port", "9002", "--protocol", protocol];
+        control(&socket, &args).unwrap();
+        control(&socket, &["refresh"]).unwrap();
+    };
+    let mapped = |protocol: &str| igd.mappings().contains_key(&(protocol.to_string(), 9002));
+
+    // Closing TCP keeps UDP, and TCP is not mapped again.
+    control(&socket, &open).unwrap();
+    close("TCP");
+    assert!(!mapped("TCP"));
+    assert!(mapped("UDP"));
+    let status = control::send(&socket, &json!({ "command": "status" })).unwrap();
+    assert_eq!(status.mappings.len(), 2);
+    assert_eq!(status.mappings[1].protocol, upnp_daemon::Protocols::UDP);
+
+    // Closing both forgets a mapping of one protocol.
+    close("BOTH");
+    assert!(!mapped("UDP"));
+    let status = control::send(&socket, &json!({ "command": "status" })).unwrap();
+    assert_eq!(status.mappings.len(), 1);
+
+    control(&socket, &["shutdown"]).unwrap();
+    assert!(daemon.wait().unwrap().success());
+}
+
+fn scrape(port: u16) -> io::Result<String> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port))?;
+    write!(stream, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+#[test]
+fn metrics() {
+    let igd = FakeIgd::start(1);
+    igd.map("TCP", 9090, "127.0.0.2", 9090);
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let daemon = spawn(
+        "metrics",
+        igd.ssdp_address,
+        "\
+address;port;protocol;duration;comment
+127.0.0.1;8080;TCP;0;Web
+127.0.0.1;9090;TCP;0;Taken
+",
+        &["--metrics-port", &port.to_string()],
+    );
+    let external_ip = "upnp_daemon_external_ip{gateway=\"127.0.0.1\",address=\"203.0.113.1\"} 1";
+    wait_until(|| scrape(port).is_ok_and(|metrics| metrics.contains(external_ip)));
+    let response = scrape(port).unwrap();
+    terminate(daemon);
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Content-Type: application/openmetrics-text; version=1.0.0"));
+    assert!(response.ends_with("# EOF\n"));
+    for line in &[
+        "upnp_daemon_cycles_total 1",
+        "upnp_daemon_mapping_successes_total{protocol=\"TCP\",external_port=\"8080\",comment=\"Web\"} 1",
+        "upnp_daemon_mapping_failures_total{protocol=\"TCP\",external_port=\"9090\",comment=\"Taken\"} 0",
+        "upnp_daemon_conflicts_stolen_total 1",
+        "upnp_daemon_ssdp_discovery_seconds_count 1",
+        "upnp_daemon_soap_call_seconds_count{action=\"AddPortMapping\"} 3",
+        "upnp_daemon_soap_call_seconds_count{action=\"DeletePortMapping\"} 1",
+    ] {
+        assert!(response.lines().any(|l| l == *line), "Missing {}", line);
+    }
+    assert!(response
+        .lines()
+        .any(|l| l.starts_with("upnp_daemon_mapping_last_success_timestamp_seconds{protocol=\"TCP\",external_port=\"8080\"")));
+}
+
+#[test]
+fn external_ip_change() {
+    let igd = FakeIgd::start(1);
+    let directory = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let file = directory.join("external_ip_change.json");
+    let log = directory.join("external_ip_change.log");
+    // The address that was known before the daemon started.
+    fs::write(&file, r#"{"127.0.0.1": "198.51.100.1"}"#).unwrap();
+    let _ = fs::remove_file(&log);
+
+    let hook = format!(
+        "echo $UPNP_DAEMON_GATEWAY $UPNP_DAEMON_OLD_IP $UPNP_DAEMON_NEW_IP >> {}",
+        log.display()
+    );
+    let daemon = spawn(
+        "external_ip_change",
+        igd.ssdp_address,
+        "\
+address;port;protocol;duration;comment
+127.0.0.1;8080;TCP;0;Web
+",
+        &[
+            "--external-ip-file",
+            file.to_str().unwrap(),
+            "--on-ip-change",
+            &hook,
+        ],
+    );
+    let changes = || fs::read_to_string(&log).unwrap_or_default();
+    wait_until(|| changes().lines().count() == 1);
+    assert_eq!(changes(), "127.0.0.1 198.51.100.1 203.0.113.1\n");
+
+    // Unchanged addresses do not run the hook.
+    signal(&daemon, "USR1");
+    wait_until(|| adds(&igd) == 2);
+    igd.set_external_ip("203.0.113.2");
+    signal(&daemon, "USR1");
+    wait_until(|| changes().lines().count() == 2);
+    terminate(daemon);
+
+    assert_eq!(
+        changes(),
+        "127.0.0.1 198.51.100.1 203.0.113.1\n127.0.0.1 203.0.113.1 203.0.113.2\n"
+    );
+    let known: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(&file).unwrap()).unwrap();
+    assert_eq!(known, json!({ "127.0.0.1": "203.0.113.2" }));
+}
+
+#[test]
+fn external_ip_change_while_stopped() {
+    let igd = FakeIgd::start(1);
+    let directory = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let state = directory.join("external_ip_change_while_stopped");
+    let file = state.join("upnp-daemon/external-ips.json");
+    let log = directory.join("external_ip_change_while_stopped.log");
+    let _ = fs::remove_dir_all(&state);
+    let _ = fs::remove_file(&log);
+    let csv = csv_file(
+        "external_ip_change_while_stopped",
+        "\
+address;port;protocol;duration;comment
+127.0.0.1;8080;TCP;0;Web
+",
+    );
+    let hook = format!(
+        "echo $UPNP_DAEMON_GATEWAY $UPNP_DAEMON_OLD_IP $UPNP_DAEMON_NEW_IP >> {}",
+        log.display()
+    );
+
+    // Without --external-ip-file, the addresses are kept in the state directory.
+    let spawn = || {
+        Command::new(env!("CARGO_BIN_EXE_upnp-daemon"))
+            .env("XDG_STATE_HOME", &state)
+            .args(["--foreground", "--file"])
+            .arg(&csv)
+            .args(["--ssdp-address", &igd.ssdp_address.to_string()])
+            .args(["--discovery-timeout", "1"])
+            .args(["--on-ip-change", &hook])
+            .spawn()
+            .unwrap()
+    };
+    let daemon = spawn();
+    wait_until(|| file.exists());
+    terminate(daemon);
+
+    igd.set_external_ip("203.0.113.2");
+    let daemon = spawn();
+    let changes = || fs::read_to_string(&log).unwrap_or_default();
+    wait_until(|| changes().lines().count() == 1);
+    terminate(daemon);
+
+    assert_eq!(changes(), "127.0.0.1 203.0.113.1 203.0.113.2\n");
+}
+
+#[test]
+fn dns_update() {
+    let igd = FakeIgd::start(1);
+    // The first update fails with SERVFAIL.
+    let dns = FakeDns::start(&[2]);
+    let directory = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let known = directory.join("dns_update.json");
+    fs::write(&known, r#"{"127.0.0.1": "198.51.100.1"}"#).unwrap();
+    let file = directory.join("dns_update.yaml");
+    fs::write(
+        &file,
+        format!(
+            "\
+daemon:
+  interval: 3600
+  external_ip_file: {}
+  ddns:
+    server: {}
+    zone: example.com
+    records: [home]
+    key_name: upnp-daemon
+    key_secret: c2VjcmV0LWtleS1mb3ItdGhlLXRlc3Rz
+mappings:
+  - address: 127.0.0.1
+    port: 8080
+    protocol: TCP
+    duration: 0
+    comment: Web
+",
+            known.display(),
+            dns.address
+        ),
+    )
+    .unwrap();
+
+    let daemon = Command::new(env!("CARGO_BIN_EXE_upnp-daemon"))
+        .arg("--foreground")
+        .arg("--file")
+        .arg(&file)
+        .args(["--ssdp-address", &igd.ssdp_address.to_string()])
+        .spawn()
+        .unwrap();
+    wait_until(|| dns.messages().len() == 1);
+
+    // A failed update is retried in the next iteration, even without another change.
+    signal(&daemon, "USR1");
+    wait_until(|| dns.messages().len() == 2);
+
+    igd.set_external_ip("203.0.113.2");
+    signal(&daemon, "USR1");
+    wait_until(|| dns.messages().len() == 3);
+    terminate(daemon);
+
+    let record = |address: [u8; 4]| {
+        let mut record = b"\x04home\x07example\x03com\x00\x00\x01\x00\x01".to_vec();
+        record.extend([0, 0, 0, 60, 0, 4]);
+        record.extend(address);
+        record
+    };
+    let messages = dns.messages();
+    for (message, address) in
+        messages
+            .iter()
+            .zip([[203, 0, 113, 1], [203, 0, 113, 1], [203, 0, 113, 2]])
+    {
+        // An UPDATE of the record.
+        assert_eq!(message[2] >> 3, 5);
+        let record = record(address);
+        assert!(message.windows(record.len()).any(|window| window == record));
+    }
+}
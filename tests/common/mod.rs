@@ -0,0 +1,631 @@
+//! Stand-ins for routers and name servers, shared by the integration tests.
+
+#![allow(dead_code)]
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use upnp_daemon::hmac::hmac_sha256;
+
+/// An HTTP request, as far as the stand-ins care about it.
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+impl Request {
+    /// The SOAP action of a control request.
+    pub fn action(&self) -> String {
+        self.headers["soapaction"]
+            .trim_matches('"')
+            .rsplit('#')
+            .next()
+            .unwrap()
+            .to_string()
+    }
+
+    /// The service type that a control request is meant for.
+    pub fn service_type(&self) -> String {
+        self.headers["soapaction"]
+            .trim_matches('"')
+            .split('#')
+            .next()
+            .unwrap()
+            .to_string()
+    }
+
+    /// The value of a SOAP argument.
+    pub fn value(&self, name: &str) -> String {
+        let start = self.body.find(&format!("<{}>", name)).unwrap() + name.len() + 2;
+        let end = self.body.find(&format!("</{}>", name)).unwrap();
+        self.body[start..end].to_string()
+    }
+}
+
+/// Answer HTTP requests on a loopback port with `handler`, which returns the status code and
+/// body of the response. Returns the port.
+pub fn serve<F>(mut handler: F) -> u16
+where
+    F: FnMut(&Request) -> (u16, String) + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut request_line = request_line.split_whitespace();
+
+            let mut headers = HashMap::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                let (name, value) = line.split_at(line.find(':').unwrap());
+                headers.insert(name.to_lowercase(), value[1..].trim().to_string());
+            }
+
+            let mut body = vec![
+                0;
+                headers
+                    .get("content-length")
+                    .map_or(0, |l| l.parse().unwrap())
+            ];
+            reader.read_exact(&mut body).unwrap();
+
+            let request = Request {
+                method: request_line.next().unwrap().to_string(),
+                path: request_line.next().unwrap().to_string(),
+                headers,
+                body: String::from_utf8(body).unwrap(),
+            };
+            let (status, response) = handler(&request);
+
+            write!(
+                stream,
+                "HTTP/1.1 {} X\r\nContent-Type: text/xml\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                response.len(),
+                response
+            )
+            .unwrap();
+        }
+    });
+
+    port
+}
+
+/// A SOAP envelope with either the response to `action` or a UPnP error code.
+pub fn soap_response(
+    action: &str,
+    service_type: &str,
+    result: Result<String, u16>,
+) -> (u16, String) {
+    let (status, body) = match result {
+        Ok(content) => (
+            200,
+            format!(
+                "<u:{0}Response xmlns:u=\"{1}\">{2}</u:{0}Response>",
+                action, service_type, content
+            ),
+        ),
+        Err(code) => (
+            500,
+            format!(
+                "<s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring>\
+                 <detail><UPnPError><errorCode>{}</errorCode>\
+                 <errorDescription>Error</errorDescription></UPnPError></detail></s:Fault>",
+                code
+            ),
+        ),
+    };
+
+    (
+        status,
+        format!(
+            "<?xml version=\"1.0\"?><s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\">\
+             <s:Body>{}</s:Body></s:Envelope>",
+            body
+        ),
+    )
+}
+
+const DESCRIPTION: &str = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+<device>
+<deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:{version}</deviceType>
+<deviceList><device>
+<deviceType>urn:schemas-upnp-org:device:WANDevice:{version}</deviceType>
+<deviceList><device>
+<deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:{version}</deviceType>
+<serviceList>
+<service>
+<serviceType>{service_type}</serviceType>
+<controlURL>/ctl/IPConn</controlURL>
+<SCPDURL>/WANIPCn.xml</SCPDURL>
+</service>
+</serviceList>
+</device></deviceList>
+</device></deviceList>
+</device>
+</root>"#;
+
+const ACTIONS: &[(&str, &[&str])] = &[
+    (
+        "AddPortMapping",
+        &[
+            "NewRemoteHost",
+            "NewExternalPort",
+            "NewProtocol",
+            "NewInternalPort",
+            "NewInternalClient",
+            "NewEnabled",
+            "NewPortMappingDescription",
+            "NewLeaseDuration",
+        ],
+    ),
+    (
+        "DeletePortMapping",
+        &["NewRemoteHost", "NewExternalPort", "NewProtocol"],
+    ),
+    ("GetExternalIPAddress", &[]),
+    ("GetGenericPortMappingEntry", &["NewPortMappingIndex"]),
+];
+
+/// Actions that were added in version 2 of `WANIPConnection`.
+const ACTIONS_V2: &[(&str, &[&str])] = &[(
+    "DeletePortMappingRange",
+    &["NewStartPort", "NewEndPort", "NewProtocol", "NewManage"],
+)];
+
+fn schema(version: u8) -> String {
+    let v2 = if version >= 2 { ACTIONS_V2 } else { &[] };
+    let actions: String = ACTIONS
+        .iter()
+        .chain(v2)
+        .map(|(name, arguments)| {
+            let arguments: String = arguments
+                .iter()
+                .map(|argument| {
+                    format!(
+                        "<argument><name>{}</name><direction>in</direction></argument>",
+                        argument
+                    )
+                })
+                .collect();
+            format!(
+                "<action><name>{}</name><argumentList>{}</argumentList></action>",
+                name, arguments
+            )
+        })
+        .collect();
+
+    format!(
+        "<?xml version=\"1.0\"?><scpd xmlns=\"urn:schemas-upnp-org:service-1-0\">\
+         <actionList>{}</actionList></scpd>",
+        actions
+    )
+}
+
+/// A port mapping request, as received by the fake gateway.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Call {
+    Add {
+        protocol: String,
+        external_port: u16,
+        internal_client: String,
+        internal_port: u16,
+        lease_duration: u32,
+        description: String,
+    },
+    Delete {
+        protocol: String,
+        external_port: u16,
+    },
+    DeleteRange {
+        protocol: String,
+        start: u16,
+        end: u16,
+        /// Whether mappings of other clients are deleted as well.
+        manage: bool,
+    },
+}
+
+/// A port mapping in the table of the fake gateway.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Entry {
+    pub internal_client: String,
+    pub internal_port: u16,
+    pub description: String,
+}
+
+#[derive(Default)]
+struct State {
+    calls: Vec<Call>,
+    mappings: BTreeMap<(String, u16), Entry>,
+    reserved: BTreeSet<u16>,
+    searches: usize,
+    offline: bool,
+    external_ip: Option<String>,
+    /// The remaining lease of mappings that do not last forever.
+    leases: BTreeMap<(String, u16), u32>,
+}
+
+/// An Internet Gateway Device on loopback, answering SSDP searches and port mapping requests of
+/// the `WANIPConnection` service.
+pub struct FakeIgd {
+    pub ssdp_address: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl FakeIgd {
+    /// Start a gateway that offers the given version of `WANIPConnection`.
+    pub fn start(version: u8) -> Self {
+        Self::start_with("WANIPConnection", version)
+    }
+
+    /// Start a gateway of the given version that offers the connection service `service`, like
+    /// `WANPPPConnection`. Requests for other services are refused, like strict routers do.
+    pub fn start_with(service: &str, version: u8) -> Self {
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let service_version = if service == "WANIPConnection" {
+            version
+        } else {
+            1
+        };
+        let service_type = format!(
+            "urn:schemas-upnp-org:service:{}:{}",
+            service, service_version
+        );
+        let description = DESCRIPTION
+            .replace("{version}", &version.to_string())
+            .replace("{service_type}", &service_type);
+        let s = state.clone();
+        let http_port = serve(move |request| match request.path.as_str() {
+            _ if s.lock().unwrap().offline => (503, String::new()),
+            "/rootDesc.xml" => (200, description.clone()),
+            "/WANIPCn.xml" => (200, schema(version)),
+            "/ctl/IPConn" => {
+                let action = request.action();
+                let result = if request.service_type() == service_type
+                    && request
+                        .body
+                        .contains(&format!("xmlns:u=\"{}\"", service_type))
+                {
+                    s.lock().unwrap().control(&action, request)
+                } else {
+                    // InvalidAction
+                    Err(401)
+                };
+                soap_response(&action, &service_type, result)
+            }
+            _ => (404, String::new()),
+        });
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let ssdp_address = socket.local_addr().unwrap();
+        let s = state.clone();
+        thread::spawn(move || {
+            let mut buffer = [0; 1500];
+            loop {
+                let (len, from) = socket.recv_from(&mut buffer).unwrap();
+                if !buffer[..len].starts_with(b"M-SEARCH") {
+                    continue;
+                }
+                {
+                    let mut state = s.lock().unwrap();
+                    state.searches += 1;
+                    if state.offline {
+                        continue;
+                    }
+                }
+
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\n\
+                     CACHE-CONTROL: max-age=120\r\n\
+                     ST: urn:schemas-upnp-org:device:InternetGatewayDevice:{}\r\n\
+                     USN: uuid:fake-igd::urn:schemas-upnp-org:device:InternetGatewayDevice:{0}\r\n\
+                     EXT:\r\n\
+                     SERVER: Fake IGD\r\n\
+                     LOCATION: http://127.0.0.1:{}/rootDesc.xml\r\n\r\n",
+                    version, http_port
+                );
+                socket.send_to(response.as_bytes(), from).unwrap();
+            }
+        });
+
+        FakeIgd {
+            ssdp_address,
+            state,
+        }
+    }
+
+    /// Add a mapping to the table, as if another client requested it.
+    pub fn map(
+        &self,
+        protocol: &str,
+        external_port: u16,
+        internal_client: &str,
+        internal_port: u16,
+    ) {
+        self.state.lock().unwrap().mappings.insert(
+            (protocol.to_string(), external_port),
+            Entry {
+                internal_client: internal_client.to_string(),
+                internal_port,
+                description: "Someone else".to_string(),
+            },
+        );
+    }
+
+    /// Refuse every mapping of `external_port` with `ConflictInMappingEntry`, as routers do for
+    /// ports they use themselves.
+    pub fn reserve(&self, external_port: u16) {
+        self.state.lock().unwrap().reserved.insert(external_port);
+    }
+
+    /// Report `seconds` as the remaining lease of a mapping.
+    pub fn set_lease(&self, protocol: &str, external_port: u16, seconds: u32) {
+        self.state
+            .lock()
+            .unwrap()
+            .leases
+            .insert((protocol.to_string(), external_port), seconds);
+    }
+
+    /// Stop answering searches and requests, as if the gateway was unplugged.
+    pub fn set_offline(&self, offline: bool) {
+        self.state.lock().unwrap().offline = offline;
+    }
+
+    /// Answer with another external address than `203.0.113.1`.
+    pub fn set_external_ip(&self, external_ip: &str) {
+        self.state.lock().unwrap().external_ip = Some(external_ip.to_string());
+    }
+
+    /// How many SSDP searches were received.
+    pub fn searches(&self) -> usize {
+        self.state.lock().unwrap().searches
+    }
+
+    pub fn calls(&self) -> Vec<Call> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    pub fn mappings(&self) -> BTreeMap<(String, u16), Entry> {
+        self.state.lock().unwrap().mappings.clone()
+    }
+}
+
+impl State {
+    fn control(&mut self, action: &str, request: &Request) -> Result<String, u16> {
+        match action {
+            "AddPortMapping" => {
+                let protocol = request.value("NewProtocol");
+                let external_port = request.value("NewExternalPort").parse().unwrap();
+                let entry = Entry {
+                    internal_client: request.value("NewInternalClient"),
+                    internal_port: request.value("NewInternalPort").parse().unwrap(),
+                    description: request.value("NewPortMappingDescription"),
+                };
+
+                self.calls.push(Call::Add {
+                    protocol: protocol.clone(),
+                    external_port,
+                    internal_client: entry.internal_client.clone(),
+                    internal_port: entry.internal_port,
+                    lease_duration: request.value("NewLeaseDuration").parse().unwrap(),
+                    description: entry.description.clone(),
+                });
+
+                let key = (protocol, external_port);
+                let taken = self
+                    .mappings
+                    .get(&key)
+                    .map_or(false, |e| e.internal_client != entry.internal_client);
+                if taken || self.reserved.contains(&external_port) {
+                    // ConflictInMappingEntry
+                    return Err(718);
+                }
+
+                self.mappings.insert(key, entry);
+                Ok(String::new())
+            }
+
+            "DeletePortMapping" => {
+                let protocol = request.value("NewProtocol");
+                let external_port = request.value("NewExternalPort").parse().unwrap();
+
+                self.calls.push(Call::Delete {
+                    protocol: protocol.clone(),
+                    external_port,
+                });
+
+                // NoSuchEntryInArray
+                self.mappings
+                    .remove(&(protocol, external_port))
+                    .map(|_| String::new())
+                    .ok_or(714)
+            }
+
+            "DeletePortMappingRange" => {
+                let protocol = request.value("NewProtocol");
+                let start = request.value("NewStartPort").parse().unwrap();
+                let end = request.value("NewEndPort").parse().unwrap();
+
+                self.calls.push(Call::DeleteRange {
+                    protocol: protocol.clone(),
+                    start,
+                    end,
+                    manage: request.value("NewManage") == "1",
+                });
+
+                let before = self.mappings.len();
+                self.mappings
+                    .retain(|(p, port), _| *p != protocol || *port < start || *port > end);
+
+                // PortMappingNotFound
+                if self.mappings.len() < before {
+                    Ok(String::new())
+                } else {
+                    Err(730)
+                }
+            }
+
+            "GetExternalIPAddress" => Ok(format!(
+                "<NewExternalIPAddress>{}</NewExternalIPAddress>",
+                self.external_ip.as_deref().unwrap_or("203.0.113.1")
+            )),
+
+            "GetGenericPortMappingEntry" => {
+                let index: usize = request.value("NewPortMappingIndex").parse().unwrap();
+                // SpecifiedArrayIndexInvalid
+                let ((protocol, external_port), entry) =
+                    self.mappings.iter().nth(index).ok_or(713u16)?;
+                Ok(format!(
+                    "<NewRemoteHost></NewRemoteHost>\
+                     <NewExternalPort>{}</NewExternalPort>\
+                     <NewProtocol>{}</NewProtocol>\
+                     <NewInternalPort>{}</NewInternalPort>\
+                     <NewInternalClient>{}</NewInternalClient>\
+                     <NewEnabled>1</NewEnabled>\
+                     <NewPortMappingDescription>{}</NewPortMappingDescription>\
+                     <NewLeaseDuration>{}</NewLeaseDuration>",
+                    external_port,
+                    protocol,
+                    entry.internal_port,
+                    entry.internal_client,
+                    entry.description,
+                    self.leases
+                        .get(&(protocol.clone(), *external_port))
+                        .unwrap_or(&0)
+                ))
+            }
+
+            // InvalidAction
+            _ => Err(401),
+        }
+    }
+}
+
+/// An address where nobody answers SSDP searches, for discovery timeouts.
+pub fn silent_ssdp_address() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let address = socket.local_addr().unwrap();
+
+    // Keep the socket open, so the searches are not refused.
+    thread::spawn(move || {
+        let mut buffer = [0; 1500];
+        while socket.recv_from(&mut buffer).is_ok() {}
+    });
+
+    address
+}
+
+/// The TSIG key that the name server shares with the tests, `c2VjcmV0LWtleS1mb3ItdGhlLXRlc3Rz`
+/// in base64.
+pub const DNS_SECRET: &[u8] = b"secret-key-for-the-tests";
+
+/// A name server on loopback, answering every DNS message with the next of the given result
+/// codes, and with NOERROR once they run out.
+pub struct FakeDns {
+    pub address: SocketAddr,
+    messages: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl FakeDns {
+    /// Start a name server that signs its responses with `DNS_SECRET`.
+    pub fn start(rcodes: &[u8]) -> Self {
+        Self::signing(rcodes, Some(DNS_SECRET))
+    }
+
+    /// Start a name server that signs its responses with `secret`, or not at all.
+    pub fn signing(rcodes: &[u8], secret: Option<&[u8]>) -> Self {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        let messages = Arc::new(Mutex::new(Vec::new()));
+
+        let m = messages.clone();
+        let rcodes = rcodes.to_vec();
+        let secret = secret.map(<[u8]>::to_vec);
+        thread::spawn(move || {
+            let mut rcodes = rcodes.into_iter();
+            let mut buffer = [0; 1500];
+            loop {
+                let (len, from) = socket.recv_from(&mut buffer).unwrap();
+                m.lock().unwrap().push(buffer[..len].to_vec());
+
+                // The ID and opcode of the request, and no records.
+                let mut response = vec![0; 12];
+                response[..2].copy_from_slice(&buffer[..2]);
+                response[2] = 0x80 | (buffer[2] & 0x78);
+                response[3] = rcodes.next().unwrap_or(0);
+                if let Some(secret) = &secret {
+                    sign_response(&mut response, &buffer[..len], secret);
+                }
+                socket.send_to(&response, from).unwrap();
+            }
+        });
+
+        FakeDns { address, messages }
+    }
+
+    /// The messages received so far.
+    pub fn messages(&self) -> Vec<Vec<u8>> {
+        self.messages.lock().unwrap().clone()
+    }
+}
+
+/// Append a TSIG record to `response`, with the key name of `request`, as described in section
+/// 5.3 of RFC 8945. The request is expected to end with an HMAC-SHA256 TSIG record.
+fn sign_response(response: &mut Vec<u8>, request: &[u8], secret: &[u8]) {
+    let key = b"\x0bupnp-daemon\x00";
+    let algorithm = b"\x0bhmac-sha256\x00";
+    let request_mac = &request[request.len() - 38..request.len() - 6];
+    let time = &SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        .to_be_bytes()[2..];
+
+    // The TSIG variables: owner, class ANY, TTL 0, algorithm, time, fudge, no error and no other
+    // data.
+    let mut variables = key.to_vec();
+    variables.extend([0, 255, 0, 0, 0, 0]);
+    variables.extend(algorithm);
+    variables.extend(time);
+    variables.extend([1, 44, 0, 0, 0, 0]);
+
+    let mut signed = vec![0, 32];
+    signed.extend(request_mac);
+    signed.extend(&*response);
+    signed.extend(&variables);
+    let mac = hmac_sha256(secret, &signed);
+
+    let mut rdata = algorithm.to_vec();
+    rdata.extend(time);
+    rdata.extend([1, 44, 0, 32]);
+    rdata.extend(mac);
+    rdata.extend(&response[..2]);
+    rdata.extend([0, 0, 0, 0]);
+
+    response.extend(key);
+    response.extend([0, 250, 0, 255, 0, 0, 0, 0]);
+    response.extend((rdata.len() as u16).to_be_bytes());
+    response.extend(rdata);
+    response[11] = 1;
+}
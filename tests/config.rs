@@ -0,0 +1,155 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use upnp_daemon::config::{Config, Format};
+use upnp_daemon::error::Error;
+use upnp_daemon::{ConflictPolicy, PortRange, Protocols};
+
+const TOML: &str = r#"
+[daemon]
+interval = 30
+pid_file = "/run/upnp-daemon.pid"
+log_level = "debug"
+log_file = "/var/log/upnp-daemon.log"
+
+[[mappings]]
+address = "192.168.0.10"
+port = 8080
+protocol = "TCP"
+duration = 60
+comment = "Web"
+
+[[mappings]]
+port = "50000-50100"
+protocol = "UDP"
+duration = 0
+comment = "Media"
+conflict = "skip-and-warn"
+"#;
+
+const YAML: &str = r#"
+daemon:
+  interval: 30
+  pid_file: /run/upnp-daemon.pid
+  log_level: debug
+  log_file: /var/log/upnp-daemon.log
+mappings:
+  - address: 192.168.0.10
+    port: 8080
+    protocol: TCP
+    duration: 60
+    comment: Web
+  - port: 50000-50100
+    protocol: UDP
+    duration: 0
+    comment: Media
+    conflict: skip-and-warn
+"#;
+
+const JSON: &str = r#"{
+  "daemon": {
+    "interval": 30,
+    "pid_file": "/run/upnp-daemon.pid",
+    "log_level": "debug",
+    "log_file": "/var/log/upnp-daemon.log"
+  },
+  "mappings": [
+    {"address": "192.168.0.10", "port": 8080, "protocol": "TCP", "duration": 60, "comment": "Web"},
+    {"port": "50000-50100", "protocol": "UDP", "duration": 0, "comment": "Media",
+     "conflict": "skip-and-warn"}
+  ]
+}"#;
+
+fn write(name: &str, content: &str) -> PathBuf {
+    let file = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    fs::write(&file, content).unwrap();
+    file
+}
+
+fn check(config: &Config) {
+    assert_eq!(config.daemon.interval, Some(30));
+    assert_eq!(
+        config.daemon.pid_file.as_deref(),
+        Some(Path::new("/run/upnp-daemon.pid"))
+    );
+    assert_eq!(config.daemon.log_level.as_deref(), Some("debug"));
+    assert_eq!(
+        config.daemon.log_file.as_deref(),
+        Some(Path::new("/var/log/upnp-daemon.log"))
+    );
+
+    assert_eq!(config.mappings.len(), 2);
+
+    let web = &config.mappings[0];
+    assert_eq!(web.address.as_deref(), Some("192.168.0.10"));
+    assert_eq!(web.port, Some(PortRange::single(8080)));
+    assert_eq!(web.protocol, Protocols::TCP);
+    assert_eq!(web.conflict, None);
+
+    let media = &config.mappings[1];
+    assert_eq!(media.address, None);
+    assert_eq!(
+        media.port,
+        Some(PortRange {
+            start: 50000,
+            end: 50100
+        })
+    );
+    assert_eq!(media.protocol, Protocols::UDP);
+    assert_eq!(media.conflict, Some(ConflictPolicy::SkipAndWarn));
+}
+
+#[test]
+fn toml() {
+    let file = write("config.toml", TOML);
+    assert_eq!(Format::from_path(&file), Format::Toml);
+    check(&Config::read(&file, Format::Toml).unwrap());
+}
+
+#[test]
+fn yaml() {
+    let file = write("config.yml", YAML);
+    assert_eq!(Format::from_path(&file), Format::Yaml);
+    check(&Config::read(&file, Format::Yaml).unwrap());
+}
+
+#[test]
+fn json() {
+    let file = write("config.json", JSON);
+    assert_eq!(Format::from_path(&file), Format::Json);
+    check(&Config::read(&file, Format::Json).unwrap());
+}
+
+#[test]
+fn csv() {
+    let file = write(
+        "config.csv",
+        "address;port;protocol;duration;comment\n192.168.0.10;8080;TCP;60;Web\n",
+    );
+    assert_eq!(Format::from_path(&file), Format::Csv);
+
+    let config = Config::read(&file, Format::Csv).unwrap();
+    assert_eq!(config.daemon.interval, None);
+    assert_eq!(config.mappings.len(), 1);
+}
+
+#[test]
+fn mappings_only() {
+    let file = write(
+        "mappings_only.toml",
+        "[[mappings]]\nport = 22\nprotocol = \"TCP\"\nduration = 0\ncomment = \"SSH\"\n",
+    );
+
+    let config = Config::read(&file, Format::Toml).unwrap();
+    assert_eq!(config.daemon.interval, None);
+    assert_eq!(config.mappings.len(), 1);
+}
+
+#[test]
+fn unknown_daemon_setting() {
+    let file = write("unknown.toml", "[daemon]\nintervall = 30\n");
+    assert!(matches!(
+        Config::read(&file, Format::Toml),
+        Err(Error::Config(_))
+    ));
+}
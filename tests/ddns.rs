@@ -0,0 +1,181 @@
+use std::net::SocketAddr;
+
+use serde_json::json;
+use upnp_daemon::ddns::{self, Config};
+
+use common::FakeDns;
+
+mod common;
+
+/// `secret-key-for-the-tests` in base64.
+const SECRET: &str = "c2VjcmV0LWtleS1mb3ItdGhlLXRlc3Rz";
+
+fn config(server: SocketAddr, records: &[&str]) -> Config {
+    serde_json::from_value(json!({
+        "server": server,
+        "zone": "example.com",
+        "records": records,
+        "key_name": "Upnp-Daemon",
+        "key_secret": SECRET,
+    }))
+    .unwrap()
+}
+
+/// `name` in wire format.
+fn name(name: &str) -> Vec<u8> {
+    let mut wire = Vec::new();
+    for label in name.split('.') {
+        wire.push(label.len() as u8);
+        wire.extend_from_slice(label.as_bytes());
+    }
+    wire.push(0);
+    wire
+}
+
+fn contains(message: &[u8], part: &[u8]) -> bool {
+    message.windows(part.len()).any(|window| window == part)
+}
+
+#[test]
+fn signed_message() {
+    let config = config("127.0.0.1:53".parse().unwrap(), &["home"]);
+    let message = ddns::message(
+        &config,
+        "203.0.113.1".parse().unwrap(),
+        0x1234,
+        1_700_000_000,
+    )
+    .unwrap();
+
+    // An UPDATE with one zone, two updates and the signature.
+    let mut expected = vec![0x12, 0x34, 0x28, 0, 0, 1, 0, 0, 0, 2, 0, 1];
+    expected.extend(name("example.com"));
+    expected.extend([0, 6, 0, 1]);
+    // Delete all addresses, then add the new one.
+    expected.extend(name("home.example.com"));
+    expected.extend([0, 1, 0, 255, 0, 0, 0, 0, 0, 0]);
+    expected.extend(name("home.example.com"));
+    expected.extend([0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 203, 0, 113, 1]);
+    // The key name is signed in lowercase.
+    expected.extend(name("upnp-daemon"));
+    expected.extend([0, 250, 0, 255, 0, 0, 0, 0, 0, 61]);
+    expected.extend(name("hmac-sha256"));
+    expected.extend([0, 0, 0x65, 0x53, 0xf1, 0x00, 0x01, 0x2c, 0, 32]);
+    // Computed independently with Python's hmac module.
+    expected.extend([
+        0x7f, 0xd6, 0xdc, 0x5c, 0x1e, 0xb2, 0x7f, 0x4f, 0x6d, 0x54, 0x91, 0x3b, 0x12, 0xbd, 0x74,
+        0x2a, 0x89, 0x96, 0x29, 0x62, 0x3e, 0xc8, 0x98, 0xf1, 0xea, 0x27, 0xcd, 0xf6, 0x16, 0x7c,
+        0x9a, 0x89,
+    ]);
+    expected.extend([0x12, 0x34, 0, 0, 0, 0]);
+
+    assert_eq!(message, expected);
+}
+
+#[test]
+fn record_names() {
+    let config = config(
+        "127.0.0.1:53".parse().unwrap(),
+        &["@", "other.example.org."],
+    );
+    let message = ddns::message(&config, "203.0.113.1".parse().unwrap(), 1, 0).unwrap();
+
+    assert!(contains(
+        &message,
+        &[name("example.com"), vec![0, 1, 0, 1]].concat()
+    ));
+    assert!(contains(
+        &message,
+        &[name("other.example.org"), vec![0, 1, 0, 1]].concat()
+    ));
+
+    let config = self::config("127.0.0.1:53".parse().unwrap(), &["in..valid"]);
+    let error = ddns::message(&config, "203.0.113.1".parse().unwrap(), 1, 0).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Invalid domain name: in..valid.example.com"
+    );
+
+    let config = self::config("127.0.0.1:53".parse().unwrap(), &["home"; 40000]);
+    let error = ddns::message(&config, "203.0.113.1".parse().unwrap(), 1, 0).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Too many records to update at once: 40000"
+    );
+}
+
+#[test]
+fn update_ipv6() {
+    let dns = FakeDns::start(&[]);
+    ddns::update(
+        &config(dns.address, &["home"]),
+        "2001:db8::1".parse().unwrap(),
+    )
+    .unwrap();
+
+    let messages = dns.messages();
+    assert_eq!(messages.len(), 1);
+    assert!(contains(
+        &messages[0],
+        &[
+            name("home.example.com"),
+            vec![0, 28, 0, 1, 0, 0, 0, 60, 0, 16, 0x20, 0x01, 0x0d, 0xb8],
+            vec![0; 11],
+            vec![1],
+        ]
+        .concat()
+    ));
+}
+
+#[test]
+fn rejected_update() {
+    let dns = FakeDns::start(&[9]);
+    let error = ddns::update(
+        &config(dns.address, &["home"]),
+        "203.0.113.1".parse().unwrap(),
+    )
+    .unwrap_err();
+
+    assert_eq!(error.to_string(), "DNS server rejected the update: NOTAUTH");
+}
+
+#[test]
+fn forged_response() {
+    let dns = FakeDns::signing(&[], Some(b"another-secret"));
+    let error = ddns::update(
+        &config(dns.address, &["home"]),
+        "203.0.113.1".parse().unwrap(),
+    )
+    .unwrap_err();
+
+    assert_eq!(
+        error.to_string(),
+        "DNS server response has an invalid signature"
+    );
+}
+
+#[test]
+fn unsigned_response() {
+    let dns = FakeDns::signing(&[], None);
+    let error = ddns::update(
+        &config(dns.address, &["home"]),
+        "203.0.113.1".parse().unwrap(),
+    )
+    .unwrap_err();
+
+    assert_eq!(error.to_string(), "DNS server did not sign its response");
+}
+
+#[test]
+fn invalid_secret() {
+    let error = serde_json::from_value::<Config>(json!({
+        "server": "127.0.0.1:53",
+        "zone": "example.com",
+        "records": ["home"],
+        "key_name": "upnp-daemon",
+        "key_secret": "not base64!",
+    }))
+    .unwrap_err();
+
+    assert_eq!(error.to_string(), "the key secret is not valid base64");
+}
@@ -0,0 +1,160 @@
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use std::thread;
+
+use upnp_daemon::natpmp::{self, Client, Mapping};
+use upnp_daemon::PortMappingProtocol;
+
+// All tests share the well-known NAT-PMP port, so they must not run concurrently.
+static PORT_LOCK: Mutex<()> = Mutex::new(());
+
+/// Answer `requests` packets on 127.0.0.1:5351 with whatever `respond` returns. Returning
+/// `None` drops the request, to simulate packet loss.
+fn stand_in<F>(requests: usize, mut respond: F) -> thread::JoinHandle<Vec<Vec<u8>>>
+where
+    F: FnMut(&[u8]) -> Option<Vec<u8>> + Send + 'static,
+{
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, natpmp::PORT)).unwrap();
+
+    thread::spawn(move || {
+        let mut received = Vec::new();
+        for _ in 0..requests {
+            let mut buf = [0u8; 64];
+            let (read, from) = socket.recv_from(&mut buf).unwrap();
+            received.push(buf[..read].to_vec());
+            if let Some(response) = respond(&buf[..read]) {
+                socket.send_to(&response, from).unwrap();
+            }
+        }
+        received
+    })
+}
+
+fn client() -> Client {
+    Client::new(
+        Ipv4Addr::LOCALHOST,
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), natpmp::PORT),
+    )
+    .unwrap()
+}
+
+fn map_response(request: &[u8], result: u16, external_port: u16) -> Vec<u8> {
+    let mut response = vec![0, request[1] + 128];
+    response.extend_from_slice(&result.to_be_bytes());
+    response.extend_from_slice(&42u32.to_be_bytes());
+    response.extend_from_slice(&request[4..6]);
+    response.extend_from_slice(&external_port.to_be_bytes());
+    response.extend_from_slice(&request[8..12]);
+    response
+}
+
+#[test]
+fn external_address() {
+    let _lock = PORT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let server = stand_in(1, |_| Some(vec![0, 128, 0, 0, 0, 0, 0, 42, 203, 0, 113, 7]));
+
+    assert_eq!(
+        client().external_address().unwrap(),
+        Ipv4Addr::new(203, 0, 113, 7)
+    );
+    assert_eq!(server.join().unwrap(), vec![vec![0, 0]]);
+}
+
+#[test]
+fn add_and_remove_port() {
+    let _lock = PORT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let server = stand_in(2, |request| {
+        let external_port = u16::from_be_bytes([request[6], request[7]]);
+        Some(map_response(request, 0, external_port))
+    });
+
+    let client = client();
+    assert_eq!(
+        client
+            .add_port(PortMappingProtocol::TCP, 12345, 12345, 3600)
+            .unwrap(),
+        Mapping {
+            internal_port: 12345,
+            external_port: 12345,
+            lifetime: 3600,
+        }
+    );
+    client.remove_port(PortMappingProtocol::UDP, 12346).unwrap();
+
+    let received = server.join().unwrap();
+    assert_eq!(
+        received[0],
+        vec![0, 2, 0, 0, 0x30, 0x39, 0x30, 0x39, 0, 0, 0x0e, 0x10]
+    );
+    assert_eq!(received[1], vec![0, 1, 0, 0, 0x30, 0x3a, 0, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn gateway_assigns_other_port() {
+    let _lock = PORT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let server = stand_in(1, |request| Some(map_response(request, 0, 23456)));
+
+    let mapping = client()
+        .add_port(PortMappingProtocol::UDP, 12345, 12345, 60)
+        .unwrap();
+    assert_eq!(mapping.external_port, 23456);
+
+    server.join().unwrap();
+}
+
+#[test]
+fn refused_request() {
+    let _lock = PORT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let server = stand_in(1, |request| Some(map_response(request, 2, 0)));
+
+    match client().add_port(PortMappingProtocol::TCP, 80, 80, 60) {
+        Err(natpmp::Error::NotAuthorized) => {}
+        other => panic!("Unexpected result: {:?}", other),
+    }
+
+    server.join().unwrap();
+}
+
+#[test]
+fn retransmits_lost_requests() {
+    let _lock = PORT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let mut seen = 0;
+    let server = stand_in(2, move |request| {
+        seen += 1;
+        if seen == 1 {
+            None
+        } else {
+            Some(map_response(request, 0, 443))
+        }
+    });
+
+    let mapping = client()
+        .add_port(PortMappingProtocol::TCP, 443, 443, 60)
+        .unwrap();
+    assert_eq!(mapping.external_port, 443);
+
+    let received = server.join().unwrap();
+    assert_eq!(received[0], received[1]);
+}
+
+#[test]
+fn gives_up_without_answer() {
+    let _lock = PORT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let server = stand_in(2, |_| None);
+
+    match client()
+        .attempts(2)
+        .add_port(PortMappingProtocol::TCP, 80, 80, 60)
+    {
+        Err(natpmp::Error::Timeout) => {}
+        other => panic!("Unexpected result: {:?}", other),
+    }
+
+    server.join().unwrap();
+}
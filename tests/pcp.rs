@@ -0,0 +1,219 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::os::unix::fs::PermissionsExt;
+use std::thread;
+
+use upnp_daemon::pcp::{self, Client, Mapping};
+use upnp_daemon::PortMappingProtocol;
+
+/// Answer `requests` packets on a local port with whatever `respond` returns.
+fn stand_in<F>(
+    addr: IpAddr,
+    requests: usize,
+    respond: F,
+) -> (SocketAddr, thread::JoinHandle<Vec<Vec<u8>>>)
+where
+    F: Fn(&[u8]) -> Vec<u8> + Send + 'static,
+{
+    let socket = UdpSocket::bind((addr, 0)).unwrap();
+    let server = socket.local_addr().unwrap();
+
+    let handle = thread::spawn(move || {
+        let mut received = Vec::new();
+        for _ in 0..requests {
+            let mut buf = [0u8; 1100];
+            let (read, from) = socket.recv_from(&mut buf).unwrap();
+            received.push(buf[..read].to_vec());
+            socket.send_to(&respond(&buf[..read]), from).unwrap();
+        }
+        received
+    });
+
+    (server, handle)
+}
+
+/// Grant every request, using `external` as the assigned address and the suggested port.
+fn grant(external: Ipv6Addr) -> impl Fn(&[u8]) -> Vec<u8> {
+    move |request| {
+        let mut response = vec![2, 0x81, 0, 0];
+        response.extend_from_slice(&request[4..8]);
+        response.extend_from_slice(&1234u32.to_be_bytes());
+        response.extend_from_slice(&[0; 12]);
+        response.extend_from_slice(&request[24..44]);
+        response.extend_from_slice(&external.octets());
+        response
+    }
+}
+
+#[test]
+fn map_ipv4() {
+    let external = Ipv4Addr::new(198, 51, 100, 1);
+    let (server, handle) = stand_in(
+        Ipv4Addr::LOCALHOST.into(),
+        2,
+        grant(external.to_ipv6_mapped()),
+    );
+
+    let client = Client::new(Ipv4Addr::LOCALHOST.into(), server).unwrap();
+    let nonce = [7; 12];
+
+    assert_eq!(
+        client
+            .add_port(&nonce, PortMappingProtocol::UDP, 5000, 5000, 600)
+            .unwrap(),
+        Mapping {
+            internal_port: 5000,
+            external_address: external.into(),
+            external_port: 5000,
+            lifetime: 600,
+            epoch: 1234,
+        }
+    );
+    client
+        .remove_port(&nonce, PortMappingProtocol::UDP, 5000)
+        .unwrap();
+
+    let received = handle.join().unwrap();
+
+    let request = &received[0];
+    assert_eq!(request.len(), 60);
+    assert_eq!(request[..4], [2, 1, 0, 0]);
+    assert_eq!(request[4..8], 600u32.to_be_bytes());
+    assert_eq!(
+        request[8..24],
+        Ipv4Addr::LOCALHOST.to_ipv6_mapped().octets()
+    );
+    assert_eq!(request[24..36], nonce);
+    assert_eq!(request[36], 17);
+    assert_eq!(request[40..44], [0x13, 0x88, 0x13, 0x88]);
+    assert_eq!(
+        request[44..60],
+        Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets()
+    );
+
+    // Deletion reuses the nonce with a lifetime of 0.
+    let request = &received[1];
+    assert_eq!(request[4..8], [0, 0, 0, 0]);
+    assert_eq!(request[24..36], nonce);
+}
+
+#[test]
+fn map_ipv6() {
+    let socket = match UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)) {
+        Ok(socket) => socket,
+        // The test environment might not have IPv6 at all.
+        Err(_) => return,
+    };
+    drop(socket);
+
+    let external = "2001:db8::1".parse().unwrap();
+    let (server, handle) = stand_in(Ipv6Addr::LOCALHOST.into(), 1, grant(external));
+
+    let client = Client::new(Ipv6Addr::LOCALHOST.into(), server).unwrap();
+    let mapping = client
+        .add_port(&[1; 12], PortMappingProtocol::TCP, 22, 2222, 60)
+        .unwrap();
+
+    assert_eq!(mapping.external_address, IpAddr::V6(external));
+    assert_eq!(mapping.external_port, 2222);
+
+    let received = handle.join().unwrap();
+    assert_eq!(received[0][8..24], Ipv6Addr::LOCALHOST.octets());
+    assert_eq!(received[0][36], 6);
+    assert_eq!(received[0][44..60], Ipv6Addr::UNSPECIFIED.octets());
+}
+
+#[test]
+fn natpmp_only_server() {
+    let (server, handle) = stand_in(Ipv4Addr::LOCALHOST.into(), 1, |_| {
+        vec![0, 0x81, 0, 1, 0, 0, 0, 0]
+    });
+
+    let client = Client::new(Ipv4Addr::LOCALHOST.into(), server).unwrap();
+    match client.add_port(&[0; 12], PortMappingProtocol::TCP, 80, 80, 60) {
+        Err(pcp::Error::UnsupportedVersion) => {}
+        other => panic!("Unexpected result: {:?}", other),
+    }
+
+    handle.join().unwrap();
+}
+
+#[test]
+fn refused_request() {
+    let (server, handle) = stand_in(Ipv4Addr::LOCALHOST.into(), 1, |request| {
+        let mut response = grant(Ipv6Addr::UNSPECIFIED)(request);
+        response[3] = 8;
+        response
+    });
+
+    let client = Client::new(Ipv4Addr::LOCALHOST.into(), server).unwrap();
+    match client.add_port(&[0; 12], PortMappingProtocol::TCP, 80, 80, 60) {
+        Err(pcp::Error::NoResources) => {}
+        other => panic!("Unexpected result: {:?}", other),
+    }
+
+    handle.join().unwrap();
+}
+
+#[test]
+fn nonce_is_kept_for_renewals() {
+    let internal = "192.0.2.10:4000".parse().unwrap();
+
+    let mut nonces = pcp::Nonces::default();
+
+    let first = nonces.get(PortMappingProtocol::TCP, internal);
+    assert_eq!(nonces.get(PortMappingProtocol::TCP, internal), first);
+    assert_ne!(nonces.get(PortMappingProtocol::UDP, internal), first);
+}
+
+#[test]
+fn nonces_are_kept_in_a_file() {
+    let internal = "192.0.2.10:4000".parse().unwrap();
+    let file = std::env::temp_dir().join(format!("upnp-daemon-nonces-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&file);
+
+    // A readable file that was there before must not keep its permissions.
+    std::fs::write(&file, "[]").unwrap();
+    std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+    let first = pcp::Nonces::load(file.clone()).get(PortMappingProtocol::TCP, internal);
+    let mut reloaded = pcp::Nonces::load(file.clone());
+    assert_eq!(reloaded.get(PortMappingProtocol::TCP, internal), first);
+    let mode = std::fs::metadata(&file).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    std::fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn nonces_do_not_follow_links() {
+    let internal = "192.0.2.10:4000".parse().unwrap();
+    let dir = std::env::temp_dir();
+    let file = dir.join(format!("upnp-daemon-link-{}.json", std::process::id()));
+    let victim = dir.join(format!("upnp-daemon-victim-{}", std::process::id()));
+    std::fs::write(&victim, "untouched").unwrap();
+    let _ = std::fs::remove_file(&file);
+    std::os::unix::fs::symlink(&victim, &file).unwrap();
+
+    pcp::Nonces::load(file.clone()).get(PortMappingProtocol::TCP, internal);
+    assert_eq!(std::fs::read_to_string(&victim).unwrap(), "untouched");
+    assert!(std::fs::symlink_metadata(&file).unwrap().is_file());
+
+    std::fs::remove_file(&file).unwrap();
+    std::fs::remove_file(&victim).unwrap();
+}
+
+#[test]
+fn announce() {
+    let (server, handle) = stand_in(Ipv4Addr::LOCALHOST.into(), 1, |request| {
+        let mut response = vec![2, 0x80, 0, 0, 0, 0, 0, 0];
+        response.extend_from_slice(&99u32.to_be_bytes());
+        response.extend_from_slice(&[0; 12]);
+        assert_eq!(request.len(), 24);
+        response
+    });
+
+    let client = Client::new(Ipv4Addr::LOCALHOST.into(), server).unwrap();
+    assert_eq!(client.announce().unwrap(), 99);
+
+    handle.join().unwrap();
+}
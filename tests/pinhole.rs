@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::net::SocketAddrV6;
+use std::sync::{Arc, Mutex};
+
+use igd::Gateway;
+use upnp_daemon::pinhole::{self, Firewall, Pinholes};
+use upnp_daemon::PortMappingProtocol;
+
+mod common;
+
+const DESCRIPTION: &str = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+<device>
+<deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:2</deviceType>
+<deviceList><device>
+<deviceType>urn:schemas-upnp-org:device:WANDevice:2</deviceType>
+<deviceList><device>
+<deviceType>urn:schemas-upnp-org:device:WANConnectionDevice:2</deviceType>
+<serviceList>
+<service>
+<serviceType>urn:schemas-upnp-org:service:WANIPConnection:2</serviceType>
+<controlURL>/ctl/IPConn</controlURL>
+</service>
+<service>
+<serviceType>urn:schemas-upnp-org:service:WANIPv6FirewallControl:1</serviceType>
+<controlURL>/ctl/IP6FCtl</controlURL>
+</service>
+</serviceList>
+</device></deviceList>
+</device></deviceList>
+</device>
+</root>"#;
+
+/// Answers the device description and pinhole SOAP calls. The recorded calls are the SOAP action
+/// and the request body.
+struct StandIn {
+    port: u16,
+    calls: Arc<Mutex<Vec<(String, String)>>>,
+    pinholes: Arc<Mutex<HashMap<u16, String>>>,
+    /// How many of the next deletions fail.
+    failing_deletes: Arc<Mutex<u32>>,
+}
+
+impl StandIn {
+    fn start() -> Self {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let pinholes = Arc::new(Mutex::new(HashMap::new()));
+        let failing_deletes = Arc::new(Mutex::new(0));
+
+        let (c, p, f) = (calls.clone(), pinholes.clone(), failing_deletes.clone());
+        let mut next_id = 1;
+        let port = common::serve(move |request| {
+            if request.path == "/rootDesc.xml" {
+                return (200, DESCRIPTION.to_string());
+            } else if request.method == "GET" {
+                // An IGDv1 device without the firewall service.
+                return (
+                    200,
+                    DESCRIPTION.replace("WANIPv6FirewallControl", "Layer3Forwarding"),
+                );
+            }
+
+            let action = request.action();
+            c.lock()
+                .unwrap()
+                .push((action.clone(), request.body.clone()));
+
+            let mut pinholes = p.lock().unwrap();
+            let result = match action.as_str() {
+                "AddPinhole" => {
+                    let id = next_id;
+                    next_id += 1;
+                    pinholes.insert(id, request.value("InternalClient"));
+                    Ok(format!("<UniqueID>{}</UniqueID>", id))
+                }
+                "UpdatePinhole" => {
+                    let id = request.value("UniqueID").parse().unwrap();
+                    pinholes.get(&id).map(|_| String::new()).ok_or(704)
+                }
+                "DeletePinhole" if *f.lock().unwrap() > 0 => {
+                    *f.lock().unwrap() -= 1;
+                    Err(501)
+                }
+                "DeletePinhole" => {
+                    let id = request.value("UniqueID").parse().unwrap();
+                    pinholes.remove(&id).map(|_| String::new()).ok_or(704)
+                }
+                _ => Err(401),
+            };
+
+            common::soap_response(&action, pinhole::SERVICE_TYPE, result)
+        });
+
+        StandIn {
+            port,
+            calls,
+            pinholes,
+            failing_deletes,
+        }
+    }
+
+    fn gateway(&self) -> Gateway {
+        Gateway {
+            addr: format!("127.0.0.1:{}", self.port).parse().unwrap(),
+            root_url: "/rootDesc.xml".to_string(),
+            control_url: "/ctl/IPConn".to_string(),
+            control_schema_url: "/WANIPCn.xml".to_string(),
+            control_schema: HashMap::new(),
+        }
+    }
+
+    fn forget_all(&self) {
+        self.pinholes.lock().unwrap().clear();
+    }
+
+    fn actions(&self) -> Vec<String> {
+        self.calls
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(a, _)| a.clone())
+            .collect()
+    }
+}
+
+#[test]
+fn open_refresh_and_close() {
+    let stand_in = StandIn::start();
+    let firewall = Firewall::from_gateway(&stand_in.gateway()).unwrap();
+    let internal: SocketAddrV6 = "[2001:db8::10]:8080".parse().unwrap();
+    let mut pinholes = Pinholes::default();
+
+    let id = pinholes
+        .open(&firewall, PortMappingProtocol::TCP, internal, 3600)
+        .unwrap();
+    assert_eq!(
+        pinholes
+            .open(&firewall, PortMappingProtocol::TCP, internal, 3600)
+            .unwrap(),
+        id
+    );
+    assert_eq!(
+        pinholes.iter().collect::<Vec<_>>(),
+        vec![(PortMappingProtocol::TCP, internal, id)]
+    );
+
+    pinholes
+        .close(&firewall, PortMappingProtocol::TCP, internal)
+        .unwrap();
+    assert!(stand_in.pinholes.lock().unwrap().is_empty());
+    assert_eq!(pinholes.iter().count(), 0);
+
+    assert_eq!(
+        stand_in.actions(),
+        vec!["AddPinhole", "UpdatePinhole", "DeletePinhole"]
+    );
+
+    let calls = stand_in.calls.lock().unwrap();
+    let add = &calls[0].1;
+    assert!(add.contains("<InternalClient>2001:db8::10</InternalClient>"));
+    assert!(add.contains("<InternalPort>8080</InternalPort>"));
+    assert!(add.contains("<Protocol>6</Protocol>"));
+    assert!(add.contains("<LeaseTime>3600</LeaseTime>"));
+}
+
+#[test]
+fn reopen_after_gateway_forgot() {
+    let stand_in = StandIn::start();
+    let firewall = Firewall::from_gateway(&stand_in.gateway()).unwrap();
+    let internal: SocketAddrV6 = "[2001:db8::20]:53".parse().unwrap();
+    let mut pinholes = Pinholes::default();
+
+    let first = pinholes
+        .open(&firewall, PortMappingProtocol::UDP, internal, 60)
+        .unwrap();
+    stand_in.forget_all();
+    let second = pinholes
+        .open(&firewall, PortMappingProtocol::UDP, internal, 60)
+        .unwrap();
+
+    assert_ne!(first, second);
+    assert_eq!(
+        stand_in.actions(),
+        vec!["AddPinhole", "UpdatePinhole", "AddPinhole"]
+    );
+}
+
+#[test]
+fn retry_failed_close() {
+    let stand_in = StandIn::start();
+    let firewall = Firewall::from_gateway(&stand_in.gateway()).unwrap();
+    let internal: SocketAddrV6 = "[2001:db8::30]:443".parse().unwrap();
+    let mut pinholes = Pinholes::default();
+
+    pinholes
+        .open(&firewall, PortMappingProtocol::TCP, internal, 3600)
+        .unwrap();
+    *stand_in.failing_deletes.lock().unwrap() = 1;
+
+    assert!(pinholes
+        .close(&firewall, PortMappingProtocol::TCP, internal)
+        .is_err());
+    assert_eq!(pinholes.iter().count(), 1);
+    assert_eq!(stand_in.pinholes.lock().unwrap().len(), 1);
+
+    pinholes
+        .close(&firewall, PortMappingProtocol::TCP, internal)
+        .unwrap();
+    assert_eq!(pinholes.iter().count(), 0);
+    assert!(stand_in.pinholes.lock().unwrap().is_empty());
+    assert_eq!(
+        stand_in.actions(),
+        vec!["AddPinhole", "DeletePinhole", "DeletePinhole"]
+    );
+}
+
+#[test]
+fn missing_service() {
+    let stand_in = StandIn::start();
+    let mut gateway = stand_in.gateway();
+    gateway.root_url = "/other.xml".to_string();
+
+    match Firewall::from_gateway(&gateway) {
+        Err(pinhole::Error::ServiceNotFound) => {}
+        Err(e) => panic!("Unexpected error: {}", e),
+        Ok(_) => panic!("Unexpected success"),
+    }
+}
@@ -0,0 +1,823 @@
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use upnp_daemon::backend::memory::Call;
+use upnp_daemon::backend::{
+    AddPortError, Backend, Backends, Fallback, Mapping, Memory, NatPmp, PortMappingEntry,
+};
+use upnp_daemon::error;
+use upnp_daemon::{
+    remove, run_csv, BackendKind, ConflictPolicy, PortMappingProtocol, PortRange, Protocols,
+    Removal, Settings,
+};
+
+const CSV: &str = "\
+address;port;protocol;duration;comment
+192.168.0.10;12345;UDP;60;Test 1
+;12346;TCP;0;Test 2
+";
+
+fn backends(memory: &Memory) -> Backends {
+    let mut backends = Backends::empty(BackendKind::Upnp);
+    backends.insert(BackendKind::Upnp, Box::new(memory.clone()));
+    backends
+}
+
+fn mapping(internal: &str, port: u16, protocol: PortMappingProtocol, comment: &str) -> Mapping {
+    Mapping {
+        internal: internal.parse().unwrap(),
+        external_port: port,
+        protocol,
+        duration: 60,
+        comment: comment.to_string(),
+    }
+}
+
+#[test]
+fn csv_to_router() {
+    let memory = Memory::default();
+    run_csv(CSV.as_bytes(), &mut backends(&memory), &Settings::default()).unwrap();
+
+    assert_eq!(
+        memory.mappings(),
+        vec![
+            Mapping {
+                duration: 0,
+                ..mapping(
+                    "192.168.0.2:12346",
+                    12346,
+                    PortMappingProtocol::TCP,
+                    "Test 2"
+                )
+            },
+            mapping(
+                "192.168.0.10:12345",
+                12345,
+                PortMappingProtocol::UDP,
+                "Test 1"
+            ),
+        ]
+    );
+
+    // The rows are resolved concurrently.
+    let calls = memory.calls();
+    assert!(calls[..2].contains(&Call::Discover(Some("192.168.0.10".parse().unwrap()))));
+    assert!(calls[..2].contains(&Call::Discover(None)));
+}
+
+#[test]
+fn steal_port_in_use() {
+    let memory = Memory::default();
+    let foreign = mapping(
+        "192.168.0.99:12345",
+        12345,
+        PortMappingProtocol::UDP,
+        "Other",
+    );
+    memory
+        .state()
+        .mappings
+        .insert((PortMappingProtocol::UDP, 12345), foreign.clone());
+
+    run_csv(CSV.as_bytes(), &mut backends(&memory), &Settings::default()).unwrap();
+
+    let ours = mapping(
+        "192.168.0.10:12345",
+        12345,
+        PortMappingProtocol::UDP,
+        "Test 1",
+    );
+    assert_eq!(
+        memory.calls()[2..5],
+        [
+            Call::Add(ours.clone()),
+            Call::Remove(ours.clone()),
+            Call::Add(ours.clone()),
+        ]
+    );
+    assert!(memory.mappings().contains(&ours));
+    assert!(!memory.mappings().contains(&foreign));
+}
+
+#[test]
+fn missing_backend() {
+    let memory = Memory::default();
+    let csv = "address;port;protocol;duration;comment;backend\n;80;TCP;60;Web;natpmp\n";
+
+    let report = run_csv(csv.as_bytes(), &mut backends(&memory), &Settings::default()).unwrap();
+    assert_eq!(report.failed.len(), 1);
+    assert!(memory.calls().is_empty());
+}
+
+#[test]
+fn invalid_row() {
+    let memory = Memory::default();
+    let csv = "address;port;protocol;duration;comment\n;80;SCTP;60;Web\n";
+
+    assert!(run_csv(csv.as_bytes(), &mut backends(&memory), &Settings::default()).is_err());
+    assert!(memory.calls().is_empty());
+}
+
+/// A backend that never finds a gateway.
+struct Unreachable;
+
+impl Backend for Unreachable {
+    fn discover(
+        &self,
+        _address: Option<IpAddr>,
+        _gateway: Option<IpAddr>,
+    ) -> Result<IpAddr, Box<dyn Error>> {
+        Err("No gateway found".into())
+    }
+
+    fn add_port(&self, _mapping: &Mapping) -> Result<u16, AddPortError> {
+        unreachable!()
+    }
+
+    fn remove_port(&self, _mapping: &Mapping) -> Result<(), Box<dyn Error>> {
+        unreachable!()
+    }
+
+    fn list(&self, _internal: IpAddr) -> Result<Vec<PortMappingEntry>, Box<dyn Error>> {
+        unreachable!()
+    }
+
+    fn external_ip(&self, _internal: IpAddr) -> Result<IpAddr, Box<dyn Error>> {
+        unreachable!()
+    }
+}
+
+/// A backend whose gateways take a while to not answer.
+struct Slow;
+
+impl Backend for Slow {
+    fn discover(
+        &self,
+        _address: Option<IpAddr>,
+        _gateway: Option<IpAddr>,
+    ) -> Result<IpAddr, Box<dyn Error>> {
+        thread::sleep(Duration::from_millis(500));
+        Err("No gateway found".into())
+    }
+
+    fn add_port(&self, _mapping: &Mapping) -> Result<u16, AddPortError> {
+        unreachable!()
+    }
+
+    fn remove_port(&self, _mapping: &Mapping) -> Result<(), Box<dyn Error>> {
+        unreachable!()
+    }
+
+    fn list(&self, _internal: IpAddr) -> Result<Vec<PortMappingEntry>, Box<dyn Error>> {
+        unreachable!()
+    }
+
+    fn external_ip(&self, _internal: IpAddr) -> Result<IpAddr, Box<dyn Error>> {
+        unreachable!()
+    }
+}
+
+#[test]
+fn discover_gateways_concurrently() {
+    let mut backends = Backends::empty(BackendKind::Upnp);
+    backends.insert(BackendKind::Upnp, Box::new(Slow));
+    let csv = "\
+address;port;protocol;duration;comment
+192.168.0.10;80;TCP;60;Web
+192.168.1.10;80;TCP;60;Web
+";
+    let settings = Settings {
+        parallelism: 2,
+        ..Default::default()
+    };
+
+    let start = Instant::now();
+    let report = run_csv(csv.as_bytes(), &mut backends, &settings).unwrap();
+
+    assert_eq!(report.failed.len(), 2);
+    assert!(start.elapsed() < Duration::from_millis(900));
+}
+
+#[test]
+fn fallback_to_next_backend() {
+    let memory = Memory::default();
+
+    let mut backends = Backends::empty(BackendKind::Auto);
+    backends.insert(
+        BackendKind::Auto,
+        Box::new(Fallback::new(vec![
+            (BackendKind::Upnp, Box::new(Unreachable)),
+            (BackendKind::NatPmp, Box::new(memory.clone())),
+        ])),
+    );
+
+    run_csv(CSV.as_bytes(), &mut backends, &Settings::default()).unwrap();
+
+    assert_eq!(memory.mappings().len(), 2);
+    assert_eq!(
+        memory.mappings()[0].internal,
+        SocketAddr::new("192.168.0.2".parse().unwrap(), 12346)
+    );
+}
+
+#[test]
+fn remove_owned_mappings() {
+    let memory = Memory::default();
+    let foreign = mapping("192.168.0.99:80", 80, PortMappingProtocol::TCP, "Other");
+    memory
+        .state()
+        .mappings
+        .insert((PortMappingProtocol::TCP, 80), foreign.clone());
+
+    let mut backends = backends(&memory);
+    run_csv(CSV.as_bytes(), &mut backends, &Settings::default()).unwrap();
+    assert_eq!(backends.owned().count(), 2);
+
+    backends.remove_owned();
+
+    assert_eq!(backends.owned().count(), 0);
+    assert_eq!(memory.mappings(), vec![foreign]);
+}
+
+#[test]
+fn remove_deleted_rows() {
+    let memory = Memory::default();
+    let mut backends = backends(&memory);
+    run_csv(CSV.as_bytes(), &mut backends, &Settings::default()).unwrap();
+
+    let csv = "address;port;protocol;duration;comment\n192.168.0.10;12345;UDP;60;Test 1\n";
+    run_csv(csv.as_bytes(), &mut backends, &Settings::default()).unwrap();
+
+    assert_eq!(
+        memory.mappings(),
+        vec![mapping(
+            "192.168.0.10:12345",
+            12345,
+            PortMappingProtocol::UDP,
+            "Test 1"
+        )]
+    );
+    assert_eq!(backends.owned().count(), 1);
+}
+
+#[test]
+fn assigned_external_port() {
+    let memory = Memory::default();
+    memory.state().reassign.insert(12345, 22345);
+    let mut backends = backends(&memory);
+    run_csv(CSV.as_bytes(), &mut backends, &Settings::default()).unwrap();
+
+    let assigned = Mapping {
+        external_port: 22345,
+        ..mapping(
+            "192.168.0.10:12345",
+            12345,
+            PortMappingProtocol::UDP,
+            "Test 1",
+        )
+    };
+    assert!(backends.owned().any(|(_, mapping)| mapping == assigned));
+
+    // The refresh asks for the assigned port, and keeps the mapping.
+    let calls = memory.calls().len();
+    run_csv(CSV.as_bytes(), &mut backends, &Settings::default()).unwrap();
+    assert!(memory.calls()[calls..].contains(&Call::Add(assigned.clone())));
+    assert!(!memory.calls()[calls..]
+        .iter()
+        .any(|call| matches!(call, Call::Remove(_))));
+
+    // Removing the row removes the assigned port.
+    let csv = "address;port;protocol;duration;comment\n;12346;TCP;0;Test 2\n";
+    run_csv(csv.as_bytes(), &mut backends, &Settings::default()).unwrap();
+    assert!(memory.calls().contains(&Call::Remove(assigned.clone())));
+    assert_eq!(backends.owned().count(), 1);
+
+    backends.remove_owned();
+    assert!(memory.mappings().is_empty());
+}
+
+#[test]
+fn remove_adopted_mapping_by_assigned_port() {
+    let memory = Memory::default();
+    memory.state().reassign.insert(12345, 22345);
+    let mut added = backends(&memory);
+    run_csv(CSV.as_bytes(), &mut added, &Settings::default()).unwrap();
+
+    // Another process takes over the mappings, and removes one by the port that was printed.
+    let mut backends = backends(&memory);
+    for (kind, mapping) in added.owned() {
+        backends.adopt(kind, mapping);
+    }
+    let removed = remove(
+        Some("192.168.0.10".parse().unwrap()),
+        None,
+        &Removal::Ports(PortRange::single(22345), Protocols::UDP),
+        &mut backends,
+    )
+    .unwrap();
+
+    let assigned = Mapping {
+        external_port: 22345,
+        ..mapping(
+            "192.168.0.10:12345",
+            12345,
+            PortMappingProtocol::UDP,
+            "Test 1",
+        )
+    };
+    assert_eq!(removed, vec![assigned.clone()]);
+    assert!(memory.calls().contains(&Call::Remove(assigned)));
+    assert_eq!(memory.mappings().len(), 1);
+    assert_eq!(backends.owned().count(), 1);
+}
+
+#[test]
+fn swap_changed_rows() {
+    let memory = Memory::default();
+    let mut backends = backends(&memory);
+    run_csv(CSV.as_bytes(), &mut backends, &Settings::default()).unwrap();
+    let calls = memory.calls().len();
+
+    let csv = "\
+address;port;protocol;duration;comment
+192.168.0.11;12345;UDP;60;Test 1
+;12346;UDP;0;Test 2
+";
+    run_csv(csv.as_bytes(), &mut backends, &Settings::default()).unwrap();
+
+    let old = mapping(
+        "192.168.0.10:12345",
+        12345,
+        PortMappingProtocol::UDP,
+        "Test 1",
+    );
+    let old_protocol = Mapping {
+        duration: 0,
+        ..mapping(
+            "192.168.0.2:12346",
+            12346,
+            PortMappingProtocol::TCP,
+            "Test 2",
+        )
+    };
+    let new = mapping(
+        "192.168.0.11:12345",
+        12345,
+        PortMappingProtocol::UDP,
+        "Test 1",
+    );
+    let new_protocol = Mapping {
+        protocol: PortMappingProtocol::UDP,
+        ..old_protocol.clone()
+    };
+
+    // Both old mappings are removed before anything is added, no port is stolen.
+    assert_eq!(
+        memory.calls()[calls + 2..],
+        [
+            Call::Remove(old_protocol),
+            Call::Remove(old),
+            Call::Add(new.clone()),
+            Call::Add(new_protocol.clone()),
+        ]
+    );
+    assert_eq!(memory.mappings(), vec![new, new_protocol]);
+}
+
+/// Map port 12345 to another client, with the given comment.
+fn map_foreign(memory: &Memory, comment: &str) -> Mapping {
+    let foreign = mapping(
+        "192.168.0.99:12345",
+        12345,
+        PortMappingProtocol::UDP,
+        comment,
+    );
+    memory
+        .state()
+        .mappings
+        .insert((PortMappingProtocol::UDP, 12345), foreign.clone());
+    foreign
+}
+
+fn with_conflict(conflict: ConflictPolicy) -> Settings {
+    Settings {
+        conflict,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn skip_port_in_use() {
+    let memory = Memory::default();
+    let foreign = map_foreign(&memory, "Other");
+
+    let settings = with_conflict(ConflictPolicy::SkipAndWarn);
+    run_csv(CSV.as_bytes(), &mut backends(&memory), &settings).unwrap();
+
+    assert!(memory.mappings().contains(&foreign));
+    assert_eq!(memory.mappings().len(), 2);
+    assert!(!memory
+        .calls()
+        .iter()
+        .any(|call| matches!(call, Call::Remove(_))));
+}
+
+#[test]
+fn fail_on_port_in_use() {
+    let memory = Memory::default();
+    let foreign = map_foreign(&memory, "Other");
+
+    let settings = with_conflict(ConflictPolicy::Fail);
+    let report = run_csv(CSV.as_bytes(), &mut backends(&memory), &settings).unwrap();
+
+    // The failure of the first row does not keep the second one from being mapped.
+    assert_eq!(report.failed.len(), 1);
+    assert_eq!(report.failed[0].0, 1);
+    assert_eq!(report.mapped, 1);
+    assert!(memory.mappings().contains(&foreign));
+    assert_eq!(memory.mappings().len(), 2);
+}
+
+#[test]
+fn fail_fast_on_port_in_use() {
+    let memory = Memory::default();
+    let foreign = map_foreign(&memory, "Other");
+
+    let settings = Settings {
+        fail_fast: true,
+        ..with_conflict(ConflictPolicy::Fail)
+    };
+    let error = run_csv(CSV.as_bytes(), &mut backends(&memory), &settings).unwrap_err();
+
+    assert_eq!(error.code(), Some(718));
+    assert_eq!(memory.mappings(), vec![foreign]);
+}
+
+#[test]
+fn keep_old_mappings_of_unresolved_rows() {
+    let memory = Memory::default();
+    let mut backends = backends(&memory);
+    run_csv(CSV.as_bytes(), &mut backends, &Settings::default()).unwrap();
+    let mappings = memory.mappings();
+
+    // The first row fails to resolve, so its old mapping is kept. The second row is gone, so its
+    // mapping is still removed.
+    let csv = "\
+address;port;protocol;duration;comment
+nowhere;12345;UDP;60;Test 1
+";
+    let report = run_csv(csv.as_bytes(), &mut backends, &Settings::default()).unwrap();
+
+    assert_eq!(report.failed.len(), 1);
+    assert_eq!(memory.mappings(), mappings[1..]);
+}
+
+#[test]
+fn steal_only_our_ports() {
+    let memory = Memory::default();
+    let foreign = map_foreign(&memory, "Other");
+
+    let settings = with_conflict(ConflictPolicy::StealOnlyIfOurs);
+    run_csv(CSV.as_bytes(), &mut backends(&memory), &settings).unwrap();
+    assert!(memory.mappings().contains(&foreign));
+
+    // A mapping with our comment was probably made by us for an old address of the machine.
+    let foreign = map_foreign(&memory, "Test 1");
+    run_csv(CSV.as_bytes(), &mut backends(&memory), &settings).unwrap();
+    assert!(!memory.mappings().contains(&foreign));
+}
+
+#[test]
+fn conflict_policy_per_row() {
+    let memory = Memory::default();
+    let foreign = map_foreign(&memory, "Other");
+    let csv = "\
+address;port;protocol;duration;comment;conflict
+192.168.0.10;12345;UDP;60;Test 1;skip-and-warn
+";
+
+    run_csv(csv.as_bytes(), &mut backends(&memory), &Settings::default()).unwrap();
+
+    assert_eq!(memory.mappings(), vec![foreign]);
+}
+
+#[test]
+fn separate_ports() {
+    let memory = Memory::default();
+    let csv = "\
+address;port;external_port;internal_port;protocol;duration;comment
+192.168.0.10;;443;8443;TCP;60;HTTPS
+192.168.0.11;8080;18080;;TCP;60;Web
+";
+
+    run_csv(csv.as_bytes(), &mut backends(&memory), &Settings::default()).unwrap();
+
+    assert_eq!(
+        memory.mappings(),
+        vec![
+            mapping("192.168.0.10:8443", 443, PortMappingProtocol::TCP, "HTTPS"),
+            mapping("192.168.0.11:8080", 18080, PortMappingProtocol::TCP, "Web"),
+        ]
+    );
+}
+
+#[test]
+fn missing_port() {
+    let memory = Memory::default();
+    let csv = "address;port;internal_port;protocol;duration;comment\n;;8443;TCP;60;HTTPS\n";
+
+    let report = run_csv(csv.as_bytes(), &mut backends(&memory), &Settings::default()).unwrap();
+    assert_eq!(report.failed.len(), 1);
+    assert!(memory.calls().is_empty());
+}
+
+#[test]
+fn port_range() {
+    let memory = Memory::default();
+    let csv = "\
+address;port;external_port;internal_port;protocol;duration;comment
+192.168.0.10;50000-50002;;;UDP;60;Media
+192.168.0.11;;40000-40001;41000-41001;TCP;60;Game
+";
+
+    run_csv(csv.as_bytes(), &mut backends(&memory), &Settings::default()).unwrap();
+
+    assert_eq!(
+        memory.mappings(),
+        vec![
+            mapping(
+                "192.168.0.11:41000",
+                40000,
+                PortMappingProtocol::TCP,
+                "Game"
+            ),
+            mapping(
+                "192.168.0.11:41001",
+                40001,
+                PortMappingProtocol::TCP,
+                "Game"
+            ),
+            mapping(
+                "192.168.0.10:50000",
+                50000,
+                PortMappingProtocol::UDP,
+                "Media"
+            ),
+            mapping(
+                "192.168.0.10:50001",
+                50001,
+                PortMappingProtocol::UDP,
+                "Media"
+            ),
+            mapping(
+                "192.168.0.10:50002",
+                50002,
+                PortMappingProtocol::UDP,
+                "Media"
+            ),
+        ]
+    );
+}
+
+#[test]
+fn invalid_port_ranges() {
+    for ports in &["50002-50000;;", ";50000-50002;60000", "1-2-3;;"] {
+        let memory = Memory::default();
+        let csv = format!(
+            "address;port;external_port;internal_port;protocol;duration;comment\n;{};UDP;60;x\n",
+            ports
+        );
+
+        let result = run_csv(csv.as_bytes(), &mut backends(&memory), &Settings::default());
+        assert!(result.map_or(true, |report| report.failed.len() == 1));
+        assert!(memory.mappings().is_empty());
+    }
+}
+
+#[test]
+fn partial_failure_in_range() {
+    let memory = Memory::default();
+    let foreign = map_foreign(&memory, "Other");
+    let csv = "address;port;protocol;duration;comment\n192.168.0.10;12344-12346;UDP;60;Media\n";
+
+    let mut report = run_csv(
+        csv.as_bytes(),
+        &mut backends(&memory),
+        &with_conflict(ConflictPolicy::Fail),
+    )
+    .unwrap();
+
+    match report.failed.remove(0).1 {
+        error::Error::Ports { total, failed } => {
+            assert_eq!(total, 3);
+            assert_eq!(failed.len(), 1);
+            assert_eq!(
+                (failed[0].0, failed[0].1),
+                (12345, PortMappingProtocol::UDP)
+            );
+            assert_eq!(failed[0].2.code(), Some(718));
+        }
+        e => panic!("Unexpected error: {}", e),
+    }
+    assert_eq!(
+        memory.mappings(),
+        vec![
+            mapping(
+                "192.168.0.10:12344",
+                12344,
+                PortMappingProtocol::UDP,
+                "Media"
+            ),
+            foreign,
+            mapping(
+                "192.168.0.10:12346",
+                12346,
+                PortMappingProtocol::UDP,
+                "Media"
+            ),
+        ]
+    );
+}
+
+#[test]
+fn remove_range_as_unit() {
+    let memory = Memory::default();
+    let mut backends = backends(&memory);
+    let csv = "address;port;protocol;duration;comment\n192.168.0.10;50000-50002;UDP;60;Media\n";
+    run_csv(csv.as_bytes(), &mut backends, &Settings::default()).unwrap();
+
+    let csv = "address;port;protocol;duration;comment\n192.168.0.10;50000-50001;UDP;60;Media\n";
+    run_csv(csv.as_bytes(), &mut backends, &Settings::default()).unwrap();
+    assert_eq!(memory.mappings().len(), 2);
+
+    backends.remove_owned();
+    assert!(memory.mappings().is_empty());
+    assert_eq!(backends.owned().count(), 0);
+}
+
+#[test]
+fn both_protocols() {
+    let memory = Memory::default();
+    let csv = "\
+address;port;protocol;duration;comment
+192.168.0.10;53;BOTH;60;DNS
+192.168.0.11;51820;TCP+UDP;60;WireGuard
+";
+
+    let mut backends = backends(&memory);
+    run_csv(csv.as_bytes(), &mut backends, &Settings::default()).unwrap();
+
+    assert_eq!(
+        memory.mappings(),
+        vec![
+            mapping("192.168.0.10:53", 53, PortMappingProtocol::TCP, "DNS"),
+            mapping(
+                "192.168.0.11:51820",
+                51820,
+                PortMappingProtocol::TCP,
+                "WireGuard"
+            ),
+            mapping("192.168.0.10:53", 53, PortMappingProtocol::UDP, "DNS"),
+            mapping(
+                "192.168.0.11:51820",
+                51820,
+                PortMappingProtocol::UDP,
+                "WireGuard"
+            ),
+        ]
+    );
+
+    // Removing the row removes both protocols.
+    let csv = "address;port;protocol;duration;comment\n192.168.0.10;53;BOTH;60;DNS\n";
+    run_csv(csv.as_bytes(), &mut backends, &Settings::default()).unwrap();
+    assert_eq!(memory.mappings().len(), 2);
+    assert_eq!(backends.owned().count(), 2);
+}
+
+#[test]
+fn failure_per_protocol() {
+    let memory = Memory::default();
+    let foreign = map_foreign(&memory, "Other");
+    let csv = "address;port;protocol;duration;comment\n192.168.0.10;12345;BOTH;60;Game\n";
+
+    let mut report = run_csv(
+        csv.as_bytes(),
+        &mut backends(&memory),
+        &with_conflict(ConflictPolicy::Fail),
+    )
+    .unwrap();
+
+    match report.failed.remove(0).1 {
+        error::Error::Ports { total, failed } => {
+            assert_eq!(total, 2);
+            assert_eq!(failed.len(), 1);
+            assert_eq!(
+                (failed[0].0, failed[0].1),
+                (12345, PortMappingProtocol::UDP)
+            );
+            assert_eq!(failed[0].2.code(), Some(718));
+        }
+        e => panic!("Unexpected error: {}", e),
+    }
+    assert_eq!(
+        memory.mappings(),
+        vec![
+            mapping(
+                "192.168.0.10:12345",
+                12345,
+                PortMappingProtocol::TCP,
+                "Game"
+            ),
+            foreign,
+        ]
+    );
+}
+
+#[test]
+fn report_counts() {
+    let memory = Memory::default();
+    map_foreign(&memory, "Other");
+    let csv = "address;port;protocol;duration;comment\n192.168.0.10;12345;BOTH;60;Game\n";
+
+    let report = run_csv(
+        csv.as_bytes(),
+        &mut backends(&memory),
+        &with_conflict(ConflictPolicy::SkipAndWarn),
+    )
+    .unwrap();
+
+    assert_eq!((report.mapped, report.skipped), (1, 1));
+    assert!(report.failed.is_empty());
+    assert_eq!(
+        report.to_string(),
+        "1 ports mapped, 1 skipped, 0 rows failed"
+    );
+}
+
+#[test]
+fn fail_fast_stops_the_gateway() {
+    let memory = Memory::default();
+    let foreign = map_foreign(&memory, "Other");
+
+    for parallelism in [1, 4] {
+        let settings = Settings {
+            conflict: ConflictPolicy::Fail,
+            parallelism,
+            fail_fast: true,
+        };
+        assert!(run_csv(CSV.as_bytes(), &mut backends(&memory), &settings).is_err());
+        assert_eq!(memory.mappings(), vec![foreign.clone()]);
+    }
+}
+
+#[test]
+fn gateways_are_independent() {
+    let memory = Memory::default();
+    memory.state().gateway = None;
+    let foreign = map_foreign(&memory, "Other");
+
+    let settings = with_conflict(ConflictPolicy::Fail);
+    let report = run_csv(CSV.as_bytes(), &mut backends(&memory), &settings).unwrap();
+
+    // The second row goes to the router of another local address.
+    assert_eq!(report.failed.len(), 1);
+    assert_eq!(
+        memory.mappings(),
+        vec![
+            Mapping {
+                duration: 0,
+                ..mapping(
+                    "192.168.0.2:12346",
+                    12346,
+                    PortMappingProtocol::TCP,
+                    "Test 2",
+                )
+            },
+            foreign,
+        ]
+    );
+}
+
+#[test]
+fn typed_errors() {
+    let memory = Memory::default();
+    let mut backends = backends(&memory);
+    backends.insert(BackendKind::NatPmp, Box::new(NatPmp::default()));
+    let csv = "\
+address;port;protocol;duration;comment;backend
+nowhere;80;TCP;60;Web;
+fd00::10;80;TCP;60;Web;natpmp
+;;TCP;60;Web;
+";
+
+    let report = run_csv(csv.as_bytes(), &mut backends, &Settings::default()).unwrap();
+
+    let errors: Vec<_> = report.failed.iter().map(|(row, e)| (*row, e)).collect();
+    assert!(
+        matches!(errors[0], (1, error::Error::InvalidAddress(address)) if address == "nowhere")
+    );
+    assert!(matches!(errors[1], (2, error::Error::Ipv6Unsupported)));
+    assert!(matches!(errors[2], (3, error::Error::Config(_))));
+}
@@ -0,0 +1,91 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use upnp_daemon::backend::{Backends, Discovery};
+use upnp_daemon::config::read_csv;
+use upnp_daemon::error::Error;
+use upnp_daemon::pcp::Nonces;
+use upnp_daemon::{run_all, BackendKind, Options, Settings};
+
+use common::FakeIgd;
+
+mod common;
+
+const ROWS: &str = "\
+address;port;protocol;duration;comment
+127.0.0.1;8080;TCP;60;Web
+127.0.0.1;5353;UDP;60;DNS
+127.0.0.1;443;TCP;60;HTTPS
+";
+
+fn backends(ssdp_address: SocketAddr) -> Backends {
+    Backends::new(
+        BackendKind::Upnp,
+        Discovery {
+            ssdp_address,
+            timeout: Duration::from_secs(1),
+        },
+        Nonces::default(),
+    )
+}
+
+fn rows() -> Vec<Options> {
+    read_csv(ROWS.as_bytes()).unwrap()
+}
+
+#[test]
+fn search_once_for_all_rows() {
+    let igd = FakeIgd::start(1);
+    let mut backends = backends(igd.ssdp_address);
+
+    run_all(rows(), &mut backends, &Settings::default()).unwrap();
+    assert_eq!(igd.searches(), 1);
+    assert_eq!(igd.mappings().len(), 3);
+
+    // The gateway is remembered for the following cycles.
+    run_all(rows(), &mut backends, &Settings::default()).unwrap();
+    assert_eq!(igd.searches(), 1);
+}
+
+#[test]
+fn search_again_after_failure() {
+    let igd = FakeIgd::start(1);
+    let mut backends = backends(igd.ssdp_address);
+
+    run_all(rows(), &mut backends, &Settings::default()).unwrap();
+    assert_eq!(igd.searches(), 1);
+
+    igd.set_offline(true);
+    let report = run_all(rows(), &mut backends, &Settings::default()).unwrap();
+    assert_eq!(report.failed.len(), 3);
+    // The first failure makes the other rows search again, once.
+    assert_eq!(igd.searches(), 2);
+
+    igd.set_offline(false);
+    run_all(rows(), &mut backends, &Settings::default()).unwrap();
+    assert_eq!(igd.searches(), 3);
+}
+
+#[test]
+fn failed_search_is_not_repeated_in_a_cycle() {
+    let ssdp_address = common::silent_ssdp_address();
+    let mut backends = backends(ssdp_address);
+
+    for options in rows() {
+        let start = Instant::now();
+        backends.start_cycle();
+        assert!(upnp_daemon::run(options, &mut backends, &Settings::default()).is_err());
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    // Within one cycle, the rows after the first one fail right away.
+    let start = Instant::now();
+    backends.start_cycle();
+    for options in rows() {
+        assert!(matches!(
+            upnp_daemon::run(options, &mut backends, &Settings::default()),
+            Err(Error::Discovery(_))
+        ));
+    }
+    assert!(start.elapsed() < Duration::from_secs(2));
+}